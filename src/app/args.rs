@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+
+use particles::surface::MeshFormat;
+use particles::trajectory::Channels;
+
+/*
+ *
+ * Command Line of the Windowed App
+ * Every argument is read here once, before the window opens, and each module gets its values
+ * from AppArgs. An unknown argument or a value that does not parse stops the app with the usage,
+ * like the headless runner does.
+ *
+ */
+
+pub const USAGE: &str = "usage: particles [--benchmark] [--scene <file>] [--points <file>]... [--checkpoint <file>] [--autosave <seconds>] [--resume <checkpoint>] [--vtk] [--vtk-dir <directory>] [--surface <obj|ply|glb>] [--surface-dir <directory>] [--surface-normals] [--surface-attributes] [--record <file>] [--record-channels <list>] [--replay <file>] [--conservation <file>] [--probe-csv <file>]";
+
+#[derive(Default)]
+pub struct AppArgs {
+    pub benchmark: bool,
+    pub scene: Option<PathBuf>,
+    // Point cloud files added as particles, in the order given
+    pub points: Vec<PathBuf>,
+    pub checkpoint: Option<PathBuf>,
+    // Simulated seconds between two autosaves
+    pub autosave: Option<f64>,
+    pub resume: Option<PathBuf>,
+    pub vtk: bool,
+    pub vtk_directory: Option<PathBuf>,
+    pub surface: Option<MeshFormat>,
+    pub surface_directory: Option<PathBuf>,
+    pub surface_normals: bool,
+    pub surface_attributes: bool,
+    pub record: Option<PathBuf>,
+    pub record_channels: Option<Channels>,
+    pub replay: Option<PathBuf>,
+    pub conservation: Option<PathBuf>,
+    pub probe_csv: Option<PathBuf>,
+}
+
+impl AppArgs {
+    // Arguments of the process, without the program name
+    pub fn from_env() -> Result<AppArgs, String> {
+        AppArgs::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<AppArgs, String> {
+        let mut parsed = AppArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--benchmark" => parsed.benchmark = true,
+                "--scene" => parsed.scene = Some(PathBuf::from(value()?)),
+                "--points" => parsed.points.push(PathBuf::from(value()?)),
+                "--checkpoint" => parsed.checkpoint = Some(PathBuf::from(value()?)),
+                "--autosave" => {
+                    let seconds = value()?;
+                    match seconds.parse::<f64>() {
+                        Ok(seconds) if seconds > 0. => parsed.autosave = Some(seconds),
+                        _ => {
+                            return Err(format!(
+                                "--autosave expects a positive number, got {seconds}"
+                            ))
+                        }
+                    }
+                }
+                "--resume" => parsed.resume = Some(PathBuf::from(value()?)),
+                "--vtk" => parsed.vtk = true,
+                "--vtk-dir" => parsed.vtk_directory = Some(PathBuf::from(value()?)),
+                "--surface" => {
+                    let name = value()?;
+                    parsed.surface = Some(
+                        MeshFormat::from_name(&name)
+                            .ok_or(format!("--surface expects obj, ply or glb, got {name}"))?,
+                    );
+                }
+                "--surface-dir" => parsed.surface_directory = Some(PathBuf::from(value()?)),
+                "--surface-normals" => parsed.surface_normals = true,
+                "--surface-attributes" => parsed.surface_attributes = true,
+                "--record" => parsed.record = Some(PathBuf::from(value()?)),
+                "--record-channels" => {
+                    let names = value()?;
+                    parsed.record_channels = Some(Channels::from_names(&names).ok_or(format!(
+                        "--record-channels expects velocity, density, pressure, all or none, got {names}"
+                    ))?);
+                }
+                "--replay" => parsed.replay = Some(PathBuf::from(value()?)),
+                "--conservation" => parsed.conservation = Some(PathBuf::from(value()?)),
+                "--probe-csv" => parsed.probe_csv = Some(PathBuf::from(value()?)),
+                _ => return Err(format!("unknown argument {arg}")),
+            }
+        }
+
+        if !parsed.points.is_empty() && parsed.scene.is_some() {
+            return Err(
+                "--points can not be combined with --scene, list the files in the point_clouds of the scene instead"
+                    .to_string(),
+            );
+        }
+        // The checkpoint holds the particles of the clouds already
+        if !parsed.points.is_empty() && parsed.resume.is_some() {
+            return Err("--points can not be combined with --resume".to_string());
+        }
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+
+use particles::checkpoint::{BodyState, Checkpoint};
+use particles::probe::ProbeSeries;
+use particles::scene::EmitterState;
+
+use crate::args::AppArgs;
+use crate::box_functions::spawn_body;
+use crate::probes::spawn_probe;
+use crate::scene_loader::ActiveScene;
+use crate::simulation::{Body, Fluid, SimulationParams, SimulationSettings, SimulationTime};
+use crate::{spawn_particle_entity, BevyCounter, Particle};
+
+/*
+ *
+ * Checkpoints in the Windowed App
+ * F5 saves the whole state of the run to the checkpoint file and F9 loads it back. With
+ * `--autosave <seconds>` the state is also saved every few simulated seconds, and
+ * `--resume <file>` starts the app from a checkpoint.
+ *
+ */
+
+const DEFAULT_CHECKPOINT_FILE: &str = "checkpoint.ckpt";
+
+// Sent when a checkpoint replaced the run, with the simulated time it carries on from
+pub struct CheckpointRestored {
+    pub time: f64,
+}
+
+// Entities restored from a checkpoint, despawned first
+type RestoredFilter = Or<(With<Particle>, With<Body>)>;
+
+#[derive(Resource)]
+pub struct Checkpoints {
+    // File F5, F9 and the autosave use
+    pub path: PathBuf,
+    // Simulated seconds between two autosaves
+    pub autosave_interval: Option<f64>,
+    last_saved: f64,
+    // Checkpoint restored by the next frame
+    pending: Option<Checkpoint>,
+}
+
+impl Checkpoints {
+    // Checkpoint file and autosave interval from `--checkpoint <file>` and `--autosave <seconds>`,
+    // and the checkpoint to start from with `--resume <file>`
+    pub fn new(args: &AppArgs) -> Result<Checkpoints, String> {
+        let pending = match &args.resume {
+            Some(path) => Some(Checkpoint::load(path).map_err(|error| error.to_string())?),
+            None => None,
+        };
+        Ok(Checkpoints {
+            path: (args.checkpoint.clone()).unwrap_or(PathBuf::from(DEFAULT_CHECKPOINT_FILE)),
+            autosave_interval: args.autosave,
+            last_saved: pending.as_ref().map_or(0., |checkpoint| checkpoint.time),
+            pending,
+        })
+    }
+}
+
+// Save when F5 is pressed or the autosave is due
+pub fn save_checkpoint_system(
+    input: Res<Input<KeyCode>>,
+    simulation_time: Res<SimulationTime>,
+    settings: Res<SimulationSettings>,
+    params: Res<SimulationParams>,
+    fluid: Res<Fluid>,
+    body_query: Query<(&Body, &Transform)>,
+    mut checkpoints: ResMut<Checkpoints>,
+) {
+    let autosave = checkpoints
+        .autosave_interval
+        .is_some_and(|interval| simulation_time.elapsed - checkpoints.last_saved >= interval);
+    if !(input.just_pressed(KeyCode::F5) || autosave) {
+        return;
+    }
+    checkpoints.last_saved = simulation_time.elapsed;
+
+    let checkpoint = Checkpoint {
+        time: simulation_time.elapsed,
+        steps: simulation_time.steps,
+        settings: **settings,
+        params: **params,
+        particles: fluid.0.clone(),
+        bodies: (body_query.iter())
+            .map(|(body, transform)| BodyState {
+                position: transform.translation,
+                velocity: body.velocity,
+                force: body.force,
+            })
+            .collect(),
+    };
+    match checkpoint.save(&checkpoints.path) {
+        Ok(()) => info!(
+            "Saved {} particles at {:.2} s to {}",
+            fluid.len(),
+            simulation_time.elapsed,
+            checkpoints.path.display()
+        ),
+        Err(error) => error!("{error}"),
+    }
+}
+
+// Load the checkpoint file when F9 is pressed, keeping the current run if it does not load
+pub fn load_checkpoint_system(input: Res<Input<KeyCode>>, mut checkpoints: ResMut<Checkpoints>) {
+    if !input.just_pressed(KeyCode::F9) {
+        return;
+    }
+    match Checkpoint::load(&checkpoints.path) {
+        Ok(checkpoint) => checkpoints.pending = Some(checkpoint),
+        Err(error) => error!("{error}"),
+    }
+}
+
+// Replace the particles, capsules, settings, parameters and time with the loaded checkpoint
+#[allow(clippy::too_many_arguments)]
+pub fn restore_checkpoint_system(
+    mut commands: Commands,
+    ass: Res<AssetServer>,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut checkpoints: ResMut<Checkpoints>,
+    mut restored: EventWriter<CheckpointRestored>,
+    mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+    mut settings: ResMut<SimulationSettings>,
+    mut params: ResMut<SimulationParams>,
+    mut simulation_time: ResMut<SimulationTime>,
+    active_scene: Option<ResMut<ActiveScene>>,
+    spawned_query: Query<Entity, RestoredFilter>,
+) {
+    let Some(checkpoint) = checkpoints.pending.take() else {
+        return;
+    };
+    checkpoints.last_saved = checkpoint.time;
+    restored.send(CheckpointRestored {
+        time: checkpoint.time,
+    });
+
+    for entity in &spawned_query {
+        commands.entity(entity).despawn_recursive();
+    }
+    **settings = checkpoint.settings;
+    // The checkpoint may hold its own Tait parameters, which update_params_system would overwrite
+    **params.bypass_change_detection() = checkpoint.params;
+    *simulation_time = SimulationTime {
+        elapsed: checkpoint.time,
+        steps: checkpoint.steps,
+    };
+    // The emitters of the scene carry on from the checkpoint time, and a scene resumed before it
+    // was set up still gets its probes
+    if let Some(mut active_scene) = active_scene {
+        if !active_scene.is_loaded() {
+            for probe in active_scene.scene.probes.iter() {
+                spawn_probe(
+                    &mut commands,
+                    &mut meshes,
+                    &mut materials,
+                    ProbeSeries::new(probe.name.clone(), probe.position),
+                );
+            }
+        }
+        active_scene.emitters = Some(EmitterState::resume_at(
+            &active_scene.scene,
+            checkpoint.time,
+        ));
+    }
+
+    **fluid = checkpoint.particles;
+    counter.count = 0;
+    for (&id, &position) in fluid.ids.iter().zip(fluid.positions.iter()) {
+        spawn_particle_entity(
+            &mut commands,
+            &mut counter,
+            &mut meshes,
+            &mut materials,
+            id,
+            position,
+        );
+    }
+
+    for body in checkpoint.bodies {
+        let entity = spawn_body(
+            &mut commands,
+            &ass,
+            &mut meshes,
+            &mut materials,
+            body.position,
+            body.velocity,
+        );
+        commands.entity(entity).insert(Body {
+            velocity: body.velocity,
+            force: body.force,
+        });
+    }
+    info!(
+        "Resumed {} particles at {:.2} s",
+        fluid.len(),
+        simulation_time.elapsed
+    );
+}
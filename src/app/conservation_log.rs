@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use particles::conservation::ConservationLog;
+
+use crate::args::AppArgs;
+use crate::simulation::{SimulationConservation, SimulationTime};
+
+/*
+ *
+ * Conservation Log in the Windowed App
+ * `--conservation <file>` writes the energy, momentum, mass, density error and top speeds the
+ * stats text shows to a CSV file, one row per simulated step, to compare runs with different
+ * parameters or solvers.
+ *
+ */
+
+#[derive(Resource)]
+pub struct ConservationRecorder {
+    pub path: PathBuf,
+    // A resumed run adds to the rows written before its checkpoint
+    append: bool,
+    // Created on the first step, and dropped after a write fails
+    log: Option<ConservationLog>,
+    failed: bool,
+    last_step: Option<u64>,
+}
+
+impl ConservationRecorder {
+    // CSV file to write from `--conservation <file>`
+    pub fn new(args: &AppArgs) -> Option<ConservationRecorder> {
+        Some(ConservationRecorder {
+            path: args.conservation.clone()?,
+            append: args.resume.is_some(),
+            log: None,
+            failed: false,
+            last_step: None,
+        })
+    }
+}
+
+// Add a row after every step
+pub fn conservation_log_system(
+    simulation_time: Res<SimulationTime>,
+    conservation: Res<SimulationConservation>,
+    recorder: Option<ResMut<ConservationRecorder>>,
+) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    if recorder.failed || recorder.last_step == Some(simulation_time.steps) {
+        return;
+    }
+    recorder.last_step = Some(simulation_time.steps);
+
+    let recorder = &mut *recorder;
+    if recorder.log.is_none() {
+        match ConservationLog::create(&recorder.path, recorder.append) {
+            Ok(log) => recorder.log = Some(log),
+            Err(error) => {
+                error!("{}: {error}", recorder.path.display());
+                recorder.failed = true;
+                return;
+            }
+        }
+    }
+    if let Some(log) = &mut recorder.log {
+        let written = log
+            .write(
+                simulation_time.steps,
+                simulation_time.elapsed,
+                &conservation,
+            )
+            .and_then(|()| log.flush());
+        if let Err(error) = written {
+            error!("{}: {error}", recorder.path.display());
+            recorder.log = None;
+            recorder.failed = true;
+        }
+    }
+}
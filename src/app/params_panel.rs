@@ -0,0 +1,91 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use particles::sph::{EquationOfState, SimParams, TaitParameters};
+
+use crate::simulation::{SimulationParams, SimulationSettings};
+
+/*
+ *
+ * Simulation Parameters Panel
+ * egui side panel with a slider for every physical parameter. update_params_system() recomputes
+ * the kernels once a value changes, so the next step already uses it.
+ *
+ */
+
+pub fn params_panel_system(mut contexts: EguiContexts, mut params: ResMut<SimulationParams>) {
+    // Edit a copy so the resource is only marked as changed when a value actually changes
+    let mut edited = **params;
+
+    egui::SidePanel::right("simulation_parameters").show(contexts.ctx_mut(), |ui| {
+        ui.heading("Simulation Parameters");
+
+        ui.separator();
+        ui.label("Fluid");
+        ui.add(egui::Slider::new(&mut edited.particle_mass, 1.0..=200.0).text("Particle mass"));
+        ui.add(
+            egui::Slider::new(&mut edited.base_density, 0.00005..=0.001)
+                .logarithmic(true)
+                .fixed_decimals(5)
+                .text("Base density"),
+        );
+        ui.add(
+            egui::Slider::new(&mut edited.smoothing_length, 40.0..=160.0).text("Smoothing length"),
+        );
+        ui.add(
+            egui::Slider::new(&mut edited.isotropic_exponent, 10000.0..=1000000.0)
+                .logarithmic(true)
+                .text("Stiffness"),
+        );
+        ui.add(egui::Slider::new(&mut edited.dynamic_viscosity, 0.0..=20.0).text("Viscosity"));
+        ui.add(egui::Slider::new(&mut edited.gravity, -1000.0..=0.0).text("Gravity"));
+
+        ui.separator();
+        ui.label("Box");
+        ui.add(egui::Slider::new(&mut edited.box_size.x, 400.0..=2400.0).text("Width"));
+        ui.add(egui::Slider::new(&mut edited.box_size.y, 400.0..=1600.0).text("Height"));
+        ui.add(egui::Slider::new(&mut edited.box_size.z, 400.0..=1600.0).text("Length"));
+        ui.add(egui::Slider::new(&mut edited.coef_rest, 0.0..=1.0).text("Wall restitution"));
+
+        ui.separator();
+        ui.label("Orion Capsule");
+        ui.add(
+            egui::Slider::new(&mut edited.body_mass, 0.001..=0.05)
+                .logarithmic(true)
+                .fixed_decimals(3)
+                .text("Mass"),
+        );
+        ui.add(egui::Slider::new(&mut edited.body_size, 50.0..=300.0).text("Size"));
+        ui.add(
+            egui::Slider::new(&mut edited.particle_stiffness, 0.0..=0.2)
+                .text("Collision stiffness"),
+        );
+
+        ui.separator();
+        if ui.button("Reset").clicked() {
+            edited = SimParams::default();
+        }
+    });
+
+    if edited != **params {
+        **params = edited;
+    }
+}
+
+// Recompute what is derived from the parameters when they change: the kernel normalizations
+// and the Tait equation of state
+pub fn update_params_system(
+    mut params: ResMut<SimulationParams>,
+    mut settings: ResMut<SimulationSettings>,
+) {
+    if !params.is_changed() {
+        return;
+    }
+    params.bypass_change_detection().update_kernels();
+    if let EquationOfState::Tait(tait) = settings.equation_of_state {
+        settings.equation_of_state = EquationOfState::Tait(TaitParameters {
+            clamp_negative_pressure: tait.clamp_negative_pressure,
+            ..TaitParameters::for_params(&params)
+        });
+    }
+}
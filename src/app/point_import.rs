@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+
+use particles::point_cloud::PointCloud;
+
+use crate::args::AppArgs;
+use crate::simulation::Fluid;
+use crate::{spawn_particle_entity, BevyCounter};
+
+/*
+ *
+ * Point Cloud Import in the Windowed App
+ * `--points <file>` adds a particle at every point of a CSV or PLY file when the app starts, with
+ * the velocities of the file if it has any, and can be given more than once. The files are read
+ * before the window opens, so one that does not load is reported right away. Scene files list
+ * their point clouds themselves, with an offset, scale and velocity, and a checkpoint already
+ * holds the particles, so `--points` is refused with `--scene` or `--resume`.
+ *
+ */
+
+#[derive(Resource)]
+pub struct PointImport {
+    // Point clouds not added yet, emptied on the first frame
+    pub clouds: Vec<(PathBuf, PointCloud)>,
+}
+
+impl PointImport {
+    // Point clouds from every `--points <file>`
+    pub fn load(args: &AppArgs) -> Result<PointImport, String> {
+        let mut clouds = Vec::new();
+        for path in args.points.iter() {
+            let cloud = PointCloud::load(path).map_err(|error| error.to_string())?;
+            clouds.push((path.clone(), cloud));
+        }
+        Ok(PointImport { clouds })
+    }
+}
+
+// Add the particles of the imported point clouds once, on the first frame
+pub fn import_points_system(
+    mut commands: Commands,
+    mut import: ResMut<PointImport>,
+    mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if import.clouds.is_empty() {
+        return;
+    }
+    for (path, cloud) in import.clouds.drain(..) {
+        for (&position, &velocity) in cloud.positions.iter().zip(cloud.velocities.iter()) {
+            let id = fluid.push(position, velocity);
+            spawn_particle_entity(
+                &mut commands,
+                &mut counter,
+                &mut meshes,
+                &mut materials,
+                id,
+                position,
+            );
+        }
+        info!("Added {} particles from {}", cloud.len(), path.display());
+    }
+}
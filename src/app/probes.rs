@@ -0,0 +1,260 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+use bevy::window::PrimaryWindow;
+use bevy_egui::egui::plot::{Legend, Line, Plot, PlotPoints};
+use bevy_egui::{egui, EguiContexts};
+
+use particles::neighbors::SpatialHash;
+use particles::probe::{interpolate, write_csv, ProbeSample, ProbeSeries};
+
+use crate::args::AppArgs;
+use crate::simulation::{Fluid, SimulationParams, SimulationTime};
+
+/*
+ *
+ * Probes in the Windowed App
+ * Probe entities sample the SPH interpolated pressure, density and velocity at their position
+ * after every step. They come from the probes of the scene file, or Ctrl + left click places one
+ * where the cursor points, on the plane through the middle of the box facing the camera. The
+ * Probes window plots the time series of every probe, removes probes and saves the samples to
+ * the CSV file given with `--probe-csv <file>` (probes.csv by default).
+ *
+ */
+
+const DEFAULT_PROBE_CSV: &str = "probes.csv";
+const PROBE_RADIUS: f32 = 20.;
+const PLOT_HEIGHT: f32 = 200.;
+
+#[derive(Component, Deref, DerefMut)]
+pub struct Probe(pub ProbeSeries);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeQuantity {
+    Pressure,
+    Density,
+    Speed,
+}
+
+impl ProbeQuantity {
+    const ALL: [ProbeQuantity; 3] = [
+        ProbeQuantity::Pressure,
+        ProbeQuantity::Density,
+        ProbeQuantity::Speed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeQuantity::Pressure => "Pressure",
+            ProbeQuantity::Density => "Density",
+            ProbeQuantity::Speed => "Speed",
+        }
+    }
+
+    pub fn value(&self, sample: &ProbeSample) -> f32 {
+        match self {
+            ProbeQuantity::Pressure => sample.pressure,
+            ProbeQuantity::Density => sample.density,
+            ProbeQuantity::Speed => sample.velocity.length(),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct ProbeSettings {
+    pub csv_path: PathBuf,
+    // Quantity the window plots
+    pub quantity: ProbeQuantity,
+    // Probes placed by clicking so far, to name the next one
+    placed: usize,
+}
+
+impl ProbeSettings {
+    pub fn new(args: &AppArgs) -> ProbeSettings {
+        ProbeSettings {
+            csv_path: (args.probe_csv.clone()).unwrap_or(PathBuf::from(DEFAULT_PROBE_CSV)),
+            quantity: ProbeQuantity::Pressure,
+            placed: 0,
+        }
+    }
+}
+
+// Spawn the small red sphere marking a probe
+pub fn spawn_probe(
+    commands: &mut Commands,
+    meshes: &mut Assets<BevyMesh>,
+    materials: &mut Assets<StandardMaterial>,
+    series: ProbeSeries,
+) {
+    commands
+        .spawn(PbrBundle {
+            mesh: meshes.add(BevyMesh::from(shape::UVSphere {
+                radius: PROBE_RADIUS,
+                sectors: 16,
+                stacks: 16,
+            })),
+            material: materials.add(StandardMaterial {
+                base_color: Color::RED,
+                emissive: Color::RED,
+                ..default()
+            }),
+            transform: Transform::from_translation(series.position),
+            ..default()
+        })
+        .insert(Probe(series));
+}
+
+// Place a probe with Ctrl + left click
+#[allow(clippy::too_many_arguments)]
+pub fn place_probe_system(
+    mut commands: Commands,
+    mouse_button_input: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut contexts: EguiContexts,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    params: Res<SimulationParams>,
+    mut settings: ResMut<ProbeSettings>,
+) {
+    if !(mouse_button_input.just_pressed(MouseButton::Left) && ctrl_pressed(&keys))
+        || contexts.ctx_mut().is_pointer_over_area()
+    {
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(Window::cursor_position) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = camera_query.get_single() else {
+        return;
+    };
+    let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+        return;
+    };
+
+    // Plane through the origin, the middle of the box, facing the camera
+    let normal = camera_transform.forward();
+    let facing = ray.direction.dot(normal);
+    if facing.abs() < f32::EPSILON {
+        return;
+    }
+    let distance = -ray.origin.dot(normal) / facing;
+    let half_size = params.box_size / 2.;
+    let position = ray.get_point(distance).clamp(-half_size, half_size);
+
+    settings.placed += 1;
+    let name = format!("probe {}", settings.placed);
+    info!("Placed {name} at {position}");
+    spawn_probe(
+        &mut commands,
+        &mut meshes,
+        &mut materials,
+        ProbeSeries::new(name, position),
+    );
+}
+
+pub fn ctrl_pressed(keys: &Input<KeyCode>) -> bool {
+    keys.any_pressed([KeyCode::LControl, KeyCode::RControl])
+}
+
+// Sample every probe whenever the simulated time moved, a checkpoint restored to an earlier
+// step drops the samples after it
+pub fn probe_sample_system(
+    simulation_time: Res<SimulationTime>,
+    fluid: Res<Fluid>,
+    params: Res<SimulationParams>,
+    mut probe_query: Query<&mut Probe>,
+) {
+    if !simulation_time.is_changed() || probe_query.is_empty() {
+        return;
+    }
+    let hash = SpatialHash::new(&fluid.positions, params.smoothing_length);
+    for mut probe in &mut probe_query {
+        while (probe.samples.last()).is_some_and(|sample| sample.step >= simulation_time.steps) {
+            probe.samples.pop();
+        }
+        let sample = interpolate(
+            &fluid,
+            &hash,
+            &params,
+            probe.position,
+            simulation_time.steps,
+            simulation_time.elapsed,
+        );
+        probe.samples.push(sample);
+    }
+}
+
+// Window plotting the probes, shown while there are any
+pub fn probe_window_system(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut settings: ResMut<ProbeSettings>,
+    mut probe_query: Query<(Entity, &mut Probe)>,
+) {
+    if probe_query.is_empty() {
+        return;
+    }
+    let mut quantity = settings.quantity;
+    let mut clear = false;
+    let mut save = false;
+
+    egui::Window::new("Probes").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("")
+                .selected_text(quantity.name())
+                .show_ui(ui, |ui| {
+                    for option in ProbeQuantity::ALL {
+                        ui.selectable_value(&mut quantity, option, option.name());
+                    }
+                });
+            clear = ui.button("Clear").clicked();
+            save = ui.button("Save CSV").clicked();
+        });
+
+        for (entity, probe) in &probe_query {
+            ui.horizontal(|ui| {
+                let latest = (probe.samples.last()).map_or(0., |sample| quantity.value(sample));
+                ui.label(format!(
+                    "{} ({:.0}, {:.0}, {:.0}): {latest:.4e}",
+                    probe.name, probe.position.x, probe.position.y, probe.position.z
+                ));
+                if ui.small_button("Remove").clicked() {
+                    commands.entity(entity).despawn();
+                }
+            });
+        }
+
+        Plot::new("probe_plot")
+            .height(PLOT_HEIGHT)
+            .legend(Legend::default())
+            .show(ui, |plot_ui| {
+                for (_, probe) in &probe_query {
+                    let points: PlotPoints = (probe.samples.iter())
+                        .map(|sample| [sample.time, quantity.value(sample) as f64])
+                        .collect();
+                    plot_ui.line(Line::new(points).name(&probe.name));
+                }
+            });
+    });
+
+    if quantity != settings.quantity {
+        settings.quantity = quantity;
+    }
+    if save {
+        let probes: Vec<ProbeSeries> = (probe_query.iter())
+            .map(|(_, probe)| probe.0.clone())
+            .collect();
+        match write_csv(&settings.csv_path, &probes) {
+            Ok(()) => info!("Saved the probes to {}", settings.csv_path.display()),
+            Err(error) => error!("{}: {error}", settings.csv_path.display()),
+        }
+    }
+    if clear {
+        for (_, mut probe) in &mut probe_query {
+            probe.samples.clear();
+        }
+    }
+}
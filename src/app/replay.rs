@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+use bevy_egui::{egui, EguiContexts};
+
+use particles::checkpoint::BodyState;
+use particles::trajectory::{
+    Channels, TrajectoryFrame, TrajectoryOptions, TrajectoryReader, TrajectoryWriter,
+};
+
+use crate::args::AppArgs;
+use crate::box_functions::spawn_body;
+use crate::simulation::{Body, Fluid, SimulationParams, SimulationTime};
+use crate::{spawn_particle_entity, BevyCounter, Particle};
+
+/*
+ *
+ * Record and Replay
+ * `--record <file>` writes the particles and capsules of every simulated frame to a trajectory,
+ * with the velocities, or the channels listed by `--record-channels`. The positions are rounded
+ * to about a hundred thousandth of the box size.
+ * `--replay <file>` opens the app on a recorded trajectory instead of running the physics: a
+ * timeline at the bottom plays, pauses, steps and scrubs through the frames at any speed, and
+ * the fluid surface is rebuilt from the particles of the frame on display.
+ *
+ */
+
+const SPEEDS: std::ops::RangeInclusive<f64> = 0.1..=4.0;
+const DEFAULT_CHANNELS: Channels = Channels {
+    velocity: true,
+    density: false,
+    pressure: false,
+};
+// Particles bounce back once they are past a wall, so they can be a little outside the box
+const BOUNDS_MARGIN: f32 = 0.1;
+
+#[derive(Resource)]
+pub struct TrajectoryRecorder {
+    pub path: PathBuf,
+    pub channels: Channels,
+    // Created on the first frame, when the box the positions are quantized in is known, and
+    // dropped after a write fails
+    writer: Option<TrajectoryWriter<BufWriter<File>>>,
+    failed: bool,
+    last_step: Option<u64>,
+}
+
+impl TrajectoryRecorder {
+    // Trajectory to record from `--record <file>`, and the channels it keeps from
+    // `--record-channels <list>`
+    pub fn new(args: &AppArgs) -> Option<TrajectoryRecorder> {
+        Some(TrajectoryRecorder {
+            path: args.record.clone()?,
+            channels: args.record_channels.unwrap_or(DEFAULT_CHANNELS),
+            writer: None,
+            failed: false,
+            last_step: None,
+        })
+    }
+}
+
+#[derive(Resource)]
+pub struct Replay {
+    pub path: PathBuf,
+    reader: TrajectoryReader<BufReader<File>>,
+    // Frame on display, and the one the particles were last set from
+    pub frame: usize,
+    shown: Option<usize>,
+    pub playing: bool,
+    // Recorded seconds played per second
+    pub speed: f64,
+    // Recorded time the playback has reached
+    clock: f64,
+}
+
+impl Replay {
+    // Trajectory to play from `--replay <file>`
+    pub fn open(args: &AppArgs) -> Result<Option<Replay>, String> {
+        let Some(path) = args.replay.clone() else {
+            return Ok(None);
+        };
+        let reader = TrajectoryReader::open(&path).map_err(|error| error.to_string())?;
+        let Some(clock) = reader.time(0) else {
+            return Err(format!("{}: the trajectory has no frames", path.display()));
+        };
+        Ok(Some(Replay {
+            path,
+            reader,
+            frame: 0,
+            shown: None,
+            playing: true,
+            speed: 1.,
+            clock,
+        }))
+    }
+
+    pub fn frames(&self) -> usize {
+        self.reader.len()
+    }
+
+    // Show a frame and carry on playing from its time
+    pub fn seek(&mut self, frame: usize) {
+        self.frame = frame.min(self.frames() - 1);
+        self.clock = self.reader.time(self.frame).unwrap_or(self.clock);
+    }
+}
+
+// Run condition of the systems that step the physics or add particles, which a replay replaces
+pub fn not_replaying(replay: Option<Res<Replay>>) -> bool {
+    replay.is_none()
+}
+
+// Write the particles and capsules after every step
+pub fn record_system(
+    simulation_time: Res<SimulationTime>,
+    fluid: Res<Fluid>,
+    params: Res<SimulationParams>,
+    body_query: Query<(&Body, &Transform)>,
+    recorder: Option<ResMut<TrajectoryRecorder>>,
+) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    if recorder.failed || recorder.last_step == Some(simulation_time.steps) {
+        return;
+    }
+    recorder.last_step = Some(simulation_time.steps);
+
+    let recorder = &mut *recorder;
+    if recorder.writer.is_none() {
+        let half_size = params.box_size * (0.5 + BOUNDS_MARGIN);
+        let options = TrajectoryOptions {
+            channels: recorder.channels,
+            ..TrajectoryOptions::new(-half_size, half_size)
+        };
+        match TrajectoryWriter::create(&recorder.path, options) {
+            Ok(writer) => recorder.writer = Some(writer),
+            Err(error) => {
+                error!("{}: {error}", recorder.path.display());
+                recorder.failed = true;
+                return;
+            }
+        }
+    }
+
+    let bodies = (body_query.iter())
+        .map(|(body, transform)| BodyState {
+            position: transform.translation,
+            velocity: body.velocity,
+            force: body.force,
+        })
+        .collect();
+    let frame = TrajectoryFrame::new(
+        simulation_time.elapsed,
+        simulation_time.steps,
+        &fluid,
+        bodies,
+    );
+    if let Some(writer) = &mut recorder.writer {
+        // Flushed every frame, so the recording is complete whenever the window is closed
+        if let Err(error) = writer.write_frame(&frame).and_then(|()| writer.flush()) {
+            error!("{}: {error}", recorder.path.display());
+            recorder.writer = None;
+            recorder.failed = true;
+        }
+    }
+}
+
+// Timeline at the bottom of the window
+pub fn replay_panel_system(mut contexts: EguiContexts, mut replay: ResMut<Replay>) {
+    let last = replay.frames() - 1;
+    let mut frame = replay.frame;
+    let mut playing = replay.playing;
+    let mut speed = replay.speed;
+
+    egui::TopBottomPanel::bottom("replay").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("|<").clicked() {
+                frame = frame.saturating_sub(1);
+                playing = false;
+            }
+            if ui.button(if playing { "Pause" } else { "Play" }).clicked() {
+                // Playing from the last frame starts over
+                if !playing && frame == last {
+                    frame = 0;
+                }
+                playing = !playing;
+            }
+            if ui.button(">|").clicked() {
+                frame = (frame + 1).min(last);
+                playing = false;
+            }
+            ui.add(
+                egui::Slider::new(&mut speed, SPEEDS)
+                    .logarithmic(true)
+                    .fixed_decimals(1)
+                    .suffix("x")
+                    .text("Speed"),
+            );
+            ui.label(format!(
+                "{}: {:.2} s, frame {} of {}",
+                replay.path.display(),
+                replay.reader.time(frame).unwrap_or_default(),
+                frame + 1,
+                last + 1
+            ));
+        });
+        ui.spacing_mut().slider_width = ui.available_width() - 60.;
+        ui.add(egui::Slider::new(&mut frame, 0..=last).text("Frame"));
+    });
+
+    if frame != replay.frame {
+        replay.seek(frame);
+    }
+    if playing != replay.playing {
+        replay.playing = playing;
+    }
+    if speed != replay.speed {
+        replay.speed = speed;
+    }
+}
+
+// Advance the playback and set the particles and capsules from the frame on display
+#[allow(clippy::too_many_arguments)]
+pub fn replay_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    ass: Res<AssetServer>,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut replay: ResMut<Replay>,
+    mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+    mut simulation_time: ResMut<SimulationTime>,
+    particle_query: Query<(Entity, &Particle)>,
+    mut body_query: Query<(Entity, &mut Body, &mut Transform)>,
+) {
+    if replay.playing {
+        replay.clock += time.delta_seconds_f64() * replay.speed;
+        while (replay.reader.time(replay.frame + 1)).is_some_and(|time| time <= replay.clock) {
+            replay.frame += 1;
+        }
+        if replay.frame + 1 == replay.frames() {
+            replay.playing = false;
+        }
+    }
+    if replay.shown == Some(replay.frame) {
+        return;
+    }
+    let index = replay.frame;
+    replay.shown = Some(index);
+    let frame = match replay.reader.read_frame(index) {
+        Ok(frame) => frame,
+        Err(error) => {
+            error!("{error}");
+            replay.playing = false;
+            return;
+        }
+    };
+
+    **fluid = frame.particles();
+    *simulation_time = SimulationTime {
+        elapsed: frame.time,
+        steps: frame.step,
+    };
+
+    // Particles keep their entity from frame to frame, only the ones that came or went change
+    let mut spawned = 0;
+    for (entity, particle) in &particle_query {
+        if particle.id < fluid.len() {
+            spawned += 1;
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+    for id in spawned..fluid.len() {
+        let position = fluid.positions[fluid.index_of(id)];
+        spawn_particle_entity(
+            &mut commands,
+            &mut counter,
+            &mut meshes,
+            &mut materials,
+            id,
+            position,
+        );
+    }
+    counter.count = fluid.len();
+
+    if body_query.iter().count() == frame.bodies.len() {
+        for ((_, mut body, mut transform), state) in body_query.iter_mut().zip(frame.bodies.iter())
+        {
+            transform.translation = state.position;
+            body.velocity = state.velocity;
+            body.force = state.force;
+        }
+    } else {
+        for (entity, _, _) in &body_query {
+            commands.entity(entity).despawn_recursive();
+        }
+        for state in frame.bodies.iter() {
+            spawn_body(
+                &mut commands,
+                &ass,
+                &mut meshes,
+                &mut materials,
+                state.position,
+                state.velocity,
+            );
+        }
+    }
+}
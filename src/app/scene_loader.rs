@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+
+use particles::probe::ProbeSeries;
+use particles::scene::{EmitterState, Scene};
+use particles::sph::ParticleSet;
+
+use crate::box_functions::spawn_body;
+use crate::probes::{spawn_probe, Probe};
+use crate::simulation::{Body, Fluid, SimulationParams, SimulationSettings, SimulationTime};
+use crate::{spawn_particle_entity, BevyCounter, Particle};
+
+/*
+ *
+ * Scene Loading
+ * Sets the simulation up from a scene file given with `--scene <path>`: the solver settings and
+ * parameters, the fluid blocks, the capsules, the obstacles and the probes. Emitters add their rows as the
+ * scene plays, and R reloads the file and starts the scene over.
+ *
+ */
+
+#[derive(Resource)]
+pub struct ActiveScene {
+    pub scene: Scene,
+    pub path: PathBuf,
+    // Rows the emitters added so far, None until the scene is set up or resumed from a
+    // checkpoint
+    pub emitters: Option<EmitterState>,
+}
+
+impl ActiveScene {
+    pub fn new(scene: Scene, path: PathBuf) -> Self {
+        ActiveScene {
+            scene,
+            path,
+            emitters: None,
+        }
+    }
+
+    // Whether the scene was set up, a checkpoint resumed at startup skips that
+    pub fn is_loaded(&self) -> bool {
+        self.emitters.is_some()
+    }
+}
+
+#[derive(Component)]
+pub struct SceneObstacle;
+
+// Everything a scene spawns, despawned when it starts over
+type SpawnedBySceneFilter = Or<(With<Particle>, With<Body>, With<SceneObstacle>, With<Probe>)>;
+
+// Reload the scene file when R is pressed, keeping the current scene if it no longer loads
+pub fn reload_scene_system(input: Res<Input<KeyCode>>, mut active: ResMut<ActiveScene>) {
+    if !input.just_pressed(KeyCode::R) {
+        return;
+    }
+    match Scene::load(&active.path) {
+        Ok(scene) => *active = ActiveScene::new(scene, active.path.clone()),
+        Err(error) => error!("{error}"),
+    }
+}
+
+// Replace whatever is simulated with the scene once it is loaded
+#[allow(clippy::too_many_arguments)]
+pub fn apply_scene_system(
+    mut commands: Commands,
+    ass: Res<AssetServer>,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut active: ResMut<ActiveScene>,
+    mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+    mut settings: ResMut<SimulationSettings>,
+    mut params: ResMut<SimulationParams>,
+    mut simulation_time: ResMut<SimulationTime>,
+    spawned_query: Query<Entity, SpawnedBySceneFilter>,
+) {
+    if active.is_loaded() {
+        return;
+    }
+    active.emitters = Some(EmitterState::new(&active.scene));
+
+    for entity in &spawned_query {
+        commands.entity(entity).despawn_recursive();
+    }
+    **fluid = ParticleSet::default();
+    *simulation_time = SimulationTime::default();
+    counter.count = 0;
+
+    let scene = &active.scene;
+    **settings = scene.solver;
+    // The scene may set its own Tait parameters, which update_params_system would overwrite
+    **params.bypass_change_detection() = scene.params;
+
+    let ids = scene.fill(&mut fluid);
+    for id in ids {
+        let position = fluid.positions[fluid.index_of(id)];
+        spawn_particle_entity(
+            &mut commands,
+            &mut counter,
+            &mut meshes,
+            &mut materials,
+            id,
+            position,
+        );
+    }
+
+    for body in scene.rigid_bodies.iter() {
+        spawn_body(
+            &mut commands,
+            &ass,
+            &mut meshes,
+            &mut materials,
+            body.position,
+            body.velocity,
+        );
+    }
+
+    let obstacle_material = materials.add(Color::rgba(0.5, 0.5, 0.5, 1.0).into());
+    for obstacle in scene.obstacles.iter() {
+        let size = obstacle.max - obstacle.min;
+        commands
+            .spawn(PbrBundle {
+                mesh: meshes.add(BevyMesh::from(shape::Box::new(size.x, size.y, size.z))),
+                material: obstacle_material.clone(),
+                transform: Transform::from_translation((obstacle.min + obstacle.max) / 2.),
+                ..default()
+            })
+            .insert(SceneObstacle);
+    }
+
+    for probe in scene.probes.iter() {
+        spawn_probe(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            ProbeSeries::new(probe.name.clone(), probe.position),
+        );
+    }
+}
+
+// Spawn the particles of the rows the emitters are due since the last step
+pub fn emitter_system(
+    mut commands: Commands,
+    simulation_time: Res<SimulationTime>,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut active: ResMut<ActiveScene>,
+    mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+) {
+    let ActiveScene {
+        scene, emitters, ..
+    } = &mut *active;
+    let Some(emitters) = emitters else {
+        return;
+    };
+    for id in emitters.emit_due(scene, simulation_time.elapsed, &mut fluid) {
+        let position = fluid.positions[fluid.index_of(id)];
+        spawn_particle_entity(
+            &mut commands,
+            &mut counter,
+            &mut meshes,
+            &mut materials,
+            id,
+            position,
+        );
+    }
+}
+
+// Keep the particles out of the obstacles after every step
+pub fn obstacle_collision_system(
+    active: Res<ActiveScene>,
+    params: Res<SimulationParams>,
+    mut fluid: ResMut<Fluid>,
+) {
+    active.scene.collide_obstacles(&mut fluid, params.coef_rest);
+}
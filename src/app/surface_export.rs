@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use particles::surface::{MeshFormat, SurfaceMesh, SurfaceSeries};
+
+use crate::args::AppArgs;
+use crate::simulation::{Fluid, SimulationParams, SimulationTime};
+
+/*
+ *
+ * Surface Recording in the Windowed App
+ * M starts and stops writing the reconstructed fluid surface of every simulated frame to
+ * numbered OBJ, PLY or glTF files for rendering in Blender. `--surface <obj|ply|glb>` picks the
+ * format and starts recording right away, `--surface-dir <directory>` the directory,
+ * `--surface-normals` adds the normals and `--surface-attributes` the velocity, speed, density
+ * and pressure of the particles under every vertex. The surface written is the one render_mesh
+ * extracted for the frame, it keeps a copy in FluidSurface while recording.
+ *
+ */
+
+const DEFAULT_SURFACE_DIRECTORY: &str = "surface";
+const DEFAULT_SURFACE_FORMAT: MeshFormat = MeshFormat::Glb;
+const SURFACE_SERIES: &str = "surface";
+// Particles are sampled this many smoothing lengths around a vertex, the surface lies where
+// their kernels end
+const ATTRIBUTE_RADIUS: f32 = 2.;
+
+#[derive(Resource)]
+pub struct SurfaceRecorder {
+    pub directory: PathBuf,
+    pub format: MeshFormat,
+    pub normals: bool,
+    pub attributes: bool,
+    pub recording: bool,
+    // Opened when M or --surface records the first frame, so --surface-dir is not made before
+    series: Option<SurfaceSeries>,
+    last_step: Option<u64>,
+}
+
+// Surface render_mesh extracted and the step of the particles it came from
+#[derive(Resource, Default)]
+pub struct FluidSurface {
+    pub step: Option<u64>,
+    pub mesh: SurfaceMesh,
+}
+
+impl SurfaceRecorder {
+    pub fn new(args: &AppArgs) -> SurfaceRecorder {
+        SurfaceRecorder {
+            directory: (args.surface_directory.clone())
+                .unwrap_or(PathBuf::from(DEFAULT_SURFACE_DIRECTORY)),
+            format: args.surface.unwrap_or(DEFAULT_SURFACE_FORMAT),
+            normals: args.surface_normals,
+            attributes: args.surface_attributes,
+            recording: args.surface.is_some(),
+            series: None,
+            last_step: None,
+        }
+    }
+}
+
+// Toggle recording with M
+pub fn surface_toggle_system(input: Res<Input<KeyCode>>, mut recorder: ResMut<SurfaceRecorder>) {
+    if input.just_pressed(KeyCode::M) {
+        recorder.recording = !recorder.recording;
+        info!(
+            "Surface recording {} in {}",
+            if recorder.recording { "on" } else { "off" },
+            recorder.directory.display()
+        );
+    }
+}
+
+// Write the surface render_mesh shows after every step while recording
+pub fn surface_export_system(
+    simulation_time: Res<SimulationTime>,
+    fluid: Res<Fluid>,
+    params: Res<SimulationParams>,
+    mut surface: ResMut<FluidSurface>,
+    mut recorder: ResMut<SurfaceRecorder>,
+) {
+    // The surface of this step is not extracted yet on the frame recording starts
+    if !recorder.recording
+        || recorder.last_step == Some(simulation_time.steps)
+        || surface.step != Some(simulation_time.steps)
+    {
+        return;
+    }
+    recorder.last_step = Some(simulation_time.steps);
+
+    let mesh = &mut surface.mesh;
+    if recorder.attributes {
+        mesh.sample_particles(&fluid, ATTRIBUTE_RADIUS * params.smoothing_length);
+    }
+
+    let recorder = &mut *recorder;
+    let series = match &mut recorder.series {
+        Some(series) => series,
+        None => match SurfaceSeries::new(
+            &recorder.directory,
+            SURFACE_SERIES,
+            recorder.format,
+            recorder.normals,
+        ) {
+            Ok(series) => recorder.series.insert(series),
+            Err(error) => {
+                error!("{}: {error}", recorder.directory.display());
+                recorder.recording = false;
+                return;
+            }
+        },
+    };
+    if let Err(error) = series.write_frame(simulation_time.steps, mesh) {
+        error!("{}: {error}", recorder.directory.display());
+        recorder.recording = false;
+    }
+}
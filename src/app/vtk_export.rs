@@ -0,0 +1,101 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use particles::vtk::VtkSeries;
+
+use crate::args::AppArgs;
+use crate::checkpointing::CheckpointRestored;
+use crate::simulation::{Fluid, SimulationTime};
+
+/*
+ *
+ * VTK Recording in the Windowed App
+ * V starts and stops writing every simulated frame to a VTK file for ParaView, with a .pvd
+ * collection listing them by time. `--vtk` starts recording right away and `--vtk-dir
+ * <directory>` picks the directory. After a checkpoint is restored the collection keeps the
+ * frames up to the checkpoint time and carries on from there, like the headless runner does.
+ *
+ */
+
+const DEFAULT_VTK_DIRECTORY: &str = "vtk";
+const VTK_SERIES: &str = "particles";
+
+#[derive(Resource)]
+pub struct VtkRecorder {
+    pub directory: PathBuf,
+    pub recording: bool,
+    // Opened on the first recorded frame and again after a checkpoint is restored
+    series: Option<VtkSeries>,
+    // Simulated time of the last checkpoint restored, the series carries on from it
+    resumed_at: Option<f64>,
+    last_step: Option<u64>,
+}
+
+impl VtkRecorder {
+    pub fn new(args: &AppArgs) -> VtkRecorder {
+        VtkRecorder {
+            directory: (args.vtk_directory.clone()).unwrap_or(PathBuf::from(DEFAULT_VTK_DIRECTORY)),
+            recording: args.vtk,
+            series: None,
+            resumed_at: None,
+            last_step: None,
+        }
+    }
+}
+
+// Toggle recording with V
+pub fn vtk_toggle_system(input: Res<Input<KeyCode>>, mut recorder: ResMut<VtkRecorder>) {
+    if input.just_pressed(KeyCode::V) {
+        recorder.recording = !recorder.recording;
+        info!(
+            "VTK recording {} in {}",
+            if recorder.recording { "on" } else { "off" },
+            recorder.directory.display()
+        );
+    }
+}
+
+// Write the particles after every step while recording
+pub fn vtk_export_system(
+    simulation_time: Res<SimulationTime>,
+    fluid: Res<Fluid>,
+    mut restored: EventReader<CheckpointRestored>,
+    mut recorder: ResMut<VtkRecorder>,
+) {
+    // The frames after a restored checkpoint are dropped from the collection when it is opened
+    // again
+    if let Some(restored) = restored.iter().last() {
+        recorder.series = None;
+        recorder.resumed_at = Some(restored.time);
+    }
+    if !recorder.recording || recorder.last_step == Some(simulation_time.steps) {
+        return;
+    }
+    recorder.last_step = Some(simulation_time.steps);
+
+    let recorder = &mut *recorder;
+    let series = match &mut recorder.series {
+        Some(series) => series,
+        None => match open_series(&recorder.directory, recorder.resumed_at) {
+            Ok(series) => recorder.series.insert(series),
+            Err(error) => {
+                error!("{}: {error}", recorder.directory.display());
+                recorder.recording = false;
+                return;
+            }
+        },
+    };
+    if let Err(error) = series.write_frame(simulation_time.steps, simulation_time.elapsed, &fluid) {
+        error!("{}: {error}", recorder.directory.display());
+        recorder.recording = false;
+    }
+}
+
+fn open_series(directory: &Path, resumed_at: Option<f64>) -> io::Result<VtkSeries> {
+    match resumed_at {
+        Some(time) => VtkSeries::resume(directory, VTK_SERIES, time),
+        None => VtkSeries::new(directory, VTK_SERIES),
+    }
+}
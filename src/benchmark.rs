@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use particles::sph::{step, ParticleOrder, ParticleSet, SimParams, SolverMode, SolverSettings};
+
+/*
+ *
+ * Headless Benchmark
+ * Runs a dam break on the solver core, once on a single thread and once on all CPU cores, and
+ * reports the time per step and the speedup, and once searching the neighbors every step and
+ * once keeping Verlet neighbor lists. Then steps a 20k particle block stored in a random
+ * order, left as is, sorted by grid cell and sorted along the Morton curve every step or every
+ * few steps, to measure the effect of the memory layout.
+ * Run it with `cargo run --release -- --benchmark`.
+ *
+ */
+
+const BENCHMARK_STEPS: usize = 100;
+const BENCHMARK_DT: f32 = 1. / 60.;
+
+// Particles along x, y and z, spaced at about the rest density
+const BLOCK_SIZE: [usize; 3] = [20, 10, 10];
+const BLOCK_SPACING: f32 = 58.;
+
+// Larger than the box, the steps are too few for the overflowing particles to matter
+const LAYOUT_BLOCK_SIZE: [usize; 3] = [34, 24, 25];
+const LAYOUT_STEPS: usize = 30;
+const LAYOUT_ORDERS: [(ParticleOrder, usize); 4] = [
+    (ParticleOrder::Insertion, 1),
+    (ParticleOrder::Cell, 1),
+    (ParticleOrder::Morton, 1),
+    (ParticleOrder::Morton, 10),
+];
+
+pub fn run_benchmark() {
+    let particle_count = BLOCK_SIZE.iter().product::<usize>();
+    println!(
+        "Benchmark: {particle_count} particles, {BENCHMARK_STEPS} steps, {} threads",
+        rayon::current_num_threads()
+    );
+
+    for mode in [SolverMode::Wcsph, SolverMode::Dfsph] {
+        let serial_settings = SolverSettings {
+            mode,
+            parallel: false,
+            ..default()
+        };
+        let (serial_time, serial_particles) = run(serial_settings);
+        let (parallel_time, parallel_particles) = run(SolverSettings {
+            parallel: true,
+            ..serial_settings
+        });
+
+        let max_difference = serial_particles
+            .positions
+            .iter()
+            .zip(parallel_particles.positions.iter())
+            .map(|(serial, parallel)| serial.distance(*parallel))
+            .fold(0., f32::max);
+
+        println!(
+            "{}: serial {:.2} ms/step, parallel {:.2} ms/step, speedup {:.2}x, max position difference {}",
+            mode.name(),
+            1000. * serial_time.as_secs_f64() / BENCHMARK_STEPS as f64,
+            1000. * parallel_time.as_secs_f64() / BENCHMARK_STEPS as f64,
+            serial_time.as_secs_f64() / parallel_time.as_secs_f64(),
+            max_difference
+        );
+    }
+
+    let settings = SolverSettings {
+        mode: SolverMode::Wcsph,
+        ..default()
+    };
+    let (search_time, _) = run(SolverSettings {
+        neighbor_skin: 0.,
+        ..settings
+    });
+    let (cached_time, cached_particles) = run(settings);
+    let neighbor_list = cached_particles.neighbor_list();
+    println!(
+        "WCSPH: neighbor search every step {:.2} ms/step, neighbor lists with a skin of {} smoothing lengths {:.2} ms/step ({} rebuilds), speedup {:.2}x",
+        1000. * search_time.as_secs_f64() / BENCHMARK_STEPS as f64,
+        settings.neighbor_skin,
+        1000. * cached_time.as_secs_f64() / BENCHMARK_STEPS as f64,
+        neighbor_list.builds,
+        search_time.as_secs_f64() / cached_time.as_secs_f64(),
+    );
+
+    let particle_count = LAYOUT_BLOCK_SIZE.iter().product::<usize>();
+    println!(
+        "Memory layout: {particle_count} particles stored in a random order, {LAYOUT_STEPS} steps"
+    );
+    let mut unsorted_time = None;
+    for (order, interval) in LAYOUT_ORDERS {
+        let (time, _) = run_layout(order, interval);
+        let unsorted_time = *unsorted_time.get_or_insert(time);
+        println!(
+            "WCSPH, {} order, reordered every {interval} steps: {:.2} ms/step, speedup {:.2}x",
+            order.name(),
+            1000. * time.as_secs_f64() / LAYOUT_STEPS as f64,
+            unsorted_time.as_secs_f64() / time.as_secs_f64(),
+        );
+    }
+}
+
+// Step the dam break and return the time taken and the final particles
+fn run(settings: SolverSettings) -> (Duration, ParticleSet) {
+    let mut particles = block(BLOCK_SIZE, corner());
+
+    let start = Instant::now();
+    for _ in 0..BENCHMARK_STEPS {
+        step(
+            &mut particles,
+            &settings,
+            &SimParams::default(),
+            BENCHMARK_DT,
+        );
+    }
+    (start.elapsed(), particles)
+}
+
+// Step the large block, stored in a random order, reordering it every interval steps
+fn run_layout(particle_order: ParticleOrder, reorder_interval: usize) -> (Duration, ParticleSet) {
+    let settings = SolverSettings {
+        mode: SolverMode::Wcsph,
+        particle_order,
+        reorder_interval,
+        // Reordering rebuilds the neighbor lists, search every step to only measure the layout
+        neighbor_skin: 0.,
+        ..default()
+    };
+    let size = LAYOUT_BLOCK_SIZE.map(|count| count as f32);
+    let ordered = block(LAYOUT_BLOCK_SIZE, -BLOCK_SPACING * Vec3::from(size) / 2.);
+
+    let mut order: Vec<usize> = (0..ordered.len()).collect();
+    order.shuffle(&mut StdRng::seed_from_u64(1));
+    let mut particles = ParticleSet::default();
+    for i in order {
+        particles.push(ordered.positions[i], ordered.velocities[i]);
+    }
+
+    let start = Instant::now();
+    for _ in 0..LAYOUT_STEPS {
+        step(
+            &mut particles,
+            &settings,
+            &SimParams::default(),
+            BENCHMARK_DT,
+        );
+    }
+    (start.elapsed(), particles)
+}
+
+// Corner of the box, offset so the particles are half a spacing from the walls
+fn corner() -> Vec3 {
+    -SimParams::default().box_size / 2. + BLOCK_SPACING / 2.
+}
+
+// Block of fluid at rest spacing starting at a corner
+fn block(size: [usize; 3], corner: Vec3) -> ParticleSet {
+    let mut particles = ParticleSet::default();
+    for x in 0..size[0] {
+        for y in 0..size[1] {
+            for z in 0..size[2] {
+                let position = corner + BLOCK_SPACING * Vec3::new(x as f32, y as f32, z as f32);
+                particles.push(position, Vec3::ZERO);
+            }
+        }
+    }
+    particles
+}
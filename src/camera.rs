@@ -75,8 +75,7 @@ pub fn pan_orbit_camera(
             any = true;
             let window = get_primary_window_size(&windows);
             let delta_x = {
-                let delta = rotation_move.x / window.x * std::f32::consts::PI * 2.0;
-                delta
+                rotation_move.x / window.x * std::f32::consts::PI * 2.0
                 // if pan_orbit.upside_down {
                 //     -delta
                 // } else {
@@ -88,7 +87,7 @@ pub fn pan_orbit_camera(
             let yaw = Quat::from_axis_angle(Vec3::Y, -delta_x);
             let pitch = Quat::from_rotation_x(-delta_y);
             transform.rotation = yaw * transform.rotation; // rotate around global y axis
-            transform.rotation = transform.rotation * pitch; // rotate around local x axis
+            transform.rotation *= pitch; // rotate around local x axis
         } else if pan.length_squared() > 0.0 {
             any = true;
             // make panning distance independent of resolution and FOV,
@@ -122,8 +121,7 @@ pub fn pan_orbit_camera(
 
 fn get_primary_window_size(windows: &Query<&Window, With<PrimaryWindow>>) -> Vec2 {
     let _window = windows.get_single();
-    let window = Vec2::new(1200. as f32, 400. as f32);
-    window
+    Vec2::new(1200., 400.)
 }
 
 /// Spawn a camera like this
@@ -0,0 +1,373 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+use serde::Serialize;
+
+use crate::sph::{ParticleSet, SimParams, SolverSettings};
+
+/*
+ *
+ * Checkpoints
+ * The whole state of a run in a binary file: the simulation time and step, the solver settings,
+ * the parameters, every particle in the order it is stored and every body. Restoring one and
+ * stepping on gives the same particles bit for bit as never stopping, since the neighbor lists
+ * are rebuilt to the same sorted pairs.
+ * The file starts with a magic number and CHECKPOINT_VERSION, which changes whenever the layout
+ * does, so a checkpoint from another version is refused instead of read as garbage. Numbers are
+ * little endian, the settings and parameters are stored as RON text.
+ *
+ */
+
+pub const CHECKPOINT_VERSION: u32 = 2;
+const MAGIC: &[u8; 8] = b"SPHCKPT\0";
+
+// A rigid body moved by the fluid, like the Orion capsule
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct BodyState {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    // Force the particles applied since the body last moved
+    pub force: Vec3,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Checkpoint {
+    // Simulated seconds and steps taken since the run started
+    pub time: f64,
+    pub steps: u64,
+    pub settings: SolverSettings,
+    pub params: SimParams,
+    pub particles: ParticleSet,
+    pub bodies: Vec<BodyState>,
+}
+
+// Why a checkpoint could not be saved or loaded, displayed with the file
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(PathBuf, io::Error),
+    NotACheckpoint(PathBuf),
+    Version { path: PathBuf, found: u32 },
+    Corrupt(PathBuf, String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(path, error) => write!(f, "{}: {error}", path.display()),
+            CheckpointError::NotACheckpoint(path) => {
+                write!(f, "{}: not a checkpoint file", path.display())
+            }
+            CheckpointError::Version { path, found } => write!(
+                f,
+                "{}: checkpoint version {found} cannot be loaded, this build reads version {CHECKPOINT_VERSION}",
+                path.display()
+            ),
+            CheckpointError::Corrupt(path, message) => {
+                write!(f, "{}: corrupt checkpoint, {message}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl Checkpoint {
+    // Write the checkpoint next to the path first and then rename it, so a crash while saving
+    // leaves the previous checkpoint intact
+    pub fn save(&self, path: &Path) -> Result<(), CheckpointError> {
+        let error = |error| CheckpointError::Io(path.to_path_buf(), error);
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let mut writer = BufWriter::new(File::create(&partial).map_err(error)?);
+        self.write(&mut writer).map_err(error)?;
+        writer
+            .into_inner()
+            .map_err(|into_inner| error(into_inner.into_error()))?
+            .sync_all()
+            .map_err(error)?;
+        fs::rename(&partial, path).map_err(error)
+    }
+
+    pub fn load(path: &Path) -> Result<Checkpoint, CheckpointError> {
+        let file =
+            File::open(path).map_err(|error| CheckpointError::Io(path.to_path_buf(), error))?;
+        Checkpoint::read(&mut BufReader::new(file), path)
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.time.to_le_bytes())?;
+        writer.write_all(&self.steps.to_le_bytes())?;
+        write_text(writer, &to_ron(&self.settings)?)?;
+        write_text(writer, &to_ron(&self.params)?)?;
+        // The box size is not part of the serialized parameters, scenes set it from the domain
+        write_vec3(writer, self.params.box_size)?;
+
+        let particles = &self.particles;
+        write_len(writer, particles.len())?;
+        write_len(writer, particles.steps_since_reorder)?;
+        write_len(writer, particles.neighbor_list.builds)?;
+        write_len(writer, particles.neighbor_list.uses)?;
+        for i in 0..particles.len() {
+            write_len(writer, particles.ids[i])?;
+            write_vec3(writer, particles.positions[i])?;
+            write_vec3(writer, particles.velocities[i])?;
+            writer.write_all(&particles.densities[i].to_le_bytes())?;
+            writer.write_all(&particles.pressures[i].to_le_bytes())?;
+            write_vec3(writer, particles.forces[i])?;
+            // The forces of the last step, which the exports show
+            write_vec3(writer, particles.last_forces[i])?;
+        }
+
+        write_len(writer, self.bodies.len())?;
+        for body in self.bodies.iter() {
+            write_vec3(writer, body.position)?;
+            write_vec3(writer, body.velocity)?;
+            write_vec3(writer, body.force)?;
+        }
+        Ok(())
+    }
+
+    // Read a checkpoint, the path names the file in errors
+    pub fn read(reader: &mut impl Read, path: &Path) -> Result<Checkpoint, CheckpointError> {
+        let corrupt = |message: String| CheckpointError::Corrupt(path.to_path_buf(), message);
+        let truncated = |error: io::Error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt("the file ends early".to_string()),
+            _ => CheckpointError::Io(path.to_path_buf(), error),
+        };
+
+        let mut magic = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| CheckpointError::NotACheckpoint(path.to_path_buf()))?;
+        if &magic != MAGIC {
+            return Err(CheckpointError::NotACheckpoint(path.to_path_buf()));
+        }
+        let version = u32::from_le_bytes(read_bytes(reader).map_err(truncated)?);
+        if version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::Version {
+                path: path.to_path_buf(),
+                found: version,
+            });
+        }
+
+        let time = f64::from_le_bytes(read_bytes(reader).map_err(truncated)?);
+        let steps = u64::from_le_bytes(read_bytes(reader).map_err(truncated)?);
+        let settings: SolverSettings = ron::from_str(&read_text(reader).map_err(truncated)?)
+            .map_err(|error| corrupt(format!("settings: {error}")))?;
+        let mut params: SimParams = ron::from_str(&read_text(reader).map_err(truncated)?)
+            .map_err(|error| corrupt(format!("parameters: {error}")))?;
+        params.box_size = read_vec3(reader).map_err(truncated)?;
+        params.update_kernels();
+
+        let mut particles = ParticleSet::default();
+        let count = read_len(reader).map_err(truncated)?;
+        particles.steps_since_reorder = read_len(reader).map_err(truncated)?;
+        particles.neighbor_list.builds = read_len(reader).map_err(truncated)?;
+        particles.neighbor_list.uses = read_len(reader).map_err(truncated)?;
+        for _ in 0..count {
+            particles.ids.push(read_len(reader).map_err(truncated)?);
+            particles
+                .positions
+                .push(read_vec3(reader).map_err(truncated)?);
+            particles
+                .velocities
+                .push(read_vec3(reader).map_err(truncated)?);
+            particles
+                .densities
+                .push(f32::from_le_bytes(read_bytes(reader).map_err(truncated)?));
+            particles
+                .pressures
+                .push(f32::from_le_bytes(read_bytes(reader).map_err(truncated)?));
+            particles.forces.push(read_vec3(reader).map_err(truncated)?);
+            particles
+                .last_forces
+                .push(read_vec3(reader).map_err(truncated)?);
+        }
+
+        // Every identifier below the particle count appears exactly once
+        (particles.index_ids()).map_err(|id| corrupt(format!("invalid particle id {id}")))?;
+
+        let body_count = read_len(reader).map_err(truncated)?;
+        let mut bodies = Vec::new();
+        for _ in 0..body_count {
+            bodies.push(BodyState {
+                position: read_vec3(reader).map_err(truncated)?,
+                velocity: read_vec3(reader).map_err(truncated)?,
+                force: read_vec3(reader).map_err(truncated)?,
+            });
+        }
+
+        Ok(Checkpoint {
+            time,
+            steps,
+            settings,
+            params,
+            particles,
+            bodies,
+        })
+    }
+}
+
+fn to_ron(value: &impl Serialize) -> io::Result<String> {
+    ron::to_string(value).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub(crate) fn write_len(writer: &mut impl Write, len: usize) -> io::Result<()> {
+    writer.write_all(&(len as u64).to_le_bytes())
+}
+
+pub(crate) fn write_vec3(writer: &mut impl Write, value: Vec3) -> io::Result<()> {
+    for component in value.to_array() {
+        writer.write_all(&component.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_text(writer: &mut impl Write, text: &str) -> io::Result<()> {
+    write_len(writer, text.len())?;
+    writer.write_all(text.as_bytes())
+}
+
+pub(crate) fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub(crate) fn read_len(reader: &mut impl Read) -> io::Result<usize> {
+    let len = u64::from_le_bytes(read_bytes(reader)?);
+    usize::try_from(len).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub(crate) fn read_vec3(reader: &mut impl Read) -> io::Result<Vec3> {
+    let mut components = [0.; 3];
+    for component in components.iter_mut() {
+        *component = f32::from_le_bytes(read_bytes(reader)?);
+    }
+    Ok(Vec3::from_array(components))
+}
+
+fn read_text(reader: &mut impl Read) -> io::Result<String> {
+    let len = read_len(reader)?;
+    let mut text = Vec::new();
+    reader.take(len as u64).read_to_end(&mut text)?;
+    if text.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::FluidBlock;
+    use crate::sph::{step, SolverMode};
+
+    const DT: f32 = 1. / 60.;
+
+    fn dam_break() -> Checkpoint {
+        let mut particles = ParticleSet::default();
+        for position in FluidBlock::default().positions() {
+            particles.push(position, Vec3::ZERO);
+        }
+        Checkpoint {
+            settings: SolverSettings {
+                mode: SolverMode::Dfsph,
+                ..Default::default()
+            },
+            particles,
+            bodies: vec![BodyState {
+                position: Vec3::new(-300., 1500., 0.),
+                velocity: Vec3::new(0., -1000., 0.),
+                force: Vec3::X,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn run(checkpoint: &mut Checkpoint, steps: usize) {
+        for _ in 0..steps {
+            step(
+                &mut checkpoint.particles,
+                &checkpoint.settings,
+                &checkpoint.params,
+                DT,
+            );
+            checkpoint.time += DT as f64;
+            checkpoint.steps += 1;
+        }
+    }
+
+    fn round_trip(checkpoint: &Checkpoint) -> Result<Checkpoint, CheckpointError> {
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes).unwrap();
+        Checkpoint::read(&mut bytes.as_slice(), Path::new("test.ckpt"))
+    }
+
+    #[test]
+    fn resuming_matches_running_on() {
+        let mut uninterrupted = dam_break();
+        run(&mut uninterrupted, 27);
+
+        // Stop between two reorders, with the neighbor lists partly used
+        let mut stopped = dam_break();
+        run(&mut stopped, 13);
+        let mut resumed = round_trip(&stopped).unwrap();
+        assert_eq!(resumed.time, stopped.time);
+        assert_eq!(resumed.settings, stopped.settings);
+        assert_eq!(resumed.particles.last_forces, stopped.particles.last_forces);
+        assert_eq!(resumed.params, stopped.params);
+        assert_eq!(resumed.bodies, stopped.bodies);
+        run(&mut resumed, 14);
+
+        assert_eq!(resumed.steps, 27);
+        assert_eq!(resumed.particles.ids, uninterrupted.particles.ids);
+        assert_eq!(
+            resumed.particles.positions,
+            uninterrupted.particles.positions
+        );
+        assert_eq!(
+            resumed.particles.velocities,
+            uninterrupted.particles.velocities
+        );
+        assert_eq!(
+            resumed.particles.pressures,
+            uninterrupted.particles.pressures
+        );
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let mut bytes = Vec::new();
+        dam_break().write(&mut bytes).unwrap();
+        let path = Path::new("test.ckpt");
+
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+        let error = Checkpoint::read(&mut newer.as_slice(), path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "test.ckpt: checkpoint version {} cannot be loaded, this build reads version {CHECKPOINT_VERSION}",
+                CHECKPOINT_VERSION + 1
+            )
+        );
+
+        let error = Checkpoint::read(&mut "(mode: Dfsph)".as_bytes(), path).unwrap_err();
+        assert!(matches!(error, CheckpointError::NotACheckpoint(_)));
+
+        let truncated = &bytes[..bytes.len() - 10];
+        let error = Checkpoint::read(&mut &truncated[..], path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "test.ckpt: corrupt checkpoint, the file ends early"
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use glam::{DVec3, Vec3};
+
+use crate::checkpoint::BodyState;
+use crate::csv_log;
+use crate::sph::{compression, ParticleSet, SimParams};
+
+/*
+ *
+ * Conservation Diagnostics
+ * How well a run keeps what the physics conserves: the mass, the kinetic and gravitational
+ * potential energy and the linear momentum of the fluid and of the bodies, the compression of
+ * the fluid against the base density and the top speeds. The potential energy is measured from
+ * the floor of the box, where it is zero. Without walls and viscosity the total energy
+ * would stay the same, so a change of parameters that makes it drift faster or the density
+ * error grow made the solver worse.
+ * ConservationLog writes the measurements as a CSV time series.
+ *
+ */
+
+const CSV_HEADER: &str =
+    "step,time,mass,kinetic_energy,potential_energy,total_energy,momentum_x,momentum_y,momentum_z,\
+    fluid_kinetic_energy,fluid_potential_energy,body_kinetic_energy,body_potential_energy,\
+    mean_density_error,max_density_error,fluid_max_speed,body_max_speed";
+
+// Sums over the particles of the fluid or over the bodies
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Totals {
+    pub mass: f32,
+    pub kinetic_energy: f32,
+    pub potential_energy: f32,
+    pub momentum: Vec3,
+    pub max_speed: f32,
+}
+
+impl Totals {
+    // Sum every (position, velocity) with the same mass, the floor is at the given height
+    fn measure(
+        states: impl Iterator<Item = (Vec3, Vec3)>,
+        mass: f32,
+        gravity: f32,
+        floor: f32,
+    ) -> Totals {
+        let mut count = 0;
+        let mut kinetic_energy = 0.;
+        let mut potential_energy = 0.;
+        let mut momentum = DVec3::ZERO;
+        let mut max_speed = 0f32;
+        for (position, velocity) in states {
+            count += 1;
+            kinetic_energy += 0.5 * mass as f64 * velocity.length_squared() as f64;
+            potential_energy += (mass * -gravity * (position.y - floor)) as f64;
+            momentum += mass as f64 * velocity.as_dvec3();
+            max_speed = max_speed.max(velocity.length());
+        }
+        Totals {
+            mass: count as f32 * mass,
+            kinetic_energy: kinetic_energy as f32,
+            potential_energy: potential_energy as f32,
+            momentum: momentum.as_vec3(),
+            max_speed,
+        }
+    }
+
+    pub fn energy(&self) -> f32 {
+        self.kinetic_energy + self.potential_energy
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Conservation {
+    pub fluid: Totals,
+    pub bodies: Totals,
+    // Relative compression above the base density, averaged over the particles and the largest,
+    // the same density error the pressure solvers drive down
+    pub mean_density_error: f32,
+    pub max_density_error: f32,
+}
+
+impl Conservation {
+    pub fn measure(particles: &ParticleSet, bodies: &[BodyState], params: &SimParams) -> Self {
+        let floor = -params.box_size.y / 2.;
+        let fluid = Totals::measure(
+            (particles.positions.iter().copied()).zip(particles.velocities.iter().copied()),
+            params.particle_mass,
+            params.gravity,
+            floor,
+        );
+        let bodies = Totals::measure(
+            bodies.iter().map(|body| (body.position, body.velocity)),
+            params.body_mass,
+            params.gravity,
+            floor,
+        );
+
+        // A replayed frame may come without densities
+        let errors = (particles.densities.iter())
+            .map(|&density| compression(density, params) / params.base_density);
+        let (total_error, max_density_error) = errors.fold((0., 0f32), |(total, max), error| {
+            (total + error, max.max(error))
+        });
+        let mean_density_error = match particles.densities.len() {
+            0 => 0.,
+            count => total_error / count as f32,
+        };
+
+        Conservation {
+            fluid,
+            bodies,
+            mean_density_error,
+            max_density_error,
+        }
+    }
+
+    pub fn mass(&self) -> f32 {
+        self.fluid.mass + self.bodies.mass
+    }
+
+    pub fn energy(&self) -> f32 {
+        self.fluid.energy() + self.bodies.energy()
+    }
+
+    pub fn momentum(&self) -> Vec3 {
+        self.fluid.momentum + self.bodies.momentum
+    }
+}
+
+// CSV time series of the measurements, one row per call to write()
+pub struct ConservationLog {
+    writer: BufWriter<File>,
+}
+
+impl ConservationLog {
+    // Open the CSV of the measurements, see csv_log::open()
+    pub fn create(path: &Path, append: bool) -> io::Result<ConservationLog> {
+        let writer = csv_log::open(path, CSV_HEADER, append)?;
+        Ok(ConservationLog { writer })
+    }
+
+    pub fn write(&mut self, step: u64, time: f64, conservation: &Conservation) -> io::Result<()> {
+        let Conservation { fluid, bodies, .. } = conservation;
+        let momentum = conservation.momentum();
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            step,
+            time,
+            conservation.mass(),
+            fluid.kinetic_energy + bodies.kinetic_energy,
+            fluid.potential_energy + bodies.potential_energy,
+            conservation.energy(),
+            momentum.x,
+            momentum.y,
+            momentum.z,
+            fluid.kinetic_energy,
+            fluid.potential_energy,
+            bodies.kinetic_energy,
+            bodies.potential_energy,
+            conservation.mean_density_error,
+            conservation.max_density_error,
+            fluid.max_speed,
+            bodies.max_speed
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_add_up_the_particles_and_bodies() {
+        let params = SimParams::default();
+        let floor = -params.box_size.y / 2.;
+        let mut particles = ParticleSet::default();
+        particles.push(Vec3::new(0., floor + 10., 0.), Vec3::new(3., 4., 0.));
+        particles.push(Vec3::new(5., floor, 0.), Vec3::new(-3., 0., 0.));
+        let body = BodyState {
+            position: Vec3::new(0., floor + 100., 0.),
+            velocity: Vec3::new(0., -2., 0.),
+            force: Vec3::ZERO,
+        };
+
+        let conservation = Conservation::measure(&particles, &[body], &params);
+        let mass = params.particle_mass;
+        assert_eq!(conservation.fluid.mass, 2. * mass);
+        assert_eq!(conservation.mass(), 2. * mass + params.body_mass);
+        assert_eq!(conservation.fluid.kinetic_energy, 0.5 * mass * (25. + 9.));
+        assert_eq!(
+            conservation.fluid.potential_energy,
+            mass * -params.gravity * 10.
+        );
+        assert_eq!(conservation.fluid.momentum, Vec3::new(0., 4. * mass, 0.));
+        assert_eq!(conservation.fluid.max_speed, 5.);
+        assert_eq!(conservation.bodies.max_speed, 2.);
+        assert_eq!(
+            conservation.momentum(),
+            Vec3::new(0., 4. * mass - 2. * params.body_mass, 0.)
+        );
+    }
+
+    #[test]
+    fn only_compression_counts_as_density_error() {
+        let params = SimParams::default();
+        let mut particles = ParticleSet::default();
+        for _ in 0..4 {
+            particles.push(Vec3::ZERO, Vec3::ZERO);
+        }
+        let base = params.base_density;
+        particles.densities = vec![base * 1.1, base * 1.02, base, base * 0.5];
+
+        let conservation = Conservation::measure(&particles, &[], &params);
+        assert!((conservation.max_density_error - 0.1).abs() < 1e-5);
+        assert!((conservation.mean_density_error - 0.03).abs() < 1e-5);
+
+        particles.densities.clear();
+        let conservation = Conservation::measure(&particles, &[], &params);
+        assert_eq!(conservation.mean_density_error, 0.);
+    }
+
+    #[test]
+    fn log_keeps_its_rows_when_appending() {
+        let path = std::env::temp_dir().join(format!("conservation_{}.csv", std::process::id()));
+        let conservation = Conservation::default();
+        let mut log = ConservationLog::create(&path, false).unwrap();
+        log.write(10, 0.5, &conservation).unwrap();
+        log.flush().unwrap();
+        drop(log);
+        let mut log = ConservationLog::create(&path, true).unwrap();
+        log.write(20, 1., &conservation).unwrap();
+        log.flush().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("step,time,mass,"));
+        assert_eq!(lines[0].split(',').count(), lines[2].split(',').count());
+        assert!(lines[2].starts_with("20,1,"));
+    }
+}
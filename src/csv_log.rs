@@ -0,0 +1,61 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/*
+ *
+ * CSV Logs
+ * The time series written as a run goes, the conservation log, the probe log and the headless
+ * diagnostics, open their file here. A resumed run appends to the file so it keeps the rows
+ * written before the checkpoint, and the header only goes into a file that is still empty.
+ *
+ */
+
+// Start a CSV file with its header, or add to an existing one when appending
+pub fn open(path: &Path, header: &str, append: bool) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let empty = file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if empty {
+        writeln!(writer, "{header}")?;
+    }
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_row(path: &Path, append: bool, row: &str) {
+        let mut writer = open(path, "a,b", append).unwrap();
+        writeln!(writer, "{row}").unwrap();
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn appending_keeps_the_rows_and_the_header_once() {
+        let path = std::env::temp_dir().join(format!("csv_log_append_{}.csv", std::process::id()));
+        write_row(&path, false, "1,2");
+        write_row(&path, true, "3,4");
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text, "a,b\n1,2\n3,4\n");
+    }
+
+    #[test]
+    fn a_new_log_replaces_the_old_rows() {
+        let path = std::env::temp_dir().join(format!("csv_log_new_{}.csv", std::process::id()));
+        write_row(&path, false, "1,2");
+        write_row(&path, false, "3,4");
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text, "a,b\n3,4\n");
+    }
+}
@@ -0,0 +1,168 @@
+use glam::Vec3;
+
+use crate::sph::{compression, FluidState, SolverSettings, SolverStats};
+
+/*
+ *
+ * Divergence-Free SPH (DFSPH) Implementation
+ * Bender & Koschier 2015, https://www.dankoschier.de/resources/papers/BK15.pdf
+ *
+ */
+
+// Compute the DFSPH factor (alpha) of every particle, it only depends on the particle positions
+// and densities so it is shared by the divergence and constant density solves
+fn compute_factors(fluid: &FluidState) -> Vec<f32> {
+    let mut factors = vec![0.; fluid.positions.len()];
+
+    for (i, factor) in factors.iter_mut().enumerate() {
+        let mut gradient_sum = Vec3::ZERO;
+        let mut gradient_squared_sum = 0.;
+
+        for &j in fluid.neighbors[i].iter() {
+            let gradient = fluid
+                .params
+                .pressure_kernel_gradient(fluid.positions[i] - fluid.positions[j]);
+            gradient_sum += gradient;
+            gradient_squared_sum += gradient.length_squared();
+        }
+
+        let denominator = gradient_sum.length_squared() + gradient_squared_sum;
+        // A particle without neighbors can not be corrected
+        if denominator > 0. {
+            *factor = fluid.densities[i] / denominator;
+        }
+    }
+    factors
+}
+
+// Rate of change of the density of a particle for the current velocities
+fn density_change_rate(i: usize, fluid: &FluidState) -> f32 {
+    let mut rate = 0.;
+    for &j in fluid.neighbors[i].iter() {
+        let gradient = fluid
+            .params
+            .pressure_kernel_gradient(fluid.positions[i] - fluid.positions[j]);
+        rate += (fluid.velocities[i] - fluid.velocities[j]).dot(gradient);
+    }
+    rate
+}
+
+// Apply the velocity change that results from the stiffness value of every particle
+fn apply_stiffness(fluid: &mut FluidState, stiffness: &[f32], dt: f32) {
+    for i in 0..fluid.positions.len() {
+        let mut dv = Vec3::ZERO;
+        for &j in fluid.neighbors[i].iter() {
+            let gradient = fluid
+                .params
+                .pressure_kernel_gradient(fluid.positions[i] - fluid.positions[j]);
+            dv -=
+                (stiffness[i] / fluid.densities[i] + stiffness[j] / fluid.densities[j]) * gradient;
+        }
+        fluid.velocities[i] += dt * dv;
+    }
+}
+
+// Divergence-free solve: correct the velocities so that the density stops changing.
+// Returns the number of iterations and the average relative density change over one step.
+fn correct_divergence_error(
+    fluid: &mut FluidState,
+    factors: &[f32],
+    dt: f32,
+    settings: &SolverSettings,
+) -> (usize, f32) {
+    let count = fluid.positions.len();
+    let mut stiffness = vec![0.; count];
+    let mut iterations = 0;
+    let mut average_error = 0.;
+
+    while iterations < settings.max_iterations {
+        let mut total_error = 0.;
+        for (i, particle_stiffness) in stiffness.iter_mut().enumerate() {
+            // A density growing towards compression, like compression() for the density itself
+            let rate = density_change_rate(i, fluid).max(0.);
+            *particle_stiffness = rate * factors[i] / dt;
+            total_error += rate;
+        }
+        average_error = dt * total_error / (count as f32 * fluid.params.base_density);
+
+        if average_error <= settings.max_divergence_error && iterations >= settings.min_iterations {
+            break;
+        }
+
+        apply_stiffness(fluid, &stiffness, dt);
+        iterations += 1;
+    }
+    (iterations, average_error)
+}
+
+// Constant density solve: correct the predicted velocities so that the density after the
+// position update is the base density. Returns the number of iterations, the average relative
+// density error and the pressure of every particle.
+fn correct_density_error(
+    fluid: &mut FluidState,
+    factors: &[f32],
+    dt: f32,
+    settings: &SolverSettings,
+) -> (usize, f32, Vec<f32>) {
+    let count = fluid.positions.len();
+    let base_density = fluid.params.base_density;
+    let mut stiffness = vec![0.; count];
+    let mut pressures = vec![0.; count];
+    let mut iterations = 0;
+    let mut average_error = 0.;
+
+    while iterations < settings.max_iterations {
+        let mut total_error = 0.;
+        for (i, particle_stiffness) in stiffness.iter_mut().enumerate() {
+            let rate = density_change_rate(i, fluid);
+            let error = compression(fluid.densities[i] + dt * rate, &fluid.params);
+            *particle_stiffness = error * factors[i] / (dt * dt);
+            total_error += error;
+        }
+        average_error = total_error / (count as f32 * base_density);
+
+        if average_error <= settings.max_density_error && iterations >= settings.min_iterations {
+            break;
+        }
+
+        apply_stiffness(fluid, &stiffness, dt);
+        for (i, pressure) in pressures.iter_mut().enumerate() {
+            *pressure += stiffness[i] * fluid.densities[i];
+        }
+        iterations += 1;
+    }
+    (iterations, average_error, pressures)
+}
+
+// Run the divergence-free solve on the current velocities, then the constant density solve on
+// the velocities predicted from the non-pressure accelerations. The corrected velocities are left
+// in the fluid state. Returns the pressure acceleration and the pressure of every particle.
+pub fn solve(
+    fluid: &mut FluidState,
+    non_pressure_accelerations: &[Vec3],
+    dt: f32,
+    settings: &SolverSettings,
+    stats: &mut SolverStats,
+) -> (Vec<Vec3>, Vec<f32>) {
+    let factors = compute_factors(fluid);
+    (stats.divergence_iterations, stats.divergence_error) =
+        correct_divergence_error(fluid, &factors, dt, settings);
+    let corrected_velocities = fluid.velocities.clone();
+
+    for (velocity, acceleration) in fluid.velocities.iter_mut().zip(non_pressure_accelerations) {
+        *velocity += dt * *acceleration;
+    }
+    let predicted_velocities = fluid.velocities.clone();
+    let pressures;
+    (stats.density_iterations, stats.density_error, pressures) =
+        correct_density_error(fluid, &factors, dt, settings);
+
+    let accelerations = fluid
+        .velocities
+        .iter()
+        .zip(predicted_velocities.iter())
+        .map(|(velocity, predicted)| (*velocity - *predicted) / dt)
+        .collect();
+    fluid.velocities = corrected_velocities;
+    (accelerations, pressures)
+}
@@ -0,0 +1,497 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+use bevy::time::{TimeSystem, TimeUpdateStrategy};
+
+use particles::checkpoint::{BodyState, Checkpoint};
+use particles::conservation::ConservationLog;
+use particles::csv_log;
+use particles::probe::{sample_probes, ProbeLog, ProbeSeries};
+use particles::scene::{EmitterState, Scene};
+use particles::sph::ParticleSet;
+use particles::vtk::VtkSeries;
+
+mod simulation;
+use simulation::body_collision_mesh;
+use simulation::body_movement_system;
+use simulation::body_wall_collision_system;
+use simulation::box_collision_system;
+use simulation::conservation_system;
+use simulation::simulation_step_system;
+use simulation::Body;
+use simulation::BoxCollision;
+use simulation::Fluid;
+use simulation::SimulationConservation;
+use simulation::SimulationParams;
+use simulation::SimulationSettings;
+use simulation::SimulationStats;
+use simulation::SimulationTime;
+
+/*
+ *
+ * Headless Runner
+ * Runs a scene file for a number of steps or simulated seconds without a window, on
+ * MinimalPlugins and the same systems as the windowed app. The clock advances by a fixed time
+ * step every frame, and every few steps the particles are written to a CSV snapshot and a row
+ * is added to diagnostics.csv in the output directory.
+ * With --vtk every snapshot is also written as a VTK file, listed in particles.pvd for ParaView.
+ * With --conservation the energy, momentum, mass and density error of every snapshot are also
+ * added to conservation.csv.
+ * The probes of the scene are sampled after every step and written to probes.csv.
+ * With --checkpoint-every the whole state is also saved to checkpoint.ckpt in the output
+ * directory, and a run that stopped is picked up again with the same command plus --resume.
+ * Run it with `cargo run --release --bin headless -- --scene scenes/dam_break.ron --time 10`.
+ *
+ */
+
+const USAGE: &str = "usage: headless --scene <file> (--steps <n> | --time <seconds>) [--dt <seconds>] [--interval <steps>] [--output <directory>] [--vtk] [--conservation] [--checkpoint-every <steps>] [--resume <checkpoint>]";
+
+const DEFAULT_DT: f32 = 1. / 60.;
+// Longest time step, the clock counts the frames in Durations of it
+const MAX_DT: f32 = 1.;
+const DEFAULT_INTERVAL: u64 = 10;
+const DEFAULT_OUTPUT: &str = "output";
+const CHECKPOINT_FILE: &str = "checkpoint.ckpt";
+const VTK_SERIES: &str = "particles";
+const CONSERVATION_FILE: &str = "conservation.csv";
+const PROBE_FILE: &str = "probes.csv";
+const DIAGNOSTICS_HEADER: &str = "step,time,particles,max_speed,divergence_iterations,density_iterations,divergence_error,density_error,neighbor_builds,wall_time";
+
+#[derive(Resource)]
+struct HeadlessRun {
+    scene_path: PathBuf,
+    scene: Scene,
+    dt: f32,
+    // Step the run stops at, counted from the start of the scene
+    steps: u64,
+    // Steps between two snapshots, and between two checkpoints
+    interval: u64,
+    checkpoint_interval: Option<u64>,
+    output: PathBuf,
+    // VTK files written next to the CSV snapshots
+    vtk: Option<VtkSeries>,
+    conservation: Option<ConservationLog>,
+    // Probes of the scene and where their samples go, when it has any
+    probes: Vec<ProbeSeries>,
+    probe_log: Option<ProbeLog>,
+    // Checkpoint the run starts from instead of the start of the scene
+    resume: Option<Checkpoint>,
+    // Rows the emitters added so far
+    emitters: EmitterState,
+    diagnostics: BufWriter<File>,
+    started: Instant,
+}
+
+fn main() {
+    let run = parse_args().unwrap_or_else(|error| {
+        eprintln!("{error}\n{USAGE}");
+        std::process::exit(2);
+    });
+    println!(
+        "{}: {} steps of {} s, snapshots every {} steps in {}",
+        run.scene_path.display(),
+        run.steps,
+        run.dt,
+        run.interval,
+        run.output.display()
+    );
+
+    App::new()
+        .add_plugins(MinimalPlugins)
+        // The capsules collide with the particles through their mesh
+        .add_plugin(AssetPlugin::default())
+        .add_asset::<BevyMesh>()
+        .insert_resource(run)
+        .init_resource::<SimulationSettings>()
+        .init_resource::<SimulationStats>()
+        .init_resource::<Fluid>()
+        .init_resource::<SimulationParams>()
+        .init_resource::<SimulationTime>()
+        .init_resource::<SimulationConservation>()
+        .add_startup_system(setup_scene_system)
+        .add_system(
+            fixed_clock_system
+                .in_base_set(CoreSet::First)
+                .before(TimeSystem),
+        )
+        .add_system(emitter_system.before(simulation_step_system))
+        .add_system(box_collision_system.before(simulation_step_system))
+        .add_system(simulation_step_system)
+        .add_system(obstacle_collision_system.after(simulation_step_system))
+        .add_system(body_wall_collision_system.after(simulation_step_system))
+        .add_system(body_movement_system.after(body_wall_collision_system))
+        .add_system(conservation_system.after(body_movement_system))
+        .add_system(probe_system.in_base_set(CoreSet::Last))
+        .add_system(checkpoint_system.in_base_set(CoreSet::Last))
+        .add_system(
+            output_system
+                .in_base_set(CoreSet::Last)
+                .after(checkpoint_system),
+        )
+        .run();
+}
+
+fn parse_args() -> Result<HeadlessRun, String> {
+    let mut scene_path = None;
+    let mut steps = None;
+    let mut duration = None;
+    let mut dt = DEFAULT_DT;
+    let mut interval = DEFAULT_INTERVAL;
+    let mut output = PathBuf::from(DEFAULT_OUTPUT);
+    let mut checkpoint_interval = None;
+    let mut resume_path = None;
+    let mut vtk = false;
+    let mut conservation = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--scene" => scene_path = Some(PathBuf::from(value()?)),
+            "--steps" => steps = Some(parse_value(&arg, &value()?)?),
+            "--time" => duration = Some(parse_value::<f32>(&arg, &value()?)?),
+            "--dt" => dt = parse_value(&arg, &value()?)?,
+            "--interval" => interval = parse_value(&arg, &value()?)?,
+            "--output" => output = PathBuf::from(value()?),
+            "--vtk" => vtk = true,
+            "--conservation" => conservation = true,
+            "--checkpoint-every" => checkpoint_interval = Some(parse_value(&arg, &value()?)?),
+            "--resume" => resume_path = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+
+    let scene_path = scene_path.ok_or("a scene file is needed")?;
+    if !(dt.is_finite() && dt > 0. && dt <= MAX_DT) {
+        return Err(format!(
+            "--dt must be a positive number of seconds, at most {MAX_DT}"
+        ));
+    }
+    if interval == 0 || checkpoint_interval == Some(0) {
+        return Err("--interval and --checkpoint-every must be at least 1".to_string());
+    }
+    let steps = match (steps, duration) {
+        (Some(steps), None) => steps,
+        (None, Some(duration)) => (duration / dt).ceil() as u64,
+        _ => return Err("give either --steps or --time".to_string()),
+    };
+
+    let scene = Scene::load(&scene_path).map_err(|error| error.to_string())?;
+    let resume = match resume_path {
+        Some(path) => Some(Checkpoint::load(&path).map_err(|error| error.to_string())?),
+        None => None,
+    };
+    if let Some(checkpoint) = &resume {
+        if checkpoint.steps >= steps {
+            return Err(format!(
+                "the checkpoint is at step {}, past the last step {steps}",
+                checkpoint.steps
+            ));
+        }
+    }
+    let write_error = |error| format!("could not write to {}: {error}", output.display());
+    let diagnostics = create_diagnostics(&output, resume.is_some()).map_err(write_error)?;
+    let vtk = match (vtk, &resume) {
+        (false, _) => None,
+        (true, None) => Some(VtkSeries::new(&output, VTK_SERIES).map_err(write_error)?),
+        (true, Some(checkpoint)) => {
+            Some(VtkSeries::resume(&output, VTK_SERIES, checkpoint.time).map_err(write_error)?)
+        }
+    };
+    let conservation = if conservation {
+        let path = output.join(CONSERVATION_FILE);
+        Some(ConservationLog::create(&path, resume.is_some()).map_err(write_error)?)
+    } else {
+        None
+    };
+    let probes: Vec<ProbeSeries> = (scene.probes.iter())
+        .map(|probe| ProbeSeries::new(probe.name.clone(), probe.position))
+        .collect();
+    let probe_log = if probes.is_empty() {
+        None
+    } else {
+        let path = output.join(PROBE_FILE);
+        Some(ProbeLog::create(&path, resume.is_some()).map_err(write_error)?)
+    };
+    Ok(HeadlessRun {
+        emitters: EmitterState::new(&scene),
+        scene_path,
+        scene,
+        dt,
+        steps,
+        interval,
+        checkpoint_interval,
+        output,
+        vtk,
+        conservation,
+        probes,
+        probe_log,
+        resume,
+        diagnostics,
+        started: Instant::now(),
+    })
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{arg} expects a number, got {value}"))
+}
+
+// Start diagnostics.csv, or add to it when resuming
+fn create_diagnostics(output: &Path, resume: bool) -> io::Result<BufWriter<File>> {
+    fs::create_dir_all(output)?;
+    csv_log::open(&output.join("diagnostics.csv"), DIAGNOSTICS_HEADER, resume)
+}
+
+// Set the solver up and spawn the fluid blocks and capsules of the scene, or restore them from
+// the checkpoint
+fn setup_scene_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<BevyMesh>>,
+    mut run: ResMut<HeadlessRun>,
+    mut fluid: ResMut<Fluid>,
+    mut settings: ResMut<SimulationSettings>,
+    mut params: ResMut<SimulationParams>,
+    mut simulation_time: ResMut<SimulationTime>,
+) {
+    let bodies = match run.resume.take() {
+        Some(checkpoint) => {
+            **settings = checkpoint.settings;
+            **params = checkpoint.params;
+            **fluid = checkpoint.particles;
+            *simulation_time = SimulationTime {
+                elapsed: checkpoint.time,
+                steps: checkpoint.steps,
+            };
+            run.emitters = EmitterState::resume_at(&run.scene, checkpoint.time);
+            checkpoint.bodies
+        }
+        None => {
+            **settings = run.scene.solver;
+            **params = run.scene.params;
+            run.scene.fill(&mut fluid);
+            (run.scene.rigid_bodies.iter())
+                .map(|body| BodyState {
+                    position: body.position,
+                    velocity: body.velocity,
+                    force: Vec3::ZERO,
+                })
+                .collect()
+        }
+    };
+
+    let mesh = meshes.add(body_collision_mesh());
+    for body in bodies {
+        commands.spawn((
+            mesh.clone(),
+            Transform::from_translation(body.position),
+            BoxCollision,
+            Body {
+                velocity: body.velocity,
+                force: body.force,
+            },
+        ));
+    }
+}
+
+// Advance the clock by exactly one time step every frame. The first frame has no time step,
+// like the first frame of the windowed app.
+fn fixed_clock_system(
+    time: Res<Time>,
+    run: Res<HeadlessRun>,
+    mut update_strategy: ResMut<TimeUpdateStrategy>,
+    mut frame: Local<u32>,
+) {
+    let elapsed = Duration::from_secs_f32(run.dt) * *frame;
+    *update_strategy = TimeUpdateStrategy::ManualInstant(time.startup() + elapsed);
+    *frame += 1;
+}
+
+// Add the rows the emitters are due since the last step
+fn emitter_system(
+    simulation_time: Res<SimulationTime>,
+    mut run: ResMut<HeadlessRun>,
+    mut fluid: ResMut<Fluid>,
+) {
+    let run = &mut *run;
+    run.emitters
+        .emit_due(&run.scene, simulation_time.elapsed, &mut fluid);
+}
+
+fn obstacle_collision_system(
+    run: Res<HeadlessRun>,
+    params: Res<SimulationParams>,
+    mut fluid: ResMut<Fluid>,
+) {
+    run.scene.collide_obstacles(&mut fluid, params.coef_rest);
+}
+
+// Write the snapshot and diagnostics the step is due, and stop after the last step
+fn output_system(
+    time: Res<Time>,
+    simulation_time: Res<SimulationTime>,
+    mut run: ResMut<HeadlessRun>,
+    fluid: Res<Fluid>,
+    stats: Res<SimulationStats>,
+    conservation: Res<SimulationConservation>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if time.delta_seconds() == 0. {
+        return;
+    }
+    let step = simulation_time.steps;
+    if step % run.interval == 0 || step == run.steps {
+        if let Err(error) = write_output(&mut run, &fluid, &stats, &conservation, &simulation_time)
+        {
+            eprintln!("could not write to {}: {error}", run.output.display());
+            std::process::exit(1);
+        }
+        println!(
+            "step {step}/{}, t = {:.3} s, {} particles",
+            run.steps,
+            simulation_time.elapsed,
+            fluid.len()
+        );
+    }
+    if step >= run.steps {
+        println!("Done in {:.1} s", run.started.elapsed().as_secs_f32());
+        exit.send(AppExit);
+    }
+}
+
+// Sample the probes after every step
+fn probe_system(
+    time: Res<Time>,
+    simulation_time: Res<SimulationTime>,
+    mut run: ResMut<HeadlessRun>,
+    fluid: Res<Fluid>,
+    params: Res<SimulationParams>,
+) {
+    if time.delta_seconds() == 0. {
+        return;
+    }
+    let run = &mut *run;
+    let Some(log) = &mut run.probe_log else {
+        return;
+    };
+    sample_probes(
+        &mut run.probes,
+        &fluid,
+        &params,
+        simulation_time.steps,
+        simulation_time.elapsed,
+    );
+    if let Err(error) = log.write(&mut run.probes) {
+        eprintln!("could not write to {}: {error}", run.output.display());
+        std::process::exit(1);
+    }
+}
+
+// Save the whole state every checkpoint interval and after the last step
+fn checkpoint_system(
+    time: Res<Time>,
+    simulation_time: Res<SimulationTime>,
+    run: Res<HeadlessRun>,
+    fluid: Res<Fluid>,
+    settings: Res<SimulationSettings>,
+    params: Res<SimulationParams>,
+    body_query: Query<(&Body, &Transform)>,
+) {
+    let step = simulation_time.steps;
+    let Some(checkpoint_interval) = run.checkpoint_interval else {
+        return;
+    };
+    if time.delta_seconds() == 0. || !(step % checkpoint_interval == 0 || step == run.steps) {
+        return;
+    }
+    let checkpoint = Checkpoint {
+        time: simulation_time.elapsed,
+        steps: step,
+        settings: **settings,
+        params: **params,
+        particles: fluid.0.clone(),
+        bodies: (body_query.iter())
+            .map(|(body, transform)| BodyState {
+                position: transform.translation,
+                velocity: body.velocity,
+                force: body.force,
+            })
+            .collect(),
+    };
+    if let Err(error) = checkpoint.save(&run.output.join(CHECKPOINT_FILE)) {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+}
+
+fn write_output(
+    run: &mut HeadlessRun,
+    fluid: &ParticleSet,
+    stats: &SimulationStats,
+    conservation: &SimulationConservation,
+    simulation_time: &SimulationTime,
+) -> io::Result<()> {
+    write_snapshot(
+        &run.output
+            .join(format!("snapshot_{:06}.csv", simulation_time.steps)),
+        fluid,
+    )?;
+    if let Some(vtk) = &mut run.vtk {
+        vtk.write_frame(simulation_time.steps, simulation_time.elapsed, fluid)?;
+    }
+    if let Some(log) = &mut run.conservation {
+        log.write(simulation_time.steps, simulation_time.elapsed, conservation)?;
+        log.flush()?;
+    }
+
+    let max_speed = fluid
+        .velocities
+        .iter()
+        .map(|velocity| velocity.length())
+        .fold(0., f32::max);
+    writeln!(
+        run.diagnostics,
+        "{},{},{},{},{},{},{},{},{},{}",
+        simulation_time.steps,
+        simulation_time.elapsed,
+        fluid.len(),
+        max_speed,
+        stats.divergence_iterations,
+        stats.density_iterations,
+        stats.divergence_error,
+        stats.density_error,
+        fluid.neighbor_list().builds,
+        run.started.elapsed().as_secs_f32()
+    )?;
+    run.diagnostics.flush()
+}
+
+// One line per particle, in the order they are stored
+fn write_snapshot(path: &Path, fluid: &ParticleSet) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "id,x,y,z,vx,vy,vz,density,pressure")?;
+    for i in 0..fluid.len() {
+        let position = fluid.positions[i];
+        let velocity = fluid.velocities[i];
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{}",
+            fluid.ids[i],
+            position.x,
+            position.y,
+            position.z,
+            velocity.x,
+            velocity.y,
+            velocity.z,
+            fluid.densities[i],
+            fluid.pressures[i]
+        )?;
+    }
+    file.flush()
+}
@@ -0,0 +1,125 @@
+use glam::Vec3;
+
+use crate::sph::{compression, FluidState, SolverSettings, SolverStats};
+
+/*
+ *
+ * Implicit Incompressible SPH (IISPH) Implementation
+ * Ihmsen et al. 2014, https://doi.org/10.1109/TVCG.2013.105
+ *
+ */
+
+// Relaxation factor of the Jacobi iterations
+const RELAXATION: f32 = 0.5;
+
+// Solve the pressure Poisson equation with relaxed Jacobi iterations until the average density
+// error is below the tolerance. Returns the pressure acceleration and the pressure of every particle.
+pub fn solve(
+    fluid: &FluidState,
+    non_pressure_accelerations: &[Vec3],
+    dt: f32,
+    settings: &SolverSettings,
+    stats: &mut SolverStats,
+) -> (Vec<Vec3>, Vec<f32>) {
+    let count = fluid.positions.len();
+    let positions = &fluid.positions;
+    let densities = &fluid.densities;
+    let neighbors = &fluid.neighbors;
+    let base_density = fluid.params.base_density;
+    let gradient = |i: usize, j: usize| {
+        fluid
+            .params
+            .pressure_kernel_gradient(positions[i] - positions[j])
+    };
+
+    let advected_velocities: Vec<Vec3> = fluid
+        .velocities
+        .iter()
+        .zip(non_pressure_accelerations)
+        .map(|(velocity, acceleration)| *velocity + dt * *acceleration)
+        .collect();
+
+    // Displacement of each particle caused by its own pressure, and the density it reaches
+    // from the non-pressure forces alone
+    let mut own_displacements = vec![Vec3::ZERO; count];
+    let mut advected_densities = densities.clone();
+    for i in 0..count {
+        for &j in neighbors[i].iter() {
+            let gradient_ij = gradient(i, j);
+            own_displacements[i] -= dt * dt / (densities[i] * densities[i]) * gradient_ij;
+            advected_densities[i] +=
+                dt * (advected_velocities[i] - advected_velocities[j]).dot(gradient_ij);
+        }
+    }
+
+    // Diagonal element of the pressure Poisson equation
+    let mut diagonals = vec![0.; count];
+    for (i, diagonal) in diagonals.iter_mut().enumerate() {
+        for &j in neighbors[i].iter() {
+            let gradient_ij = gradient(i, j);
+            let displacement_ji = dt * dt / (densities[i] * densities[i]) * gradient_ij;
+            *diagonal += (own_displacements[i] - displacement_ji).dot(gradient_ij);
+        }
+    }
+
+    let mut pressures = vec![0.; count];
+    let mut next_pressures = vec![0.; count];
+    let mut neighbor_displacements = vec![Vec3::ZERO; count];
+    let mut iterations = 0;
+    let mut average_error = 0.;
+
+    while iterations < settings.max_iterations {
+        // Displacement of each particle caused by the pressure of its neighbors
+        for (i, displacement) in neighbor_displacements.iter_mut().enumerate() {
+            *displacement = Vec3::ZERO;
+            for &j in neighbors[i].iter() {
+                *displacement -=
+                    dt * dt * pressures[j] / (densities[j] * densities[j]) * gradient(i, j);
+            }
+        }
+
+        let mut total_error = 0.;
+        for (i, next_pressure) in next_pressures.iter_mut().enumerate() {
+            let mut sum = 0.;
+            for &j in neighbors[i].iter() {
+                let gradient_ij = gradient(i, j);
+                let displacement_ji = dt * dt / (densities[i] * densities[i]) * gradient_ij;
+                sum += (neighbor_displacements[i]
+                    - own_displacements[j] * pressures[j]
+                    - (neighbor_displacements[j] - displacement_ji * pressures[i]))
+                    .dot(gradient_ij);
+            }
+
+            let predicted_density = advected_densities[i] + diagonals[i] * pressures[i] + sum;
+            total_error += compression(predicted_density, &fluid.params);
+
+            *next_pressure = if diagonals[i] != 0. {
+                let jacobi = (base_density - advected_densities[i] - sum) / diagonals[i];
+                ((1. - RELAXATION) * pressures[i] + RELAXATION * jacobi).max(0.)
+            } else {
+                0.
+            };
+        }
+        average_error = total_error / (count as f32 * base_density);
+
+        if average_error <= settings.max_density_error && iterations >= settings.min_iterations {
+            break;
+        }
+
+        std::mem::swap(&mut pressures, &mut next_pressures);
+        iterations += 1;
+    }
+
+    let mut accelerations = vec![Vec3::ZERO; count];
+    for (i, acceleration) in accelerations.iter_mut().enumerate() {
+        for &j in neighbors[i].iter() {
+            *acceleration -= (pressures[i] / (densities[i] * densities[i])
+                + pressures[j] / (densities[j] * densities[j]))
+                * gradient(i, j);
+        }
+    }
+
+    stats.density_iterations = iterations;
+    stats.density_error = average_error;
+    (accelerations, pressures)
+}
@@ -0,0 +1,365 @@
+use glam::Vec3;
+
+use std::f32::consts::PI;
+
+/*
+ *
+ * SPH Smoothing Kernels
+ * All kernels have compact support: they are zero once the distance reaches the smoothing length.
+ * The vector r points from the neighbor to the particle the kernel is evaluated for.
+ * The normalizations are computed once by new(), make a new kernel when the smoothing length
+ * changes.
+ *
+ */
+
+pub trait SmoothingKernel {
+    fn value(&self, r: Vec3) -> f32;
+    fn gradient(&self, r: Vec3) -> Vec3;
+    fn laplacian(&self, r: Vec3) -> f32;
+}
+
+// Müller et al. 2003, used for the density since it does not need a square root
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Poly6 {
+    smoothing_length: f32,
+    // Normalization of the value, and of the gradient and laplacian
+    value_normalization: f32,
+    derivative_normalization: f32,
+}
+
+impl Poly6 {
+    pub fn new(smoothing_length: f32) -> Self {
+        let h = smoothing_length;
+        Poly6 {
+            smoothing_length,
+            value_normalization: 315. / (64. * PI * h.powi(9)),
+            derivative_normalization: -945. / (32. * PI * h.powi(9)),
+        }
+    }
+
+    pub fn smoothing_length(&self) -> f32 {
+        self.smoothing_length
+    }
+}
+
+impl SmoothingKernel for Poly6 {
+    fn value(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let difference = h * h - r.length_squared();
+        if difference <= 0. {
+            return 0.;
+        }
+        self.value_normalization * difference.powi(3)
+    }
+
+    fn gradient(&self, r: Vec3) -> Vec3 {
+        let h = self.smoothing_length;
+        let difference = h * h - r.length_squared();
+        if difference <= 0. {
+            return Vec3::ZERO;
+        }
+        self.derivative_normalization * difference.powi(2) * r
+    }
+
+    fn laplacian(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let length_squared = r.length_squared();
+        let difference = h * h - length_squared;
+        if difference <= 0. {
+            return 0.;
+        }
+        self.derivative_normalization * difference * (3. * h * h - 7. * length_squared)
+    }
+}
+
+// Müller et al. 2003, used for the pressure force since its gradient does not vanish at the center
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Spiky {
+    smoothing_length: f32,
+    // 15 / (pi h^6), the gradient and laplacian are multiples of it
+    normalization: f32,
+}
+
+impl Spiky {
+    pub fn new(smoothing_length: f32) -> Self {
+        Spiky {
+            smoothing_length,
+            normalization: 15. / (PI * smoothing_length.powi(6)),
+        }
+    }
+
+    pub fn smoothing_length(&self) -> f32 {
+        self.smoothing_length
+    }
+}
+
+impl SmoothingKernel for Spiky {
+    fn value(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h {
+            return 0.;
+        }
+        self.normalization * (h - length).powi(3)
+    }
+
+    fn gradient(&self, r: Vec3) -> Vec3 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h || length == 0. {
+            return Vec3::ZERO;
+        }
+        -3. * self.normalization * (h - length).powi(2) * (r / length)
+    }
+
+    fn laplacian(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h || length == 0. {
+            return 0.;
+        }
+        6. * self.normalization * (h - length) * (2. * length - h) / length
+    }
+}
+
+// Müller et al. 2003, used for the viscous force since its laplacian is positive everywhere
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Viscosity {
+    smoothing_length: f32,
+    // Normalization of the value and gradient, and of the laplacian
+    value_normalization: f32,
+    laplacian_normalization: f32,
+}
+
+impl Viscosity {
+    pub fn new(smoothing_length: f32) -> Self {
+        let h = smoothing_length;
+        Viscosity {
+            smoothing_length,
+            value_normalization: 15. / (2. * PI * h.powi(3)),
+            laplacian_normalization: 45. / (PI * h.powi(6)),
+        }
+    }
+
+    pub fn smoothing_length(&self) -> f32 {
+        self.smoothing_length
+    }
+}
+
+impl SmoothingKernel for Viscosity {
+    fn value(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h || length == 0. {
+            return 0.;
+        }
+        self.value_normalization
+            * (-length.powi(3) / (2. * h.powi(3)) + length.powi(2) / (h * h) + h / (2. * length)
+                - 1.)
+    }
+
+    fn gradient(&self, r: Vec3) -> Vec3 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h || length == 0. {
+            return Vec3::ZERO;
+        }
+        self.value_normalization
+            * (-3. * length / (2. * h.powi(3)) + 2. / (h * h) - h / (2. * length.powi(3)))
+            * r
+    }
+
+    fn laplacian(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        if length >= h {
+            return 0.;
+        }
+        self.laplacian_normalization * (h - length)
+    }
+}
+
+// Cubic B-spline (Monaghan 1992) scaled so its support is the smoothing length.
+// Not used by default, swap it into one of the kernels of SimParams in sph.rs to try it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CubicSpline {
+    smoothing_length: f32,
+    // 8 / (pi h^3), each derivative divides it by another h and multiplies it by 6
+    normalization: f32,
+}
+
+impl CubicSpline {
+    pub fn new(smoothing_length: f32) -> Self {
+        CubicSpline {
+            smoothing_length,
+            normalization: 8. / (PI * smoothing_length.powi(3)),
+        }
+    }
+
+    pub fn smoothing_length(&self) -> f32 {
+        self.smoothing_length
+    }
+}
+
+impl SmoothingKernel for CubicSpline {
+    fn value(&self, r: Vec3) -> f32 {
+        let q = r.length() / self.smoothing_length;
+        if q <= 0.5 {
+            self.normalization * (6. * q.powi(3) - 6. * q.powi(2) + 1.)
+        } else if q < 1. {
+            self.normalization * 2. * (1. - q).powi(3)
+        } else {
+            0.
+        }
+    }
+
+    fn gradient(&self, r: Vec3) -> Vec3 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        let q = length / h;
+        if q >= 1. || length == 0. {
+            return Vec3::ZERO;
+        }
+        let normalization = 6. * self.normalization / h;
+        let derivative = if q <= 0.5 {
+            normalization * q * (3. * q - 2.)
+        } else {
+            -normalization * (1. - q).powi(2)
+        };
+        derivative * (r / length)
+    }
+
+    fn laplacian(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let q = r.length() / h;
+        let normalization = 6. * self.normalization / (h * h);
+        if q <= 0.5 {
+            normalization * (12. * q - 6.)
+        } else if q < 1. {
+            normalization * 2. * (1. - q) * (2. * q - 1.) / q
+        } else {
+            0.
+        }
+    }
+}
+
+// Wendland C2 (Wendland 1995), avoids the pairing instability of the cubic spline.
+// Not used by default, swap it into one of the kernels of SimParams in sph.rs to try it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WendlandC2 {
+    smoothing_length: f32,
+    // 21 / (2 pi h^3), each derivative divides it by another h
+    normalization: f32,
+}
+
+impl WendlandC2 {
+    pub fn new(smoothing_length: f32) -> Self {
+        WendlandC2 {
+            smoothing_length,
+            normalization: 21. / (2. * PI * smoothing_length.powi(3)),
+        }
+    }
+
+    pub fn smoothing_length(&self) -> f32 {
+        self.smoothing_length
+    }
+}
+
+impl SmoothingKernel for WendlandC2 {
+    fn value(&self, r: Vec3) -> f32 {
+        let q = r.length() / self.smoothing_length;
+        if q >= 1. {
+            return 0.;
+        }
+        self.normalization * (1. - q).powi(4) * (1. + 4. * q)
+    }
+
+    fn gradient(&self, r: Vec3) -> Vec3 {
+        let h = self.smoothing_length;
+        let length = r.length();
+        let q = length / h;
+        if q >= 1. || length == 0. {
+            return Vec3::ZERO;
+        }
+        -20. * self.normalization / h * q * (1. - q).powi(3) * (r / length)
+    }
+
+    fn laplacian(&self, r: Vec3) -> f32 {
+        let h = self.smoothing_length;
+        let q = r.length() / h;
+        if q >= 1. {
+            return 0.;
+        }
+        -60. * self.normalization / (h * h) * (1. - q).powi(2) * (1. - 2. * q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const H: f32 = 80.;
+
+    // Integrate the kernel over its support with Simpson's rule in spherical shells
+    fn integral(kernel: &dyn SmoothingKernel) -> f64 {
+        let steps = 2000;
+        let dr = H as f64 / steps as f64;
+        let shell = |i: usize| {
+            let r = i as f64 * dr;
+            4. * std::f64::consts::PI * r * r * kernel.value(Vec3::new(r as f32, 0., 0.)) as f64
+        };
+        let mut sum = shell(0) + shell(steps);
+        for i in 1..steps {
+            sum += if i % 2 == 1 { 4. } else { 2. } * shell(i);
+        }
+        sum * dr / 3.
+    }
+
+    fn check_gradient_antisymmetry(kernel: &dyn SmoothingKernel) {
+        let samples = [
+            Vec3::new(10., 0., 0.),
+            Vec3::new(-3., 25., 7.),
+            Vec3::new(30., -30., 30.),
+            Vec3::new(0.5, 0.5, -70.),
+        ];
+        for r in samples {
+            let forward = kernel.gradient(r);
+            let backward = kernel.gradient(-r);
+            assert!(forward.length() > 0.);
+            assert!((forward + backward).length() <= 1e-6 * forward.length());
+        }
+    }
+
+    fn check_kernel(kernel: &dyn SmoothingKernel) {
+        let integral = integral(kernel);
+        assert!((integral - 1.).abs() < 1e-3, "integral is {integral}");
+        check_gradient_antisymmetry(kernel);
+        assert_eq!(kernel.value(Vec3::new(H, 0., 0.)), 0.);
+        assert_eq!(kernel.gradient(Vec3::new(0., H + 1., 0.)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn poly6() {
+        check_kernel(&Poly6::new(H));
+    }
+
+    #[test]
+    fn spiky() {
+        check_kernel(&Spiky::new(H));
+    }
+
+    #[test]
+    fn viscosity() {
+        check_kernel(&Viscosity::new(H));
+    }
+
+    #[test]
+    fn cubic_spline() {
+        check_kernel(&CubicSpline::new(H));
+    }
+
+    #[test]
+    fn wendland_c2() {
+        check_kernel(&WendlandC2::new(H));
+    }
+}
@@ -0,0 +1,32 @@
+/*
+ *
+ * SPH Solver Core
+ * The fluid simulation without Bevy: a ParticleSet, the SolverSettings, the SimParams and
+ * sph::step() to advance them in time. The Bevy app in main.rs is a frontend that keeps the
+ * ParticleSet in a resource and copies the particle positions into Transforms every frame.
+ * scene.rs reads the experiments a ParticleSet is set up from out of RON or JSON files,
+ * point_cloud.rs reads measured starting shapes of the fluid out of CSV or PLY files,
+ * checkpoint.rs saves and restores the whole state of a run, trajectory.rs records it frame by
+ * frame for replays, vtk.rs writes the particles out for ParaView and surface.rs writes the
+ * fluid surface out for Blender. conservation.rs measures the energy, momentum, mass and density
+ * error a run should keep, and probe.rs samples the pressure, density and velocity at fixed
+ * points. csv_log.rs opens the CSV files their time series are logged to.
+ *
+ */
+
+pub mod checkpoint;
+pub mod conservation;
+pub mod csv_log;
+pub mod dfsph;
+pub mod iisph;
+pub mod kernels;
+pub mod neighbors;
+pub mod octree_nearest_neighbor;
+pub mod pcisph;
+pub mod point_cloud;
+pub mod probe;
+pub mod scene;
+pub mod sph;
+pub mod surface;
+pub mod trajectory;
+pub mod vtk;
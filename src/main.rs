@@ -1,10 +1,13 @@
 use bevy::{
-    diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    diagnostic::{
+        Diagnostic, DiagnosticId, Diagnostics, FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin,
+    },
     prelude::shape::UVSphere,
 };
 
 use bevy::prelude::*;
 use bevy::render::mesh::Mesh as BevyMesh;
+use bevy_egui::{EguiContexts, EguiPlugin};
 use transvoxel::structs::*;
 use transvoxel::transition_sides::*;
 
@@ -21,18 +24,100 @@ pub mod camera;
 #[path = "functions/load_materials.rs"]
 mod load_materials;
 
-mod sph;
-use sph::movement_system;
-use sph::particle_collision_system;
-use sph::pressure_and_density_system;
-use sph::wall_collision_system;
-use sph::SIZE_X;
-use sph::SIZE_Y;
-use sph::SIZE_Z;
+mod benchmark;
+
+#[path = "./app/args.rs"]
+mod args;
+use args::AppArgs;
+use args::USAGE;
+
+mod simulation;
+use simulation::body_movement_system;
+use simulation::body_wall_collision_system;
+use simulation::box_collision_system;
+use simulation::conservation_system;
+use simulation::simulation_step_system;
+use simulation::Fluid;
+use simulation::SimulationConservation;
+use simulation::SimulationParams;
+use simulation::SimulationSettings;
+use simulation::SimulationStats;
+use simulation::SimulationTime;
+
+#[path = "./app/params_panel.rs"]
+mod params_panel;
+use params_panel::params_panel_system;
+use params_panel::update_params_system;
+
+#[path = "./app/checkpointing.rs"]
+mod checkpointing;
+use checkpointing::load_checkpoint_system;
+use checkpointing::restore_checkpoint_system;
+use checkpointing::save_checkpoint_system;
+use checkpointing::CheckpointRestored;
+use checkpointing::Checkpoints;
+
+#[path = "./app/vtk_export.rs"]
+mod vtk_export;
+use vtk_export::vtk_export_system;
+use vtk_export::vtk_toggle_system;
+use vtk_export::VtkRecorder;
+
+#[path = "./app/surface_export.rs"]
+mod surface_export;
+use surface_export::surface_export_system;
+use surface_export::surface_toggle_system;
+use surface_export::FluidSurface;
+use surface_export::SurfaceRecorder;
+
+#[path = "./app/replay.rs"]
+mod replay;
+use replay::not_replaying;
+use replay::record_system;
+use replay::replay_panel_system;
+use replay::replay_system;
+use replay::Replay;
+use replay::TrajectoryRecorder;
+
+#[path = "./app/conservation_log.rs"]
+mod conservation_log;
+use conservation_log::conservation_log_system;
+use conservation_log::ConservationRecorder;
+
+#[path = "./app/point_import.rs"]
+mod point_import;
+use point_import::import_points_system;
+use point_import::PointImport;
+
+#[path = "./app/probes.rs"]
+mod probes;
+use probes::ctrl_pressed;
+use probes::place_probe_system;
+use probes::probe_sample_system;
+use probes::probe_window_system;
+use probes::ProbeSettings;
+
+#[path = "./app/scene_loader.rs"]
+mod scene_loader;
+use scene_loader::apply_scene_system;
+use scene_loader::emitter_system;
+use scene_loader::obstacle_collision_system;
+use scene_loader::reload_scene_system;
+use scene_loader::ActiveScene;
+
+use particles::neighbors::NeighborBackend;
+use particles::scene::Scene;
+use particles::sph::EquationOfState;
+use particles::sph::SimParams;
+use particles::sph::SolverMode;
+use particles::sph::TaitParameters;
+use particles::sph::SIZE_X;
+use particles::sph::SIZE_Y;
+use particles::sph::SIZE_Z;
+use particles::surface::SurfaceMesh;
 
 mod box_functions;
 use box_functions::add_mesh;
-use box_functions::box_collision_system;
 
 // mod marching_cubes;
 // use marching_cubes::render_mesh;
@@ -51,29 +136,11 @@ const MAIN_BLOCK: BlockDims<f32> = BlockDims {
     size: 1400.,
 };
 
-#[derive(Component)]
-pub struct BoxCollision;
-
 #[derive(Resource)]
 struct BevyCounter {
     pub count: usize,
 }
 
-#[derive(Component)]
-pub struct Particle {
-    velocity: Vec3,
-    acceleration: Vec3,
-    density: f32,
-    pressure: f32,
-    force: Vec3,
-}
-
-#[derive(Component)]
-pub struct Body {
-    velocity: Vec3,
-    force: Vec3,
-}
-
 #[derive(Resource)]
 struct ParticleScheduled {
     wave: usize,
@@ -115,6 +182,7 @@ struct MaterialsResource {
 //     //LoadModel,
 // }
 
+#[allow(clippy::too_many_arguments)]
 fn render_mesh(
     // mut events: EventReader<AppEvent>,
     mut commands: Commands,
@@ -122,19 +190,30 @@ fn render_mesh(
     mats_cache: Res<MaterialsResource>,
     models_query: Query<(Entity, &ModelMarkerComponent)>,
     params: Res<ModelParams>,
+    sim_params: Res<SimulationParams>,
     particle_query: Query<(&Particle, &Transform)>,
+    simulation_time: Res<SimulationTime>,
+    recorder: Res<SurfaceRecorder>,
+    mut fluid_surface: ResMut<FluidSurface>,
 ) {
     // let params = &ui_state.desired_things;
     for (entity, _) in models_query.iter() {
         commands.entity(entity).despawn();
     }
+    // Keep the surface for surface_export_system while recording
+    let recorded = recorder.recording.then_some(&mut fluid_surface.mesh);
     load_model(
         &mut commands,
         &mut meshes,
         &mats_cache,
         &params,
+        &sim_params,
         particle_query,
+        recorded,
     ); // where everything happens
+    if recorder.recording {
+        fluid_surface.step = Some(simulation_time.steps);
+    }
 }
 
 fn add_grid(
@@ -148,7 +227,7 @@ fn add_grid(
         dims: MAIN_BLOCK,
         subdivisions: model_params.subdivisions,
     };
-    let grid_mesh = utils::grid_lines(&block, &transition_sides);
+    let grid_mesh = utils::grid_lines(&block, transition_sides);
     commands
         .spawn(PbrBundle {
             mesh: meshes.add(grid_mesh),
@@ -158,7 +237,7 @@ fn add_grid(
         .insert(ModelMarkerComponent {});
     let cube = BevyMesh::from(shape::Cube { size: 1.0 });
     let cube_handle = meshes.add(cube);
-    for (x, y, z) in utils::inside_grid_points(&model_params.model, &block, &transition_sides) {
+    for (x, y, z) in utils::inside_grid_points(&model_params.model, &block, transition_sides) {
         let cell_size = MAIN_BLOCK.size / model_params.subdivisions as f32;
         let point_size = cell_size * 0.05;
         let resize = Transform::from_scale(Vec3::new(point_size, point_size, point_size));
@@ -185,7 +264,9 @@ fn load_model(
     meshes: &mut ResMut<Assets<BevyMesh>>,
     mats_cache: &Res<MaterialsResource>,
     model_params: &ModelParams,
+    sim_params: &SimParams,
     particle_query: Query<(&Particle, &Transform)>,
+    recorded: Option<&mut SurfaceMesh>,
 ) {
     let wireframe = model_params.wireframe;
     let transition_sides = if model_params.with_transition {
@@ -203,7 +284,15 @@ fn load_model(
         positions.push(transform.translation);
     }
 
-    let bevy_mesh = utils::mesh_for_model(positions, wireframe, &block, &transition_sides);
+    let surface = utils::surface_for_model(positions, sim_params, &block, &transition_sides);
+    if let Some(recorded) = recorded {
+        *recorded = SurfaceMesh::from_flat(
+            &surface.positions,
+            &surface.normals,
+            &surface.triangle_indices,
+        );
+    }
+    let bevy_mesh = utils::to_bevy(surface, wireframe);
     let mat = if wireframe {
         mats_cache.wireframe_model.clone()
     } else {
@@ -228,8 +317,57 @@ fn load_model(
     }
 }
 
+// What the arguments ask to read from disk: the scene, the checkpoint to resume from, the
+// trajectory to replay and the point clouds
+struct StartupFiles {
+    active_scene: Option<ActiveScene>,
+    checkpoints: Checkpoints,
+    replay: Option<Replay>,
+    point_import: PointImport,
+}
+
+impl StartupFiles {
+    fn load(args: &AppArgs) -> Result<StartupFiles, String> {
+        let active_scene = match &args.scene {
+            Some(path) => Some(ActiveScene::new(
+                Scene::load(path).map_err(|error| error.to_string())?,
+                path.clone(),
+            )),
+            None => None,
+        };
+        Ok(StartupFiles {
+            active_scene,
+            checkpoints: Checkpoints::new(args)?,
+            replay: Replay::open(args)?,
+            point_import: PointImport::load(args)?,
+        })
+    }
+}
+
 fn main() {
-    App::new()
+    let args = AppArgs::from_env().unwrap_or_else(|error| {
+        eprintln!("{error}\n{USAGE}");
+        std::process::exit(2);
+    });
+    if args.benchmark {
+        benchmark::run_benchmark();
+        return;
+    }
+
+    // The files the arguments name are read before opening the window, so one that does not
+    // load is reported right away
+    let StartupFiles {
+        active_scene,
+        checkpoints,
+        replay,
+        point_import,
+    } = StartupFiles::load(&args).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(1);
+    });
+
+    let mut app = App::new();
+    app
         // bevy setup stuff
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -247,32 +385,150 @@ fn main() {
             resizable: true,
             ..default()
         })*/
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(FrameTimeDiagnosticsPlugin)
         .add_plugin(LogDiagnosticsPlugin::default())
+        .add_plugin(EguiPlugin)
         .insert_resource(BevyCounter { count: 0 })
+        .init_resource::<SimulationSettings>()
+        .init_resource::<SimulationStats>()
+        .init_resource::<Fluid>()
+        .init_resource::<SimulationParams>()
+        .init_resource::<SimulationTime>()
+        .init_resource::<SimulationConservation>()
+        .add_event::<CheckpointRestored>()
+        .insert_resource(checkpoints)
+        .insert_resource(VtkRecorder::new(&args))
+        .insert_resource(SurfaceRecorder::new(&args))
+        .insert_resource(point_import)
+        .insert_resource(ProbeSettings::new(&args))
         // camera setup
         .add_startup_system(camera::spawn_camera)
         .add_system(camera::pan_orbit_camera)
         // Particles setup
         .add_startup_system(setup)
-        //.add_startup_system(initialize_octree)
-        .add_system(mouse_handler)
+        .add_startup_system(setup_solver_diagnostics)
+        .add_system(mouse_handler.run_if(not_replaying))
+        .add_system(keyboard_handler)
         //.add_system(movement_system)
-        //.add_system(populate_octree)
-        //.add_system(pressure_and_density_system.after(populate_octree))
-        .add_system(pressure_and_density_system)
-        .add_system(particle_collision_system.after(pressure_and_density_system))
-        .add_system(wall_collision_system.after(particle_collision_system))
-        .add_system(movement_system.after(wall_collision_system))
+        .add_system(params_panel_system)
+        .add_system(
+            update_params_system
+                .after(params_panel_system)
+                .before(simulation_step_system),
+        )
+        .add_system(load_checkpoint_system.run_if(not_replaying))
+        .add_system(
+            restore_checkpoint_system
+                .after(load_checkpoint_system)
+                .after(update_params_system)
+                .before(simulation_step_system)
+                .run_if(not_replaying),
+        )
+        .add_system(
+            import_points_system
+                .after(restore_checkpoint_system)
+                .before(simulation_step_system)
+                .run_if(not_replaying),
+        )
+        .add_system(simulation_step_system.run_if(not_replaying))
+        .add_system(sync_transforms_system.after(simulation_step_system))
+        .add_system(solver_diagnostics_system.after(simulation_step_system))
+        .add_system(
+            body_wall_collision_system
+                .after(simulation_step_system)
+                .run_if(not_replaying),
+        )
+        .add_system(
+            body_movement_system
+                .after(body_wall_collision_system)
+                .run_if(not_replaying),
+        )
+        .add_system(
+            save_checkpoint_system
+                .after(body_movement_system)
+                .run_if(not_replaying),
+        )
+        .add_system(
+            conservation_system
+                .after(body_movement_system)
+                .after(restore_checkpoint_system),
+        )
+        .add_system(
+            conservation_log_system
+                .after(conservation_system)
+                .run_if(not_replaying),
+        )
+        .add_system(place_probe_system.run_if(not_replaying))
+        .add_system(
+            probe_sample_system
+                .after(conservation_system)
+                .run_if(not_replaying),
+        )
+        .add_system(probe_window_system.after(params_panel_system))
+        .add_system(vtk_toggle_system)
+        // After the obstacles of a scene moved the particles out
+        .add_system(vtk_export_system.in_base_set(CoreSet::PostUpdate))
+        .add_system(surface_toggle_system)
+        .init_resource::<FluidSurface>()
+        .add_system(surface_export_system.in_base_set(CoreSet::PostUpdate))
+        .add_system(
+            record_system
+                .in_base_set(CoreSet::PostUpdate)
+                .run_if(not_replaying),
+        )
         .add_system(counter_system)
-        .add_system(add_mesh)
-        .add_system(box_collision_system)
+        .add_system(add_mesh.run_if(not_replaying))
+        .add_system(
+            box_collision_system
+                .after(restore_checkpoint_system)
+                .before(simulation_step_system)
+                .run_if(not_replaying),
+        )
         .insert_resource(FixedTime::new_from_secs(1. / SPAWN_RATE))
-        .add_system(scheduled_spawner)
+        .add_system(scheduled_spawner.run_if(not_replaying))
         .init_resource::<ModelParams>()
         .init_resource::<MaterialsResource>()
-        .add_system(render_mesh)
-        .run();
+        // The particles of the step, for the surface it keeps for the export
+        .add_system(render_mesh.after(sync_transforms_system));
+
+    if let Some(recorder) = TrajectoryRecorder::new(&args) {
+        app.insert_resource(recorder);
+    }
+    if let Some(conservation_recorder) = ConservationRecorder::new(&args) {
+        app.insert_resource(conservation_recorder);
+    }
+
+    // A replay shows the recorded particles, so the scene is not set up
+    if let Some(replay) = replay {
+        app.insert_resource(replay)
+            .add_system(replay_panel_system.after(params_panel_system))
+            .add_system(
+                replay_system
+                    .after(replay_panel_system)
+                    .before(sync_transforms_system),
+            );
+    } else if let Some(active_scene) = active_scene {
+        app.insert_resource(active_scene)
+            .add_system(reload_scene_system)
+            .add_system(
+                apply_scene_system
+                    .after(reload_scene_system)
+                    .after(restore_checkpoint_system)
+                    .before(simulation_step_system),
+            )
+            .add_system(
+                emitter_system
+                    .after(apply_scene_system)
+                    .before(simulation_step_system),
+            )
+            .add_system(
+                obstacle_collision_system
+                    .after(simulation_step_system)
+                    .before(sync_transforms_system),
+            );
+    }
+
+    app.run();
 }
 
 // Scheduler is used to control the rate that particles are spawned
@@ -280,11 +536,20 @@ fn scheduled_spawner(
     mut commands: Commands,
     mut scheduled: ResMut<ParticleScheduled>,
     mut counter: ResMut<BevyCounter>,
+    mut fluid: ResMut<Fluid>,
+    params: Res<SimulationParams>,
     mut meshes: ResMut<Assets<BevyMesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     if scheduled.wave > 0 {
-        spawn_particles(&mut commands, &mut counter, &mut meshes, &mut materials);
+        spawn_particles(
+            &mut commands,
+            &mut counter,
+            &mut fluid,
+            &params,
+            &mut meshes,
+            &mut materials,
+        );
         scheduled.wave -= 1;
     }
 }
@@ -292,6 +557,77 @@ fn scheduled_spawner(
 #[derive(Component)]
 struct StatsText;
 
+const STATS_FONT: &str = "fonts/FiraSans-Bold.ttf";
+
+// Lines of the stats text, in the order of STATS_LABELS
+#[derive(Clone, Copy)]
+enum Stat {
+    ParticleCount,
+    AverageFps,
+    PressureSolver,
+    SolverIterations,
+    NeighborSearch,
+    DensityError,
+    NeighborRebuilds,
+    Energy,
+    Momentum,
+    Mass,
+    Compression,
+    MaxSpeed,
+}
+
+const STATS_LABELS: &[&str] = &[
+    "Particle Count",
+    "Average FPS",
+    "Pressure Solver",
+    "Solver Iterations",
+    "Neighbor Search",
+    "Density Error",
+    "Neighbor Rebuilds",
+    "Energy",
+    "Momentum",
+    "Mass",
+    "Compression",
+    "Max Speed",
+];
+
+impl Stat {
+    // Every line is a label section followed by the value section
+    fn section(self) -> usize {
+        2 * self as usize + 1
+    }
+}
+
+fn label_style(font: &Handle<Font>) -> TextStyle {
+    TextStyle {
+        font: font.clone(),
+        font_size: 40.0,
+        color: Color::rgb(0.0, 1.0, 0.0),
+    }
+}
+
+fn value_style(font: &Handle<Font>) -> TextStyle {
+    TextStyle {
+        font: font.clone(),
+        font_size: 40.0,
+        color: Color::rgb(0.0, 1.0, 1.0),
+    }
+}
+
+// A label and an empty value for every line, counter_system fills in the values
+fn stats_sections(font: &Handle<Font>) -> Vec<TextSection> {
+    let mut sections = Vec::new();
+    for (line, label) in STATS_LABELS.iter().enumerate() {
+        let separator = if line == 0 { "" } else { "\n" };
+        sections.push(TextSection::new(
+            format!("{separator}{label}: "),
+            label_style(font),
+        ));
+        sections.push(TextSection::from_style(value_style(font)));
+    }
+    sections
+}
+
 fn setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
@@ -303,43 +639,17 @@ fn setup(
     // Setup text for particle count and FPS
     commands
         .spawn(
-            TextBundle::from_sections([
-                TextSection::new(
-                    "Particle Count: ",
-                    TextStyle {
-                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                        font_size: 40.0,
-                        color: Color::rgb(0.0, 1.0, 0.0),
-                    },
-                ),
-                TextSection::from_style(TextStyle {
-                    font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                    font_size: 40.0,
-                    color: Color::rgb(0.0, 1.0, 1.0),
-                }),
-                TextSection::new(
-                    "\nAverage FPS: ",
-                    TextStyle {
-                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                        font_size: 40.0,
-                        color: Color::rgb(0.0, 1.0, 0.0),
+            TextBundle::from_sections(stats_sections(&asset_server.load(STATS_FONT))).with_style(
+                Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        top: Val::Px(5.0),
+                        left: Val::Px(5.0),
+                        ..default()
                     },
-                ),
-                TextSection::from_style(TextStyle {
-                    font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                    font_size: 40.0,
-                    color: Color::rgb(0.0, 1.0, 1.0),
-                }),
-            ])
-            .with_style(Style {
-                position_type: PositionType::Absolute,
-                position: UiRect {
-                    top: Val::Px(5.0),
-                    left: Val::Px(5.0),
                     ..default()
                 },
-                ..default()
-            }),
+            ),
         )
         .insert(StatsText);
 
@@ -372,8 +682,14 @@ fn setup(
 //  Handle mouse events
 fn mouse_handler(
     mouse_button_input: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
     mut scheduled: ResMut<ParticleScheduled>,
+    mut contexts: EguiContexts,
 ) {
+    // Clicks on the parameters panel are not meant for the scene, Ctrl + click places a probe
+    if contexts.ctx_mut().is_pointer_over_area() || ctrl_pressed(&keys) {
+        return;
+    }
     //  If the left mouse button is pressed, and a wave of particles is not scheduled
     if mouse_button_input.pressed(MouseButton::Left) & (scheduled.wave == 0) {
         // schedule a wave of particles
@@ -381,74 +697,230 @@ fn mouse_handler(
     }
 }
 
+//  Switch the pressure solver with the number keys, the equation of state with T, the
+//  neighbor search with O and between the serial and parallel paths with P
+fn keyboard_handler(
+    input: Res<Input<KeyCode>>,
+    params: Res<SimulationParams>,
+    mut settings: ResMut<SimulationSettings>,
+) {
+    if input.just_pressed(KeyCode::Key1) {
+        settings.mode = SolverMode::Wcsph;
+    }
+    if input.just_pressed(KeyCode::Key2) {
+        settings.mode = SolverMode::Dfsph;
+    }
+    if input.just_pressed(KeyCode::Key3) {
+        settings.mode = SolverMode::Pcisph;
+    }
+    if input.just_pressed(KeyCode::Key4) {
+        settings.mode = SolverMode::Iisph;
+    }
+    // Toggle the equation of state of the weakly compressible solver
+    if input.just_pressed(KeyCode::T) {
+        settings.equation_of_state = match settings.equation_of_state {
+            EquationOfState::Linear => EquationOfState::Tait(TaitParameters::for_params(&params)),
+            EquationOfState::Tait(_) => EquationOfState::Linear,
+        };
+    }
+    // Toggle between the uniform grid and the octree neighbor search
+    if input.just_pressed(KeyCode::O) {
+        settings.neighbor_backend = match settings.neighbor_backend {
+            NeighborBackend::UniformGrid => NeighborBackend::Octree,
+            NeighborBackend::Octree => NeighborBackend::UniformGrid,
+        };
+    }
+    // Toggle evaluating the density, forces and integration on all CPU cores
+    if input.just_pressed(KeyCode::P) {
+        settings.parallel = !settings.parallel;
+    }
+}
+
 fn spawn_particles(
     commands: &mut Commands,
     counter: &mut BevyCounter,
+    fluid: &mut Fluid,
+    params: &SimParams,
     meshes: &mut ResMut<Assets<BevyMesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
 ) {
     let mut rng = thread_rng(); // create random number generator
 
-    let spawn_count = (SPAWN_WIDTH_RATIO * SIZE_X / (PARTICLE_RADIUS)).floor() as usize; // how many particle to spawn
-    let particle_x_source = (-SIZE_X / 2.) + PARTICLE_RADIUS; // left side of the window
-    let particle_y_source = SIZE_Y / 2.; // top of the window
-    let particle_z_source = -0. * SIZE_Z / 2.;
+    let size = params.box_size;
+    let spawn_count = (SPAWN_WIDTH_RATIO * size.x / (PARTICLE_RADIUS)).floor() as usize; // how many particle to spawn
+    let particle_x_source = (-size.x / 2.) + PARTICLE_RADIUS; // left side of the window
+    let particle_y_source = size.y / 2.; // top of the window
+    let particle_z_source = -0. * size.z / 2.;
 
     for count in 0..spawn_count {
         // offset each particle so they do not start on top of each other
         let particle_offset_x = (count as f32) * PARTICLE_RADIUS;
         let particle_x = particle_x_source + particle_offset_x;
 
-        // spawn a new particle
-        commands
-            .spawn(PbrBundle {
-                /*mesh: meshes.add(BevyMesh::from(shape::Icosphere {
-                    // add the mesh
-                    radius: PARTICLE_RADIUS,
-                    subdivisions: 5,
-                })),*/
-                mesh: meshes.add(BevyMesh::from(UVSphere {
-                    radius: PARTICLE_RADIUS,
-                    sectors: 32,
-                    stacks: 32,
-                })),
-                material: materials.add(StandardMaterial {
-                    base_color: Color::rgba(1., 1., 1., 1.),
-                    ..default()
-                }),
-                transform: Transform::from_xyz(particle_x, particle_y_source, particle_z_source), // initial particle position
+        // add the particle to the fluid
+        let position = Vec3::new(particle_x, particle_y_source, particle_z_source); // initial particle position
+        let id = fluid.push(
+            position,
+            Vec3::new(
+                // Set initial velocity
+                0.2 * rng.gen::<f32>(), // small random velocity in the x direction, otherwise they just stack on top of each other
+                -2. * PARTICLE_RADIUS * SPAWN_RATE, // downward velocity, so the particle is out of the way when the next wave spawns
+                0.2 * rng.gen::<f32>(),
+            ),
+        );
+
+        spawn_particle_entity(commands, counter, meshes, materials, id, position);
+    }
+}
+
+// Identifier of the particle in the Fluid resource, its position is copied into the Transform
+// for rendering
+#[derive(Component)]
+struct Particle {
+    pub id: usize,
+}
+
+// Copy the particle positions into the Transforms that render them
+fn sync_transforms_system(
+    fluid: Res<Fluid>,
+    mut particle_query: Query<(&Particle, &mut Transform)>,
+) {
+    for (particle, mut transform) in &mut particle_query {
+        transform.translation = fluid.positions[fluid.index_of(particle.id)];
+    }
+}
+
+// Spawn the sphere rendering the particle with the given identifier in the Fluid resource
+fn spawn_particle_entity(
+    commands: &mut Commands,
+    counter: &mut BevyCounter,
+    meshes: &mut Assets<BevyMesh>,
+    materials: &mut Assets<StandardMaterial>,
+    id: usize,
+    position: Vec3,
+) {
+    commands
+        .spawn(PbrBundle {
+            /*mesh: meshes.add(BevyMesh::from(shape::Icosphere {
+                // add the mesh
+                radius: PARTICLE_RADIUS,
+                subdivisions: 5,
+            })),*/
+            mesh: meshes.add(BevyMesh::from(UVSphere {
+                radius: PARTICLE_RADIUS,
+                sectors: 32,
+                stacks: 32,
+            })),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(1., 1., 1., 1.),
                 ..default()
-            })
-            .insert(Particle {
-                velocity: Vec3::new(
-                    // Set initial velocity
-                    0.2 * rng.gen::<f32>(), // small random velocity in the x direction, otherwise they just stack on top of each other
-                    -2. * PARTICLE_RADIUS * SPAWN_RATE as f32, // downward velocity, so the particle is out of the way when the next wave spawns
-                    0.2 * rng.gen::<f32>(),
-                ),
-                acceleration: Vec3::ZERO,
-                density: 0.,
-                pressure: 0.,
-                force: Vec3::ZERO,
-            });
-        counter.count += 1;
+            }),
+            transform: Transform::from_translation(position),
+            ..default()
+        })
+        .insert(Particle { id });
+    counter.count += 1;
+}
+
+const SOLVER_ITERATIONS: DiagnosticId =
+    DiagnosticId::from_u128(248915385731290654731825930417283457702);
+const SOLVER_RESIDUAL: DiagnosticId =
+    DiagnosticId::from_u128(61893027461893045627183904561230987345);
+
+// Register the pressure solver diagnostics so LogDiagnosticsPlugin reports them
+fn setup_solver_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(SOLVER_ITERATIONS, "solver_iterations", 20));
+    diagnostics.add(Diagnostic::new(SOLVER_RESIDUAL, "solver_residual", 20).with_suffix("%"));
+}
+
+// Record the iteration count and remaining density error of every pressure solve
+fn solver_diagnostics_system(
+    settings: Res<SimulationSettings>,
+    stats: Res<SimulationStats>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    if settings.mode == SolverMode::Wcsph {
+        return;
     }
+    diagnostics.add_measurement(SOLVER_ITERATIONS, || {
+        (stats.divergence_iterations + stats.density_iterations) as f64
+    });
+    diagnostics.add_measurement(SOLVER_RESIDUAL, || 100. * stats.density_error as f64);
 }
 
 fn counter_system(
     diagnostics: Res<Diagnostics>,
     counter: Res<BevyCounter>,
+    solver_settings: Res<SimulationSettings>,
+    solver_stats: Res<SimulationStats>,
+    fluid: Res<Fluid>,
+    conservation: Res<SimulationConservation>,
     mut query: Query<&mut Text, With<StatsText>>,
 ) {
     let mut text = query.single_mut();
 
     if counter.is_changed() {
-        text.sections[1].value = counter.count.to_string();
+        text.sections[Stat::ParticleCount.section()].value = counter.count.to_string();
+    }
+
+    if solver_settings.is_changed() {
+        text.sections[Stat::PressureSolver.section()].value =
+            match solver_settings.equation_of_state {
+                EquationOfState::Tait(_) if solver_settings.mode == SolverMode::Wcsph => {
+                    format!("{} (Tait)", solver_settings.mode.name())
+                }
+                _ => solver_settings.mode.name().to_string(),
+            };
+        text.sections[Stat::NeighborSearch.section()].value =
+            solver_settings.neighbor_backend.name().to_string();
+    }
+
+    if solver_stats.is_changed() {
+        text.sections[Stat::SolverIterations.section()].value = format!(
+            "{} divergence, {} density",
+            solver_stats.divergence_iterations, solver_stats.density_iterations
+        );
+        text.sections[Stat::DensityError.section()].value = format!(
+            "{:.2}% (divergence {:.2}%)",
+            100. * solver_stats.density_error,
+            100. * solver_stats.divergence_error
+        );
+    }
+
+    if fluid.is_changed() {
+        let neighbor_list = fluid.neighbor_list();
+        text.sections[Stat::NeighborRebuilds.section()].value =
+            format!("{} in {} steps", neighbor_list.builds, neighbor_list.uses);
+    }
+
+    if conservation.is_changed() {
+        let (fluid, bodies) = (conservation.fluid, conservation.bodies);
+        text.sections[Stat::Energy.section()].value = format!(
+            "{:.3e} kinetic + {:.3e} potential = {:.3e}",
+            fluid.kinetic_energy + bodies.kinetic_energy,
+            fluid.potential_energy + bodies.potential_energy,
+            conservation.energy()
+        );
+        let momentum = conservation.momentum();
+        text.sections[Stat::Momentum.section()].value = format!(
+            "({:.3e}, {:.3e}, {:.3e})",
+            momentum.x, momentum.y, momentum.z
+        );
+        text.sections[Stat::Mass.section()].value = format!("{}", conservation.mass());
+        text.sections[Stat::Compression.section()].value = format!(
+            "{:.2}% average, {:.2}% max",
+            100. * conservation.mean_density_error,
+            100. * conservation.max_density_error
+        );
+        text.sections[Stat::MaxSpeed.section()].value = format!(
+            "{:.0} fluid, {:.0} capsules",
+            fluid.max_speed, bodies.max_speed
+        );
     }
 
     if let Some(fps) = diagnostics.get(FrameTimeDiagnosticsPlugin::FPS) {
         if let Some(average) = fps.average() {
-            text.sections[3].value = format!("{average:.2}");
+            text.sections[Stat::AverageFps.section()].value = format!("{average:.2}");
         }
     };
 }
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use glam::{IVec3, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::octree_nearest_neighbor::populate_octree;
+
+/*
+ *
+ * Uniform Grid Neighbor Search
+ * Particles are hashed into cubic cells with the size of the search radius, so every neighbor
+ * of a particle is either in its own cell or in one of the 26 surrounding cells.
+ *
+ */
+
+// Offsets of the 13 surrounding cells that come after a cell, visiting only these from every
+// cell finds each pair of cells once
+const FORWARD_OFFSETS: [IVec3; 13] = [
+    IVec3::new(1, -1, -1),
+    IVec3::new(1, -1, 0),
+    IVec3::new(1, -1, 1),
+    IVec3::new(1, 0, -1),
+    IVec3::new(1, 0, 0),
+    IVec3::new(1, 0, 1),
+    IVec3::new(1, 1, -1),
+    IVec3::new(1, 1, 0),
+    IVec3::new(1, 1, 1),
+    IVec3::new(0, 1, -1),
+    IVec3::new(0, 1, 0),
+    IVec3::new(0, 1, 1),
+    IVec3::new(0, 0, 1),
+];
+
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<IVec3, Vec<usize>>,
+}
+
+impl SpatialHash {
+    pub fn new(positions: &[Vec3], cell_size: f32) -> Self {
+        let mut cells: HashMap<IVec3, Vec<usize>> = HashMap::new();
+        for (index, position) in positions.iter().enumerate() {
+            let cell = (*position / cell_size).floor().as_ivec3();
+            cells.entry(cell).or_default().push(index);
+        }
+        SpatialHash { cell_size, cells }
+    }
+
+    // Every unordered pair of particles closer than the cell size, as (lower index, higher index)
+    // sorted so the result does not depend on the hash map order
+    pub fn pairs(&self, positions: &[Vec3]) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let mut push_if_close = |a: usize, b: usize| {
+            if positions[a].distance(positions[b]) < self.cell_size {
+                pairs.push((a.min(b), a.max(b)));
+            }
+        };
+
+        for (cell, indices) in self.cells.iter() {
+            for (n, &a) in indices.iter().enumerate() {
+                for &b in indices[(n + 1)..].iter() {
+                    push_if_close(a, b);
+                }
+            }
+
+            for offset in FORWARD_OFFSETS {
+                if let Some(neighbor_indices) = self.cells.get(&(*cell + offset)) {
+                    for &a in indices.iter() {
+                        for &b in neighbor_indices.iter() {
+                            push_if_close(a, b);
+                        }
+                    }
+                }
+            }
+        }
+
+        pairs.sort_unstable();
+        pairs
+    }
+
+    // Particles in the cell of a point and the 26 around it, a superset of every particle closer
+    // to the point than the cell size
+    pub fn near(&self, point: Vec3) -> impl Iterator<Item = usize> + '_ {
+        let cell = (point / self.cell_size).floor().as_ivec3();
+        (-1..=1)
+            .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| IVec3::new(x, y, z))))
+            .filter_map(move |offset| self.cells.get(&(cell + offset)))
+            .flatten()
+            .copied()
+    }
+}
+
+// Data structure used to find the neighboring pairs
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NeighborBackend {
+    #[default]
+    UniformGrid,
+    Octree,
+}
+
+impl NeighborBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NeighborBackend::UniformGrid => "Uniform Grid",
+            NeighborBackend::Octree => "Octree",
+        }
+    }
+}
+
+// Every unordered pair of particles closer than the radius, as (lower index, higher index) sorted
+pub fn find_pairs(
+    positions: &[Vec3],
+    radius: f32,
+    backend: NeighborBackend,
+) -> Vec<(usize, usize)> {
+    match backend {
+        NeighborBackend::UniformGrid => SpatialHash::new(positions, radius).pairs(positions),
+        NeighborBackend::Octree => {
+            let mut pairs: Vec<(usize, usize)> = populate_octree(positions)
+                .nearest_neighbor_list(radius)
+                .into_iter()
+                .map(|(point, neighbor)| (point.index, neighbor.index))
+                .collect();
+            pairs.sort_unstable();
+            pairs
+        }
+    }
+}
+
+/*
+ *
+ * Verlet Neighbor Lists
+ * The pairs are searched with the radius grown by a skin and kept until a particle has moved
+ * more than half the skin since the search. Two particles then came at most a skin closer, so
+ * every pair within the radius is still in the list and only the distances need checking.
+ *
+ */
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct VerletList {
+    // Pairs within the radius plus the skin at the last build, sorted
+    pairs: Vec<(usize, usize)>,
+    // Positions of the particles at the last build
+    positions: Vec<Vec3>,
+    radius: f32,
+    skin: f32,
+    backend: NeighborBackend,
+    // Number of times the list was built and asked for pairs
+    pub builds: usize,
+    pub uses: usize,
+}
+
+impl VerletList {
+    // Every unordered pair of particles closer than the radius, the same as find_pairs(),
+    // rebuilding the list first when it can be missing some
+    pub fn pairs(
+        &mut self,
+        positions: &[Vec3],
+        radius: f32,
+        skin: f32,
+        backend: NeighborBackend,
+    ) -> Vec<(usize, usize)> {
+        if self.needs_rebuild(positions, radius, skin, backend) {
+            self.pairs = find_pairs(positions, radius + skin, backend);
+            self.positions = positions.to_vec();
+            self.radius = radius;
+            self.skin = skin;
+            self.backend = backend;
+            self.builds += 1;
+        }
+        self.uses += 1;
+
+        (self.pairs.iter())
+            .copied()
+            .filter(|&(a, b)| positions[a].distance(positions[b]) < radius)
+            .collect()
+    }
+
+    // Force a rebuild on the next use, for when the particles were reordered
+    pub fn invalidate(&mut self) {
+        self.positions.clear();
+    }
+
+    fn needs_rebuild(
+        &self,
+        positions: &[Vec3],
+        radius: f32,
+        skin: f32,
+        backend: NeighborBackend,
+    ) -> bool {
+        let max_distance = skin / 2.;
+        self.positions.len() != positions.len()
+            || self.builds == 0
+            || self.radius != radius
+            || self.skin != skin
+            || self.backend != backend
+            || (positions.iter())
+                .zip(self.positions.iter())
+                .any(|(position, built)| position.distance(*built) > max_distance)
+    }
+}
+
+// Indices of the neighbors of every particle, in increasing order
+pub fn neighbors_from_pairs(count: usize, pairs: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut neighbors = vec![Vec::new(); count];
+    for &(a, b) in pairs.iter() {
+        neighbors[a].push(b);
+        neighbors[b].push(a);
+    }
+    neighbors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::SimParams;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn pairs_match_brute_force() {
+        let smoothing_length = SimParams::default().smoothing_length;
+        let mut rng = StdRng::seed_from_u64(7);
+        // Dense enough that most cells hold several particles, and spread across negative
+        // coordinates to cover the cell rounding
+        let positions: Vec<Vec3> = (0..2000)
+            .map(|_| {
+                Vec3::new(
+                    rng.gen_range(-600.0..600.0),
+                    rng.gen_range(-400.0..400.0),
+                    rng.gen_range(-400.0..400.0),
+                )
+            })
+            .collect();
+
+        let mut brute_force = Vec::new();
+        for a in 0..positions.len() {
+            for b in (a + 1)..positions.len() {
+                if positions[a].distance(positions[b]) < smoothing_length {
+                    brute_force.push((a, b));
+                }
+            }
+        }
+
+        let pairs = SpatialHash::new(&positions, smoothing_length).pairs(&positions);
+        assert!(!pairs.is_empty());
+        assert_eq!(pairs, brute_force);
+
+        let octree_pairs = find_pairs(&positions, smoothing_length, NeighborBackend::Octree);
+        assert_eq!(octree_pairs, brute_force);
+    }
+}
@@ -1,46 +1,27 @@
 /*
  *
  * Octree and Nearest Neighbor (nn) Implementation
- * 
+ *
  */
 
-// Max point for octree and nn list. Should be changed to be a dynamic size later
-const MAX_POINTS: usize = 1000;
-
-#[derive(Resource, Default)]
-struct NearestNeightborList {
-    // list: [Particle; MAX_POINTS]   // Figure out how to satisfy the derived default trait for the list
-    list: Vec<Particle>, // Playing with Vecs for now
-}
+use glam::{BVec3, UVec3, Vec3};
 
-// Fill up the octree with particles from the environment
-fn populate_octree(
-    mut octree: ResMut<Octree>,
-    particle_query: Query<(&mut Particle, &Transform, Entity)>,
-) {
-    let mut new_octree = Octree::new(10);
-
-    for (_particle, transform, entity) in &particle_query {
-        let point = Point3D {
-            x: transform.translation.x,
-            y: transform.translation.y,
-            z: transform.translation.z,
-            entity,
-        };
-        new_octree.insert(point);
-    }
+use crate::sph::{SIZE_X, SIZE_Y, SIZE_Z};
 
-    *octree = new_octree;
-    //somehow create nearest neighbors list global variable here
+// Particle Point that holds the xyz location alongside the particle ID, its position in the
+// list the octree was built from
+#[derive(Clone, Copy, Debug)]
+pub struct Point3D {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub index: usize,
 }
 
-// Particle Point that holds the xyz location alongside the particle ID
-#[derive(Clone, Copy)]
-struct Point3D {
-    x: f32,
-    y: f32,
-    z: f32,
-    entity: Entity,
+impl Point3D {
+    pub fn position(&self) -> Vec3 {
+        Vec3::new(self.x, self.y, self.z)
+    }
 }
 
 // An octree node could be either a branch (another octree) or it could be a leaf (which holds the particle data)
@@ -50,21 +31,69 @@ enum OctreeNode {
 }
 
 // Octree Stucture
-#[derive(Resource)]
-struct Octree {
+pub struct Octree {
     root: OctreeNode,
     max_depth: usize,
+    // Half the side length of the root cube
     length: f32,
     center: Vec3,
 }
 
-fn initialize_octree(mut commands: Commands) {
-    commands.insert_resource(Octree::new(10));
+// Given the center and half side length of a node, find the octant a point belongs in and the
+// center of that octant
+fn octant(point: &Point3D, length: f32, center: Vec3) -> (usize, Vec3) {
+    let mut new_center = center;
+    let offset = length / 2.;
+
+    // Checks to see which octant to put the new point into
+    let upper = point.position().cmpgt(center);
+    let index = octant_index(upper);
+    new_center += Vec3::select(upper, Vec3::splat(offset), Vec3::splat(-offset));
+
+    (index, new_center)
+}
+
+// Index of the child octant on the upper side of the center along the set axes, x is bit 0, y
+// bit 1 and z bit 2
+pub fn octant_index(upper: BVec3) -> usize {
+    upper.x as usize | (upper.y as usize) << 1 | (upper.z as usize) << 2
+}
+
+// Position of a grid cell along the Morton (Z-order) curve. Every 3 bits are the octant the cell
+// is in one level further down an octree over the grid, so sorting by the code visits the cells
+// in the same order as the octree visits its children. Uses the lower 21 bits of each coordinate.
+pub fn morton_code(cell: UVec3) -> u64 {
+    let mut code = 0;
+    for level in (0..21).rev() {
+        let upper = BVec3::new(
+            (cell.x >> level) & 1 == 1,
+            (cell.y >> level) & 1 == 1,
+            (cell.z >> level) & 1 == 1,
+        );
+        code = code << 3 | octant_index(upper) as u64;
+    }
+    code
+}
+
+// Fill up an octree with the particles, each point indexed by its position in the list. This is
+// how the octree is registered as a neighbor search: find_pairs() builds it here when
+// NeighborBackend::Octree is selected.
+pub fn populate_octree(positions: &[Vec3]) -> Octree {
+    let mut octree = Octree::new(10);
+    for (index, position) in positions.iter().enumerate() {
+        octree.insert(Point3D {
+            x: position.x,
+            y: position.y,
+            z: position.z,
+            index,
+        });
+    }
+    octree
 }
 
 // Functions for OctreeNode
 // Insert(): Insert a point/particle into the octree
-// get_point_by_index(): Given an idex, return all the branches in the node
+// for_each_leaf(): Call a function with the points of every leaf in the node
 impl OctreeNode {
     // Insert a new point into the octree
     fn insert(
@@ -80,220 +109,658 @@ impl OctreeNode {
             // Handles the case when the current node is a branch node
             // Perform checks and insert when the current node is a branch
             OctreeNode::Branch(children) => {
-                let mut new_center = center;
-
-                let x = point.x;
-                let y = point.y;
-                let z = point.z;
-
-                let mut index = 0;
-
-                // Checks to see which octant to put the new point into
-                if x > center.x {
-                    index |= 1;
-                    new_center.x += length;
-                } else {
-                    new_center.x -= length;
-                }
-                if y > center.y {
-                    index |= 2;
-                    new_center.y += length;
-                } else {
-                    new_center.y -= length;
-                }
-                if z > center.z {
-                    index |= 4;
-                    new_center.z += length;
-                } else {
-                    new_center.z -= length;
-                }
+                let (index, new_center) = octant(&point, length, center);
 
                 // Check to see if there is a child node and create one if there isn't
-                if children[index].is_none() {
-                    //Is this where we give the entity and store it?
-                    children[index] = Some(Box::new(OctreeNode::Leaf(Vec::new())));
-                }
-
-                // If there is a child node, then get the branch and insert the new point into the branch
-                if let Some(mut child) = children[index].take() {
-                    child.insert(point, depth + 1, max_depth, length / 2., new_center);
-                    children[index] = Some(child);
-                }
+                let child =
+                    children[index].get_or_insert_with(|| Box::new(OctreeNode::Leaf(Vec::new())));
+                child.insert(point, depth + 1, max_depth, length / 2., new_center);
             }
             // Handles the case when the current node is a leaf node
             OctreeNode::Leaf(points) => {
                 points.push(point);
+                // Splitting points at the same position never separates them, it only recurses down to max depth.
+                // A leaf above max depth only holds more than 9 points when they all coincide, so the new point
+                // only has to be compared against the first one.
+                let coincident = if points.len() > 9 {
+                    point.position() == points[0].position()
+                } else {
+                    points
+                        .iter()
+                        .all(|other| other.position() == points[0].position())
+                };
                 // If pushing the new point into the leaf node causes a size greater than 8 and max depth hasn't been hit,
                 // Split the leaf into 8 smaller leaf nodes
-                if points.len() > 8 && depth < max_depth {
-                    let mut children = [None, None, None, None, None, None, None, None];
-                    // Checks to see which octant to put the new point into
-                    for point in points.drain(..) {
-                        let mut new_center = center;
-
-                        let x = point.x;
-                        let y = point.y;
-                        let z = point.z;
-
-                        let mut index = 0;
-
-                        // Checks to see which octant to put the new point into
-                        if x > center.x {
-                            index |= 1;
-                            new_center.x += length;
-                        } else {
-                            new_center.x -= length;
-                        }
-                        if y > center.y {
-                            index |= 2;
-                            new_center.y += length;
-                        } else {
-                            new_center.y -= length;
-                        }
-                        if z > center.z {
-                            index |= 4;
-                            new_center.z += length;
-                        } else {
-                            new_center.z -= length;
-                        }
-
-                        // Check to see if there is a child node and create one if there isn't
-                        if children[index].is_none() {
-                            children[index] = Some(Box::new(OctreeNode::Leaf(Vec::new())));
-                        }
-                        // If there is a child node, then get the branch and insert the new point into the branch
-                        if let Some(mut child) = children[index].take() {
-                            child.insert(point, depth + 1, max_depth, length / 2., new_center);
-                            children[index] = Some(child);
-                        }
-                    }
+                if points.len() > 8 && depth < max_depth && !coincident {
+                    let points = std::mem::take(points);
                     // Once leaf node is split into more nodes, change itself into a branch
-                    *self = OctreeNode::Branch(children);
+                    *self = OctreeNode::Branch(Default::default());
+                    for point in points {
+                        self.insert(point, depth, max_depth, length, center);
+                    }
                 }
             }
         }
     }
 
-    // Given an idex, return all the branches in the node
-    fn get_points_by_index() {
-        //return all of the points in a single leaf
-        //recursive, possibly return empty list of neighbors when next would be out of bounds
+    // Call a function with the points of every leaf in the node
+    fn for_each_leaf(&self, function: &mut impl FnMut(&[Point3D])) {
+        match self {
+            OctreeNode::Branch(children) => {
+                for child in children.iter().flatten() {
+                    child.for_each_leaf(function);
+                }
+            }
+            OctreeNode::Leaf(points) => function(points),
+        }
     }
 }
 
 // Function for Octree
 // new(): Initiate the tree
 // nearest_neighbor_list(): Create a list that is comprised of tuples of points that are connected to eachother
-// insert(): Insert a point into the current octree
-// tranverse(): Traverse the tree to print out all the points in it
+// insert(): Insert a point into the current octree, growing the root cube if the point is outside of it
+// contains(): Check if a point is inside the root cube
+// grow_towards(): Double the root cube in the direction of a point
 // search(): Given a point, radius, and octree, search for particles within the radius
-// search_recursive(): Helper function for search()
+// nearest(): Find the k points closest to a position
+// search_box(): Find the points inside an axis aligned box
+// search_ray(): Find the points within a radius of a ray or segment
+// search_recursive(): Helper function for the searches, skips the cubes that cannot hold a match
+// nearest_recursive(): Helper function for nearest()
 impl Octree {
-    // Create a new and empty octree object
-    fn new(max_depth: usize) -> Self {
+    // Create a new and empty octree object. The root cube starts around the box and grows when a
+    // point is inserted outside of it.
+    pub fn new(max_depth: usize) -> Self {
         Octree {
             root: OctreeNode::Leaf(Vec::new()),
-            max_depth: max_depth,
-            length: 400.,
+            max_depth,
+            length: SIZE_X.max(SIZE_Y).max(SIZE_Z) / 2.,
             center: Vec3::new(0., 0., 0.),
         }
     }
 
-    // Create a list that is comprised of tuples of points that are connected to eachother
-    fn nearest_neighbor_list(&self) {
-        // Perform bitwise operation to get neighboring cells (size of particle)
-        neighbors_offset_list();
-        neighbors_list = Vec::new();
-
-        //indexs is a list of byte indices for each leaf
-        for index in indexs {
-            //get points by index
-            points = get_points_by_index(index);
-            for (i, point) in points.enumerate() {
-                //start_at might not be correct, but start at index i
-                for p in points.start_at(i) {
-                    neighbor_list.push(point, p);
-                }
-            }
-
-            // Push neighboring particles into list (offseted amount, the 7 neighboring cells)
-            // TODO: Handle out of bounds neighbor
-            for neighbor_offset in neighbor_offset_list {
-                neighbor_index = neighbor_offset + index;
-                neighbor_points = get_points_by_index(neighbor_index);
-                for point in points {
-                    for neighbor_point in neighbor_points {
-                        neighbor_list.push((point, neighbor_point));
+    // Create a list that is comprised of tuples of points that are within the radius of eachother.
+    // Each pair is only listed once, with the lower index first.
+    pub fn nearest_neighbor_list(&self, radius: f32) -> Vec<(Point3D, Point3D)> {
+        let mut neighbor_list = Vec::new();
+        self.root.for_each_leaf(&mut |points| {
+            for point in points.iter() {
+                for neighbor in self.search(*point, radius) {
+                    if neighbor.index > point.index {
+                        neighbor_list.push((*point, neighbor));
                     }
                 }
             }
-        }
-        return neighbor_list;
+        });
+        neighbor_list
     }
+
     // Insert a point into the current octree
-    fn insert(&mut self, point: Point3D) {
+    pub fn insert(&mut self, point: Point3D) {
+        // A point that blew up to infinity or NaN has no octant and would grow the root forever
+        if !point.position().is_finite() {
+            return;
+        }
+        while !self.contains(&point) {
+            self.grow_towards(&point);
+        }
         self.root
             .insert(point, 0, self.max_depth, self.length, self.center);
     }
 
-    // Traverse the tree to print out all the points in it
-    fn traverse(&self, node: &OctreeNode) {
-        // Check to see if current node is a branch or leaf
-        match node {
-            // For branches, recursively call the traverse function on all children that isn't none
-            OctreeNode::Branch(children) => {
-                for child in children.iter() {
-                    if let Some(child) = child {
-                        self.traverse(child);
-                    }
-                }
-            }
-            // For leafs, iterate through all the points in the leaf and print out the coordinate
-            OctreeNode::Leaf(points) => {
-                for point in points.iter() {
-                    println!("Point: ({}, {}, {})", point.x, point.y, point.z);
-                }
-            }
+    // Check if a point is inside the root cube
+    fn contains(&self, point: &Point3D) -> bool {
+        (point.position() - self.center).abs().max_element() <= self.length
+    }
+
+    // Double the root cube in the direction of a point outside of it, the old root becomes one of
+    // the octants of the new root
+    fn grow_towards(&mut self, point: &Point3D) {
+        let direction = Vec3::select(
+            point.position().cmpgt(self.center),
+            Vec3::ONE,
+            Vec3::NEG_ONE,
+        );
+        let new_center = self.center + self.length * direction;
+
+        // The old root sits on the opposite side of the new center from the point
+        let mut index = 0;
+        if direction.x < 0. {
+            index |= 1;
+        }
+        if direction.y < 0. {
+            index |= 2;
+        }
+        if direction.z < 0. {
+            index |= 4;
         }
+
+        let old_root = std::mem::replace(&mut self.root, OctreeNode::Leaf(Vec::new()));
+        let mut children: [Option<Box<OctreeNode>>; 8] = Default::default();
+        children[index] = Some(Box::new(old_root));
+        self.root = OctreeNode::Branch(children);
+        self.center = new_center;
+        self.length *= 2.;
     }
 
     // Given a point, radius, and octree, search for particles within the radius
-    fn search(&self, center: Point3D, radius: f32) -> Vec<Point3D> {
+    pub fn search(&self, center: Point3D, radius: f32) -> Vec<Point3D> {
+        let center = center.position();
         let mut points = Vec::new();
-        self.search_recursive(&self.root, &center, radius, &mut points);
-        return points;
+        // Skip cubes that are farther than the radius from the center
+        self.search_recursive(
+            &self.root,
+            self.length,
+            self.center,
+            &|node_center, node_length| {
+                distance_to_cube(center, node_center, node_length) <= radius
+            },
+            &mut |point| {
+                if point.position().distance_squared(center) < radius.powi(2) {
+                    points.push(*point);
+                }
+            },
+        );
+        points
     }
 
-    // Helper function for recursively searching the tree
+    // Find the k points closest to a position, sorted from closest to farthest
+    pub fn nearest(&self, position: Vec3, k: usize) -> Vec<Point3D> {
+        let mut nearest = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.nearest_recursive(
+                &self.root,
+                self.length,
+                self.center,
+                position,
+                k,
+                &mut nearest,
+            );
+        }
+        nearest.into_iter().map(|(_, point)| point).collect()
+    }
+
+    // Find every point inside an axis aligned box, including its boundary
+    pub fn search_box(&self, min: Vec3, max: Vec3) -> Vec<Point3D> {
+        let mut points = Vec::new();
+        // Skip cubes that do not overlap the box
+        self.search_recursive(
+            &self.root,
+            self.length,
+            self.center,
+            &|node_center, node_length| {
+                (node_center - Vec3::splat(node_length)).cmple(max).all()
+                    && (node_center + Vec3::splat(node_length)).cmpge(min).all()
+            },
+            &mut |point| {
+                let position = point.position();
+                if position.cmpge(min).all() && position.cmple(max).all() {
+                    points.push(*point);
+                }
+            },
+        );
+        points
+    }
+
+    // Find every point within a radius of the segment from the origin along the direction for
+    // max_distance (infinity for a ray). Returns each point with its distance along the
+    // direction, sorted from the origin outwards.
+    pub fn search_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        radius: f32,
+    ) -> Vec<(Point3D, f32)> {
+        let direction = direction.normalize();
+        let mut points = Vec::new();
+        // Skip cubes that the segment misses once they are grown by the radius
+        self.search_recursive(
+            &self.root,
+            self.length,
+            self.center,
+            &|node_center, node_length| {
+                segment_hits_cube(
+                    origin,
+                    direction,
+                    max_distance,
+                    node_center,
+                    node_length + radius,
+                )
+            },
+            &mut |point| {
+                let position = point.position();
+                let distance = (position - origin).dot(direction).clamp(0., max_distance);
+                if position.distance_squared(origin + distance * direction) < radius.powi(2) {
+                    points.push((*point, distance));
+                }
+            },
+        );
+        points.sort_by(|a, b| a.1.total_cmp(&b.1));
+        points
+    }
+
+    // Helper function for recursively searching the tree. Only descends into the cubes that
+    // overlaps() accepts, given their center and half side length, and calls visit() with every
+    // point in the leaves that are reached.
     fn search_recursive(
         &self,
         node: &OctreeNode,
-        center: &Point3D,
-        radius: f32,
-        points: &mut Vec<Point3D>,
+        node_length: f32,
+        node_center: Vec3,
+        overlaps: &impl Fn(Vec3, f32) -> bool,
+        visit: &mut impl FnMut(&Point3D),
     ) {
         match node {
-            // For branches, recursively call the search function on all children that isn't none
+            // For branches, recursively call the search function on all children that could hold matching points
             OctreeNode::Branch(children) => {
-                for child in children.iter() {
+                for (index, child) in children.iter().enumerate() {
                     if let Some(child) = child {
-                        self.search_recursive(child, center, radius, points);
+                        let child_length = node_length / 2.;
+                        let child_center = child_center(node_center, node_length, index);
+                        if overlaps(child_center, child_length) {
+                            self.search_recursive(
+                                child,
+                                child_length,
+                                child_center,
+                                overlaps,
+                                visit,
+                            );
+                        }
                     }
                 }
             }
-            // For leafs, iterate through all the points in the leaf and check if they are within the given radius from the center point
+            // For leafs, hand every point in the leaf to the query
             OctreeNode::Leaf(child_points) => {
                 for point in child_points.iter() {
-                    let dist_x = center.x - point.x;
-                    let dist_y = center.y - point.y;
-                    let dist_z = center.z - point.z;
-                    let dist_sq = dist_x.powi(2) + dist_y.powi(2) + dist_z.powi(2);
-                    if dist_sq <= radius.powi(2) {
-                        // Add point to the list of found points
-                        points.push(*point);
+                    visit(point);
+                }
+            }
+        }
+    }
+
+    // Helper function for nearest(), keeps the k closest points found so far sorted by distance
+    fn nearest_recursive(
+        &self,
+        node: &OctreeNode,
+        node_length: f32,
+        node_center: Vec3,
+        position: Vec3,
+        k: usize,
+        nearest: &mut Vec<(f32, Point3D)>,
+    ) {
+        match node {
+            // Visit the closest children first so the farther ones can be skipped once k points are found
+            OctreeNode::Branch(children) => {
+                let child_length = node_length / 2.;
+                let mut ordered: Vec<(f32, Vec3, &OctreeNode)> = children
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, child)| {
+                        child.as_ref().map(|child| {
+                            let child_center = child_center(node_center, node_length, index);
+                            (
+                                distance_to_cube(position, child_center, child_length),
+                                child_center,
+                                child.as_ref(),
+                            )
+                        })
+                    })
+                    .collect();
+                ordered.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                for (distance, child_center, child) in ordered {
+                    if nearest.len() == k && distance > nearest[k - 1].0 {
+                        break;
                     }
+                    self.nearest_recursive(child, child_length, child_center, position, k, nearest);
+                }
+            }
+            OctreeNode::Leaf(points) => {
+                for point in points.iter() {
+                    let distance = point.position().distance(position);
+                    if nearest.len() == k && distance >= nearest[k - 1].0 {
+                        continue;
+                    }
+                    let index = nearest.partition_point(|(other, _)| *other <= distance);
+                    nearest.insert(index, (distance, *point));
+                    nearest.truncate(k);
+                }
+            }
+        }
+    }
+}
+
+// Center of a child cube, given the center and half side length of its parent
+fn child_center(center: Vec3, length: f32, index: usize) -> Vec3 {
+    center
+        + length / 2.
+            * Vec3::new(
+                if index & 1 != 0 { 1. } else { -1. },
+                if index & 2 != 0 { 1. } else { -1. },
+                if index & 4 != 0 { 1. } else { -1. },
+            )
+}
+
+// Distance from a position to the closest point of a cube, zero inside the cube
+fn distance_to_cube(position: Vec3, center: Vec3, length: f32) -> f32 {
+    let closest = position.clamp(center - Vec3::splat(length), center + Vec3::splat(length));
+    closest.distance(position)
+}
+
+// Slab test of the segment from the origin along a unit direction for max_distance against a cube
+fn segment_hits_cube(
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    center: Vec3,
+    length: f32,
+) -> bool {
+    let min = center - Vec3::splat(length);
+    let max = center + Vec3::splat(length);
+    let mut enter: f32 = 0.;
+    let mut exit = max_distance;
+    for axis in 0..3 {
+        if direction[axis] == 0. {
+            // Parallel to the slab, so the origin has to be between its planes
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return false;
+            }
+        } else {
+            let t1 = (min[axis] - origin[axis]) / direction[axis];
+            let t2 = (max[axis] - origin[axis]) / direction[axis];
+            enter = enter.max(t1.min(t2));
+            exit = exit.min(t1.max(t2));
+            if enter > exit {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::SimParams;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_points(count: usize) -> Vec<Point3D> {
+        let mut rng = StdRng::seed_from_u64(11);
+        (0..count)
+            .map(|index| Point3D {
+                x: rng.gen_range(-400.0..400.0),
+                y: rng.gen_range(-400.0..400.0),
+                z: rng.gen_range(-400.0..400.0),
+                index,
+            })
+            .collect()
+    }
+
+    fn build(points: &[Point3D]) -> Octree {
+        let mut octree = Octree::new(10);
+        for point in points.iter() {
+            octree.insert(*point);
+        }
+        octree
+    }
+
+    #[test]
+    fn pair_list_matches_brute_force() {
+        let smoothing_length = SimParams::default().smoothing_length;
+        let points = random_points(1500);
+        let octree = build(&points);
+
+        let mut brute_force = Vec::new();
+        for a in 0..points.len() {
+            for b in (a + 1)..points.len() {
+                if points[a].position().distance(points[b].position()) < smoothing_length {
+                    brute_force.push((a, b));
+                }
+            }
+        }
+
+        let mut pairs: Vec<(usize, usize)> = octree
+            .nearest_neighbor_list(smoothing_length)
+            .into_iter()
+            .map(|(point, neighbor)| (point.index, neighbor.index))
+            .collect();
+        pairs.sort_unstable();
+
+        assert!(!pairs.is_empty());
+        assert_eq!(pairs, brute_force);
+    }
+
+    fn depth(node: &OctreeNode) -> usize {
+        match node {
+            OctreeNode::Branch(children) => {
+                1 + children
+                    .iter()
+                    .flatten()
+                    .map(|child| depth(child))
+                    .max()
+                    .unwrap_or(0)
+            }
+            OctreeNode::Leaf(_) => 0,
+        }
+    }
+
+    #[test]
+    fn root_grows_to_fit_far_points() {
+        let smoothing_length = SimParams::default().smoothing_length;
+        let mut points = random_points(500);
+        // Points far outside the starting root cube in every direction, like the capsule drop height
+        for (n, position) in [
+            Vec3::new(0., 10000., 0.),
+            Vec3::new(10., 10040., -20.),
+            Vec3::new(-5000., -3000., 7000.),
+            Vec3::new(-4970., -3010., 7010.),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            points.push(Point3D {
+                x: position.x,
+                y: position.y,
+                z: position.z,
+                index: 500 + n,
+            });
+        }
+        let octree = build(&points);
+
+        for point in points.iter() {
+            assert!(octree.contains(point));
+        }
+
+        let mut pairs: Vec<(usize, usize)> = octree
+            .nearest_neighbor_list(smoothing_length)
+            .into_iter()
+            .map(|(point, neighbor)| (point.index, neighbor.index))
+            .collect();
+        pairs.sort_unstable();
+
+        let mut brute_force = Vec::new();
+        for a in 0..points.len() {
+            for b in (a + 1)..points.len() {
+                if points[a].position().distance(points[b].position()) < smoothing_length {
+                    brute_force.push((a, b));
                 }
             }
         }
+
+        assert!(brute_force.contains(&(500, 501)));
+        assert!(brute_force.contains(&(502, 503)));
+        assert_eq!(pairs, brute_force);
+    }
+
+    #[test]
+    fn coincident_points_stay_in_one_leaf() {
+        let points: Vec<Point3D> = (0..200)
+            .map(|index| Point3D {
+                x: 12.5,
+                y: -3.,
+                z: 40.,
+                index,
+            })
+            .collect();
+        let octree = build(&points);
+
+        assert_eq!(depth(&octree.root), 0);
+        assert_eq!(octree.nearest_neighbor_list(1.).len(), 200 * 199 / 2);
+    }
+
+    #[test]
+    fn radius_search_matches_brute_force() {
+        let points = random_points(1500);
+        let octree = build(&points);
+
+        for center in points.iter().step_by(50) {
+            let mut found: Vec<usize> = octree
+                .search(*center, 120.)
+                .iter()
+                .map(|point| point.index)
+                .collect();
+            found.sort_unstable();
+
+            let brute_force: Vec<usize> = points
+                .iter()
+                .filter(|point| point.position().distance(center.position()) < 120.)
+                .map(|point| point.index)
+                .collect();
+
+            assert_eq!(found, brute_force);
+        }
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let points = random_points(1500);
+        let octree = build(&points);
+        let mut rng = StdRng::seed_from_u64(3);
+
+        for k in [1, 5, 40] {
+            for _ in 0..20 {
+                // Some positions outside the points to cover the pruning from far away
+                let position = Vec3::new(
+                    rng.gen_range(-600.0..600.0),
+                    rng.gen_range(-600.0..600.0),
+                    rng.gen_range(-600.0..600.0),
+                );
+                let found: Vec<usize> = octree
+                    .nearest(position, k)
+                    .iter()
+                    .map(|point| point.index)
+                    .collect();
+
+                let mut brute_force: Vec<&Point3D> = points.iter().collect();
+                brute_force.sort_by(|a, b| {
+                    a.position()
+                        .distance(position)
+                        .total_cmp(&b.position().distance(position))
+                });
+                let brute_force: Vec<usize> =
+                    brute_force[..k].iter().map(|point| point.index).collect();
+
+                assert_eq!(found, brute_force);
+            }
+        }
+        assert!(octree.nearest(Vec3::ZERO, 0).is_empty());
+        assert_eq!(octree.nearest(Vec3::ZERO, 2000).len(), points.len());
+    }
+
+    #[test]
+    fn box_search_matches_brute_force() {
+        let points = random_points(1500);
+        let octree = build(&points);
+
+        for (min, max) in [
+            (Vec3::new(-100., -50., 0.), Vec3::new(120., 60., 300.)),
+            (Vec3::new(-500., -500., -500.), Vec3::new(-350., 500., 500.)),
+            (Vec3::splat(450.), Vec3::splat(600.)),
+        ] {
+            let mut found: Vec<usize> = octree
+                .search_box(min, max)
+                .iter()
+                .map(|point| point.index)
+                .collect();
+            found.sort_unstable();
+
+            let brute_force: Vec<usize> = points
+                .iter()
+                .filter(|point| {
+                    point.position().cmpge(min).all() && point.position().cmple(max).all()
+                })
+                .map(|point| point.index)
+                .collect();
+
+            assert_eq!(found, brute_force);
+        }
+    }
+
+    #[test]
+    fn ray_search_matches_brute_force() {
+        let points = random_points(1500);
+        let octree = build(&points);
+
+        for (origin, direction, max_distance) in [
+            (Vec3::new(-800., 0., 0.), Vec3::X, f32::INFINITY),
+            (Vec3::new(0., 0., 900.), Vec3::new(0.3, -0.2, -1.), 1200.),
+            (Vec3::new(100., 700., -50.), Vec3::new(0., -1., 0.), 500.),
+            (
+                Vec3::new(500., 500., 500.),
+                Vec3::new(1., 1., 1.),
+                f32::INFINITY,
+            ),
+        ] {
+            let found = octree.search_ray(origin, direction, max_distance, 30.);
+            assert!(found.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            let mut found: Vec<usize> = found.iter().map(|(point, _)| point.index).collect();
+            found.sort_unstable();
+
+            let unit = direction.normalize();
+            let brute_force: Vec<usize> = points
+                .iter()
+                .filter(|point| {
+                    let distance = (point.position() - origin)
+                        .dot(unit)
+                        .clamp(0., max_distance);
+                    point.position().distance(origin + distance * unit) < 30.
+                })
+                .map(|point| point.index)
+                .collect();
+
+            assert_eq!(found, brute_force);
+        }
+    }
+
+    #[test]
+    fn morton_code_follows_the_octants() {
+        // Descend an octree over a 16 cell grid to the cell of a point, the octant indices on
+        // the way down are the Morton code of the cell
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..200 {
+            let cell = UVec3::new(
+                rng.gen_range(0..16),
+                rng.gen_range(0..16),
+                rng.gen_range(0..16),
+            );
+            let point = Point3D {
+                x: cell.x as f32 + 0.5,
+                y: cell.y as f32 + 0.5,
+                z: cell.z as f32 + 0.5,
+                index: 0,
+            };
+            let mut length = 8.;
+            let mut center = Vec3::splat(8.);
+            let mut code = 0;
+            for _ in 0..4 {
+                let (index, new_center) = octant(&point, length, center);
+                code = code << 3 | index as u64;
+                length /= 2.;
+                center = new_center;
+            }
+            assert_eq!(morton_code(cell), code);
+        }
     }
 }
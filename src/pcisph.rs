@@ -0,0 +1,117 @@
+use glam::Vec3;
+
+use crate::sph::{compression, FluidState, SimParams, SolverSettings, SolverStats};
+
+/*
+ *
+ * Predictive-Corrective Incompressible SPH (PCISPH) Implementation
+ * Solenthaler & Pajarola 2009, https://doi.org/10.1145/1576246.1531346
+ *
+ */
+
+// Sum of the squared kernel gradients of a prototype particle with a filled neighborhood.
+// The neighbors sit on a cubic lattice whose spacing gives the particle the base density.
+fn prototype_gradient_sum(params: &SimParams) -> f32 {
+    let lattice = |spacing: f32| {
+        let cells = (params.smoothing_length / spacing).ceil() as i32;
+        let mut offsets = Vec::new();
+        for x in -cells..=cells {
+            for y in -cells..=cells {
+                for z in -cells..=cells {
+                    offsets.push(spacing * Vec3::new(x as f32, y as f32, z as f32));
+                }
+            }
+        }
+        offsets
+    };
+
+    // Bisection on the lattice spacing, the density only decreases as the spacing grows
+    let mut low = 0.25 * params.smoothing_length;
+    let mut high = params.smoothing_length;
+    for _ in 0..32 {
+        let spacing = 0.5 * (low + high);
+        let density: f32 = (lattice(spacing).into_iter())
+            .map(|offset| params.density_kernel(offset))
+            .sum();
+        if density > params.base_density {
+            low = spacing;
+        } else {
+            high = spacing;
+        }
+    }
+
+    lattice(0.5 * (low + high))
+        .into_iter()
+        .map(|offset| params.pressure_kernel_gradient(offset).length_squared())
+        .sum()
+}
+
+// Pressure acceleration of every particle for the given pressures
+fn pressure_accelerations(fluid: &FluidState, pressures: &[f32]) -> Vec<Vec3> {
+    let mut accelerations = vec![Vec3::ZERO; fluid.positions.len()];
+    for (i, acceleration) in accelerations.iter_mut().enumerate() {
+        for &j in fluid.neighbors[i].iter() {
+            let gradient =
+                (fluid.params).pressure_kernel_gradient(fluid.positions[i] - fluid.positions[j]);
+            let base_density = fluid.params.base_density;
+            *acceleration -=
+                (pressures[i] + pressures[j]) / (base_density * base_density) * gradient;
+        }
+    }
+    accelerations
+}
+
+// Iterate pressure corrections until the predicted density error is below the tolerance.
+// Returns the pressure acceleration and the pressure of every particle.
+pub fn solve(
+    fluid: &FluidState,
+    non_pressure_accelerations: &[Vec3],
+    dt: f32,
+    settings: &SolverSettings,
+    stats: &mut SolverStats,
+) -> (Vec<Vec3>, Vec<f32>) {
+    let count = fluid.positions.len();
+    // Pressure change that removes a unit of density error from the prototype particle
+    let params = &fluid.params;
+    let scaling =
+        params.base_density * params.base_density / (2. * dt * dt * prototype_gradient_sum(params));
+
+    let mut pressures = vec![0.; count];
+    let mut accelerations = vec![Vec3::ZERO; count];
+    let mut predicted_positions = vec![Vec3::ZERO; count];
+    let mut errors = vec![0.; count];
+    let mut iterations = 0;
+    let mut average_error = 0.;
+
+    while iterations < settings.max_iterations {
+        for (i, position) in predicted_positions.iter_mut().enumerate() {
+            let velocity =
+                fluid.velocities[i] + dt * (non_pressure_accelerations[i] + accelerations[i]);
+            *position = fluid.positions[i] + dt * velocity;
+        }
+
+        for (i, error) in errors.iter_mut().enumerate() {
+            let mut predicted_density = params.density_kernel(Vec3::ZERO);
+            for &j in fluid.neighbors[i].iter() {
+                predicted_density +=
+                    params.density_kernel(predicted_positions[i] - predicted_positions[j]);
+            }
+            *error = compression(predicted_density, params);
+        }
+        average_error = errors.iter().sum::<f32>() / (count as f32 * params.base_density);
+
+        if average_error <= settings.max_density_error && iterations >= settings.min_iterations {
+            break;
+        }
+
+        for (pressure, error) in pressures.iter_mut().zip(errors.iter()) {
+            *pressure += scaling * error;
+        }
+        accelerations = pressure_accelerations(fluid, &pressures);
+        iterations += 1;
+    }
+
+    stats.density_iterations = iterations;
+    stats.density_error = average_error;
+    (accelerations, pressures)
+}
@@ -0,0 +1,480 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+
+/*
+ *
+ * Point Clouds
+ * Particle positions, and optionally velocities, read from CSV or PLY files, for starting a run
+ * from a measured free surface shape instead of blocks of fluid.
+ *  - CSV: x,y,z or x,y,z,vx,vy,vz per line. A first line of names picks the columns by name
+ *    instead, so other columns may be present. Empty lines and lines starting with # are skipped.
+ *  - PLY: the x, y and z properties of the vertex element, and vx, vy and vz when present, from
+ *    ASCII or binary files of either byte order. Other properties and elements are skipped.
+ *
+ */
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct PointCloud {
+    pub positions: Vec<Vec3>,
+    // Velocity of every point, zero when the file has none
+    pub velocities: Vec<Vec3>,
+}
+
+// Why a point cloud could not be read, displayed with the file
+#[derive(Debug)]
+pub enum PointCloudError {
+    Io(PathBuf, io::Error),
+    UnknownFormat(PathBuf),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for PointCloudError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointCloudError::Io(path, error) => {
+                write!(f, "could not read point cloud {}: {error}", path.display())
+            }
+            PointCloudError::UnknownFormat(path) => write!(
+                f,
+                "{}: unknown point cloud format, expected a .csv or .ply file",
+                path.display()
+            ),
+            PointCloudError::Invalid(path, message) => write!(f, "{}: {message}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for PointCloudError {}
+
+impl PointCloud {
+    // Read a point cloud file, the extension selects CSV or PLY
+    pub fn load(path: &Path) -> Result<PointCloud, PointCloudError> {
+        let io_error = |error| PointCloudError::Io(path.to_path_buf(), error);
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("csv") => {
+                let text = std::fs::read_to_string(path).map_err(io_error)?;
+                PointCloud::parse_csv(&text, path)
+            }
+            Some("ply") => {
+                let file = File::open(path).map_err(io_error)?;
+                PointCloud::read_ply(&mut BufReader::new(file), path)
+            }
+            _ => Err(PointCloudError::UnknownFormat(path.to_path_buf())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    // Parse CSV text, the path names the file in errors
+    pub fn parse_csv(text: &str, path: &Path) -> Result<PointCloud, PointCloudError> {
+        let invalid = |line: usize, message: String| {
+            PointCloudError::Invalid(path.to_path_buf(), format!("line {line}: {message}"))
+        };
+        let mut lines = (text.lines().enumerate())
+            .map(|(number, line)| (number + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .peekable();
+
+        // Columns of x, y, z and of vx, vy, vz, from the names on the first line if it has any
+        let mut columns = [0, 1, 2];
+        let mut velocity_columns = None;
+        if let Some(&(number, first)) = lines.peek() {
+            let names: Vec<String> = (first.split(','))
+                .map(|name| name.trim().to_ascii_lowercase())
+                .collect();
+            if names.iter().any(|name| name.parse::<f32>().is_err()) {
+                lines.next();
+                let find = |name: &str| names.iter().position(|column| column == name);
+                columns = match [find("x"), find("y"), find("z")] {
+                    [Some(x), Some(y), Some(z)] => [x, y, z],
+                    _ => return Err(invalid(number, "no x, y and z columns".to_string())),
+                };
+                velocity_columns = match [find("vx"), find("vy"), find("vz")] {
+                    [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+                    _ => None,
+                };
+            } else if names.len() >= 6 {
+                velocity_columns = Some([3, 4, 5]);
+            }
+        }
+
+        let mut cloud = PointCloud::default();
+        for (number, line) in lines {
+            let values = (line.split(','))
+                .map(|value| value.trim().parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|error| invalid(number, error.to_string()))?;
+            let vector = |columns: [usize; 3]| {
+                let [x, y, z] = columns.map(|column| values.get(column).copied());
+                match (x, y, z) {
+                    (Some(x), Some(y), Some(z)) => Ok(Vec3::new(x, y, z)),
+                    _ => Err(invalid(
+                        number,
+                        format!("expected {} values", columns.iter().max().unwrap() + 1),
+                    )),
+                }
+            };
+            cloud.positions.push(vector(columns)?);
+            let velocity = velocity_columns.map(vector).transpose()?;
+            cloud.velocities.push(velocity.unwrap_or(Vec3::ZERO));
+        }
+        cloud.check_finite(path)?;
+        Ok(cloud)
+    }
+
+    // Read a PLY file, the path names the file in errors
+    pub fn read_ply(reader: &mut impl BufRead, path: &Path) -> Result<PointCloud, PointCloudError> {
+        let invalid = |message: String| PointCloudError::Invalid(path.to_path_buf(), message);
+        let header = PlyHeader::read(reader).map_err(|error| match error {
+            HeaderError::Io(error) => PointCloudError::Io(path.to_path_buf(), error),
+            HeaderError::Invalid(message) => invalid(message),
+        })?;
+        let ends_early = |error: io::Error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("the file ends early".to_string()),
+            _ => invalid(error.to_string()),
+        };
+
+        let mut values: Box<dyn FnMut(PlyType) -> io::Result<f64>> = match header.format {
+            PlyFormat::Ascii => {
+                let mut text = String::new();
+                reader
+                    .read_to_string(&mut text)
+                    .map_err(|error| PointCloudError::Io(path.to_path_buf(), error))?;
+                let mut tokens = text
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                Box::new(move |_| {
+                    let token = tokens.next().ok_or(io::ErrorKind::UnexpectedEof)?;
+                    token
+                        .parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, token))
+                })
+            }
+            PlyFormat::BinaryLittleEndian => Box::new(|ty| ty.read(reader, false)),
+            PlyFormat::BinaryBigEndian => Box::new(|ty| ty.read(reader, true)),
+        };
+
+        let mut cloud = PointCloud::default();
+        for element in header.elements.iter() {
+            let vertex = element.name == "vertex";
+            let find = |name: &str| (element.properties.iter()).position(|p| p.name == name);
+            let columns = [find("x"), find("y"), find("z")];
+            let velocity_columns = [find("vx"), find("vy"), find("vz")];
+            if vertex && columns.contains(&None) {
+                return Err(invalid("the vertices have no x, y and z".to_string()));
+            }
+
+            for _ in 0..element.count {
+                let mut row = Vec::with_capacity(element.properties.len());
+                for property in element.properties.iter() {
+                    match property.list {
+                        // Lists like the indices of a face are read past
+                        Some(count_type) => {
+                            let count = values(count_type).map_err(ends_early)? as usize;
+                            for _ in 0..count {
+                                values(property.ty).map_err(ends_early)?;
+                            }
+                            row.push(0.);
+                        }
+                        None => row.push(values(property.ty).map_err(ends_early)?),
+                    }
+                }
+                if vertex {
+                    let vector = |columns: [Option<usize>; 3]| {
+                        Vec3::from_array(columns.map(|column| row[column.unwrap()] as f32))
+                    };
+                    cloud.positions.push(vector(columns));
+                    cloud.velocities.push(match velocity_columns {
+                        [Some(_), Some(_), Some(_)] => vector(velocity_columns),
+                        _ => Vec3::ZERO,
+                    });
+                }
+            }
+            // Nothing after the vertices is needed
+            if vertex {
+                break;
+            }
+        }
+        cloud.check_finite(path)?;
+        Ok(cloud)
+    }
+
+    fn check_finite(&self, path: &Path) -> Result<(), PointCloudError> {
+        let point = (self.positions.iter().zip(self.velocities.iter()))
+            .position(|(position, velocity)| !(position.is_finite() && velocity.is_finite()));
+        match point {
+            Some(point) => Err(PointCloudError::Invalid(
+                path.to_path_buf(),
+                format!("point {point} is not finite"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn from_name(name: &str) -> Option<PlyType> {
+        Some(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return None,
+        })
+    }
+
+    fn read(self, reader: &mut impl Read, big_endian: bool) -> io::Result<f64> {
+        macro_rules! read {
+            ($type:ty) => {{
+                let mut bytes = [0; std::mem::size_of::<$type>()];
+                reader.read_exact(&mut bytes)?;
+                if big_endian {
+                    <$type>::from_be_bytes(bytes) as f64
+                } else {
+                    <$type>::from_le_bytes(bytes) as f64
+                }
+            }};
+        }
+        Ok(match self {
+            PlyType::I8 => read!(i8),
+            PlyType::U8 => read!(u8),
+            PlyType::I16 => read!(i16),
+            PlyType::U16 => read!(u16),
+            PlyType::I32 => read!(i32),
+            PlyType::U32 => read!(u32),
+            PlyType::F32 => read!(f32),
+            PlyType::F64 => read!(f64),
+        })
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    ty: PlyType,
+    // Type of the item count of a list property
+    list: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+struct PlyHeader {
+    format: PlyFormat,
+    elements: Vec<PlyElement>,
+}
+
+enum HeaderError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl PlyHeader {
+    fn read(reader: &mut impl BufRead) -> Result<PlyHeader, HeaderError> {
+        let invalid = |message: &str| HeaderError::Invalid(message.to_string());
+        let mut line = String::new();
+        let mut next_line = |line: &mut String| {
+            line.clear();
+            match reader.read_line(line) {
+                Ok(0) => Err(invalid("the header has no end_header")),
+                Ok(_) => Ok(()),
+                Err(error) => Err(HeaderError::Io(error)),
+            }
+        };
+
+        next_line(&mut line)?;
+        if line.trim_end() != "ply" {
+            return Err(invalid("not a PLY file"));
+        }
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = Vec::new();
+        loop {
+            next_line(&mut line)?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["end_header"] => break,
+                ["format", name, _version] => {
+                    format = Some(match *name {
+                        "ascii" => PlyFormat::Ascii,
+                        "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                        "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                        _ => return Err(invalid("unknown PLY format")),
+                    })
+                }
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count
+                        .parse()
+                        .map_err(|_| invalid("invalid element count"))?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count_type, ty, name] => {
+                    let element = elements
+                        .last_mut()
+                        .ok_or(invalid("property before any element"))?;
+                    element.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        ty: PlyType::from_name(ty).ok_or(invalid("unknown property type"))?,
+                        list: Some(
+                            PlyType::from_name(count_type)
+                                .ok_or(invalid("unknown property type"))?,
+                        ),
+                    });
+                }
+                ["property", ty, name] => {
+                    let element = elements
+                        .last_mut()
+                        .ok_or(invalid("property before any element"))?;
+                    element.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        ty: PlyType::from_name(ty).ok_or(invalid("unknown property type"))?,
+                        list: None,
+                    });
+                }
+                ["comment", ..] | ["obj_info", ..] | [] => {}
+                _ => return Err(invalid("unexpected line in the header")),
+            }
+        }
+
+        if !elements.iter().any(|element| element.name == "vertex") {
+            return Err(invalid("no vertex element"));
+        }
+        Ok(PlyHeader {
+            format: format.ok_or(invalid("the header has no format"))?,
+            elements,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(text: &str) -> Result<PointCloud, PointCloudError> {
+        PointCloud::parse_csv(text, Path::new("test.csv"))
+    }
+
+    fn ply(bytes: &[u8]) -> Result<PointCloud, PointCloudError> {
+        PointCloud::read_ply(&mut &bytes[..], Path::new("test.ply"))
+    }
+
+    #[test]
+    fn csv_columns_by_position_or_name() {
+        let cloud = csv("# measured surface\n1, 2, 3\n\n4,5,6\n").unwrap();
+        assert_eq!(
+            cloud.positions,
+            [Vec3::new(1., 2., 3.), Vec3::new(4., 5., 6.)]
+        );
+        assert_eq!(cloud.velocities, [Vec3::ZERO; 2]);
+
+        let cloud = csv("1,2,3,-1,0,0.5\n").unwrap();
+        assert_eq!(cloud.velocities, [Vec3::new(-1., 0., 0.5)]);
+
+        let cloud = csv("id,Z,y,x,vx,vy,vz\n7,3,2,1,10,20,30\n").unwrap();
+        assert_eq!(cloud.positions, [Vec3::new(1., 2., 3.)]);
+        assert_eq!(cloud.velocities, [Vec3::new(10., 20., 30.)]);
+
+        let error = csv("x,y,z\n1,2,3\n1,2\n").unwrap_err().to_string();
+        assert_eq!(error, "test.csv: line 3: expected 3 values");
+        assert!(csv("a,b,c\n1,2,3\n").is_err());
+        assert!(csv("1,2,nan\n").is_err());
+    }
+
+    #[test]
+    fn ascii_ply_vertices() {
+        let text = "ply\nformat ascii 1.0\ncomment from a scanner\nelement vertex 2\n\
+            property float x\nproperty float y\nproperty float z\nproperty uchar red\n\
+            property float vx\nproperty float vy\nproperty float vz\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            1 2 3 255 0 0 -1\n4 5 6 0 1 1 1\n3 0 1 1\n";
+        let cloud = ply(text.as_bytes()).unwrap();
+        assert_eq!(
+            cloud.positions,
+            [Vec3::new(1., 2., 3.), Vec3::new(4., 5., 6.)]
+        );
+        assert_eq!(cloud.velocities, [Vec3::new(0., 0., -1.), Vec3::ONE]);
+    }
+
+    #[test]
+    fn binary_ply_in_either_byte_order() {
+        for (format, big_endian) in [("binary_little_endian", false), ("binary_big_endian", true)] {
+            // A list element before the vertices, which is read past
+            let mut bytes = format!(
+                "ply\nformat {format} 1.0\nelement camera 1\nproperty list uchar float view\n\
+                element vertex 2\nproperty double x\nproperty float y\nproperty short z\n\
+                end_header\n"
+            )
+            .into_bytes();
+            let mut push = |value: &[u8], mut reversed: Vec<u8>| {
+                if big_endian {
+                    reversed.reverse();
+                    bytes.extend(reversed);
+                } else {
+                    bytes.extend(value);
+                }
+            };
+            push(&[2], vec![2]);
+            for value in [0.5f32, 1.5] {
+                push(&value.to_le_bytes(), value.to_le_bytes().to_vec());
+            }
+            for (x, y, z) in [(1.25f64, -2f32, 3i16), (4., 5., -6)] {
+                push(&x.to_le_bytes(), x.to_le_bytes().to_vec());
+                push(&y.to_le_bytes(), y.to_le_bytes().to_vec());
+                push(&z.to_le_bytes(), z.to_le_bytes().to_vec());
+            }
+
+            let cloud = ply(&bytes).unwrap();
+            assert_eq!(
+                cloud.positions,
+                [Vec3::new(1.25, -2., 3.), Vec3::new(4., 5., -6.)]
+            );
+
+            let error = ply(&bytes[..bytes.len() - 1]).unwrap_err().to_string();
+            assert_eq!(error, "test.ply: the file ends early");
+        }
+
+        let error = ply(b"ply\nformat ascii 1.0\nelement face 0\nend_header\n");
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "test.ply: no vertex element"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use glam::Vec3;
+
+use crate::csv_log;
+use crate::neighbors::SpatialHash;
+use crate::sph::{ParticleSet, SimParams};
+
+/*
+ *
+ * Probes
+ * Fixed points in the fluid where the pressure, density and velocity are sampled every step, to
+ * compare with the pressure sensors of a laboratory experiment. The values are SPH interpolated
+ * with the density kernel the solver uses: the density is the kernel sum of the particle masses
+ * around the probe, the pressure and velocity the sums of the particle values weighted by their
+ * volume m / density. A probe out of the fluid reads zero.
+ * The time series are written as CSV, one row per probe and step.
+ *
+ */
+
+const CSV_HEADER: &str = "probe,x,y,z,step,time,density,pressure,vx,vy,vz";
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ProbeSample {
+    pub step: u64,
+    pub time: f64,
+    pub density: f32,
+    pub pressure: f32,
+    pub velocity: Vec3,
+}
+
+// Time series sampled at a point
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ProbeSeries {
+    pub name: String,
+    pub position: Vec3,
+    pub samples: Vec<ProbeSample>,
+}
+
+impl ProbeSeries {
+    pub fn new(name: impl Into<String>, position: Vec3) -> Self {
+        ProbeSeries {
+            name: name.into(),
+            position,
+            samples: Vec::new(),
+        }
+    }
+
+    fn write_row(&self, writer: &mut impl Write, sample: &ProbeSample) -> io::Result<()> {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            self.name,
+            self.position.x,
+            self.position.y,
+            self.position.z,
+            sample.step,
+            sample.time,
+            sample.density,
+            sample.pressure,
+            sample.velocity.x,
+            sample.velocity.y,
+            sample.velocity.z
+        )
+    }
+}
+
+// Interpolate the density, pressure and velocity at a point, the hash holds the particle
+// positions in cells of the smoothing length
+pub fn interpolate(
+    particles: &ParticleSet,
+    hash: &SpatialHash,
+    params: &SimParams,
+    point: Vec3,
+    step: u64,
+    time: f64,
+) -> ProbeSample {
+    let mut sample = ProbeSample {
+        step,
+        time,
+        ..Default::default()
+    };
+    for index in hash.near(point) {
+        let weight = params.density_kernel(point - particles.positions[index]);
+        sample.density += weight;
+        // Particles added since the last step have no density yet
+        let density = particles.densities[index];
+        if density > 0. {
+            sample.pressure += weight / density * particles.pressures[index];
+            sample.velocity += weight / density * particles.velocities[index];
+        }
+    }
+    sample
+}
+
+// Add a sample at the current step to every probe
+pub fn sample_probes(
+    probes: &mut [ProbeSeries],
+    particles: &ParticleSet,
+    params: &SimParams,
+    step: u64,
+    time: f64,
+) {
+    if probes.is_empty() {
+        return;
+    }
+    let hash = SpatialHash::new(&particles.positions, params.smoothing_length);
+    for probe in probes.iter_mut() {
+        let sample = interpolate(particles, &hash, params, probe.position, step, time);
+        probe.samples.push(sample);
+    }
+}
+
+// Write every sample of the probes
+pub fn write_csv(path: &Path, probes: &[ProbeSeries]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{CSV_HEADER}")?;
+    for probe in probes.iter() {
+        for sample in probe.samples.iter() {
+            probe.write_row(&mut writer, sample)?;
+        }
+    }
+    writer.flush()
+}
+
+// CSV time series written as the run goes, for runs too long to keep every sample
+pub struct ProbeLog {
+    writer: BufWriter<File>,
+}
+
+impl ProbeLog {
+    // Open the CSV of the probe samples, with the same columns as write_csv()
+    pub fn create(path: &Path, append: bool) -> io::Result<ProbeLog> {
+        let writer = csv_log::open(path, CSV_HEADER, append)?;
+        Ok(ProbeLog { writer })
+    }
+
+    // Write the samples of the probes and take them out
+    pub fn write(&mut self, probes: &mut [ProbeSeries]) -> io::Result<()> {
+        for probe in probes.iter_mut() {
+            for sample in probe.samples.iter() {
+                probe.write_row(&mut self.writer, sample)?;
+            }
+            probe.samples.clear();
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::FluidBlock;
+    use crate::sph::{step, SolverSettings};
+
+    #[test]
+    fn probes_read_the_fluid_they_are_in() {
+        let params = SimParams::default();
+        let settings = SolverSettings::default();
+        let block = FluidBlock {
+            min: Vec3::new(-300., -400., -300.),
+            max: Vec3::new(300., 0., 300.),
+            velocity: Vec3::new(10., 0., 0.),
+            ..Default::default()
+        };
+        let mut particles = ParticleSet::default();
+        for position in block.positions() {
+            particles.push(position, block.velocity);
+        }
+        step(&mut particles, &settings, &params, 0.001);
+
+        let mut probes = [
+            ProbeSeries::new("inside", Vec3::new(0., -200., 0.)),
+            ProbeSeries::new("above", Vec3::new(0., 300., 0.)),
+        ];
+        sample_probes(&mut probes, &particles, &params, 1, 0.001);
+        let inside = probes[0].samples[0];
+        let nearest = (0..particles.len())
+            .min_by(|&a, &b| {
+                (particles.positions[a].distance(probes[0].position))
+                    .total_cmp(&particles.positions[b].distance(probes[0].position))
+            })
+            .unwrap();
+        let density = particles.densities[nearest];
+        assert!((inside.density - density).abs() < 0.1 * density);
+        assert!((inside.velocity.x - 10.).abs() < 1.);
+        assert!(inside.pressure.is_finite());
+        assert_eq!(
+            probes[1].samples[0],
+            ProbeSample {
+                step: 1,
+                time: 0.001,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn logged_samples_are_taken_out() {
+        let path = std::env::temp_dir().join(format!("probes_{}.csv", std::process::id()));
+        let mut probes = [ProbeSeries::new("gauge", Vec3::new(1., 2., 3.))];
+        probes[0].samples.push(ProbeSample {
+            step: 5,
+            time: 0.25,
+            density: 2.,
+            pressure: -1.,
+            velocity: Vec3::X,
+        });
+        let mut log = ProbeLog::create(&path, false).unwrap();
+        log.write(&mut probes).unwrap();
+        assert!(probes[0].samples.is_empty());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            text,
+            format!("{CSV_HEADER}\ngauge,1,2,3,5,0.25,2,-1,1,0,0\n")
+        );
+    }
+}
@@ -0,0 +1,661 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::point_cloud::{PointCloud, PointCloudError};
+use crate::sph::{ParticleSet, SimParams, SolverSettings, SIZE_X, SIZE_Y, SIZE_Z};
+
+/*
+ *
+ * Scene Files
+ * An experiment described as data: the domain, the blocks of fluid and the point clouds it
+ * starts with, the emitters that add particles over time, the rigid bodies, the static
+ * obstacles, the probes that sample the fluid, the solver settings and the physical parameters.
+ * Scenes are read from .ron or .json files. Every field has a default, so a scene only lists
+ * what it changes, and misspelled fields are rejected.
+ *
+ */
+
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scene {
+    pub domain: Domain,
+    pub fluid_blocks: Vec<FluidBlock>,
+    pub point_clouds: Vec<PointCloudSource>,
+    pub emitters: Vec<Emitter>,
+    pub rigid_bodies: Vec<RigidBody>,
+    pub obstacles: Vec<Obstacle>,
+    pub probes: Vec<ProbePoint>,
+    pub solver: SolverSettings,
+    pub params: SimParams,
+}
+
+// Box the fluid is kept in, centered on the origin
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Domain {
+    pub size: Vec3,
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Domain {
+            size: Vec3::new(SIZE_X, SIZE_Y, SIZE_Z),
+        }
+    }
+}
+
+// Box filled with particles on a cubic lattice when the scene starts
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FluidBlock {
+    pub min: Vec3,
+    pub max: Vec3,
+    // Distance between the particles, 58 is about the rest density
+    pub spacing: f32,
+    pub velocity: Vec3,
+}
+
+impl Default for FluidBlock {
+    fn default() -> Self {
+        FluidBlock {
+            min: Vec3::new(-SIZE_X / 2., -SIZE_Y / 2., -SIZE_Z / 2.),
+            max: Vec3::new(0., 0., SIZE_Z / 2.),
+            spacing: 58.,
+            velocity: Vec3::ZERO,
+        }
+    }
+}
+
+impl FluidBlock {
+    // Lattice positions inside the block, half a spacing from its faces
+    pub fn positions(&self) -> Vec<Vec3> {
+        let counts = ((self.max - self.min) / self.spacing).floor().as_uvec3();
+        let mut positions = Vec::new();
+        for x in 0..counts.x {
+            for y in 0..counts.y {
+                for z in 0..counts.z {
+                    let cell = Vec3::new(x as f32, y as f32, z as f32) + 0.5;
+                    positions.push(self.min + self.spacing * cell);
+                }
+            }
+        }
+        positions
+    }
+}
+
+// Adds a row of particles rate times per second, like holding the left mouse button
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Emitter {
+    // Position of the first particle of a row, and the offset from one particle to the next
+    pub position: Vec3,
+    pub offset: Vec3,
+    pub count: usize,
+    pub velocity: Vec3,
+    // Largest random velocity added along x and z, so the particles do not stack up
+    pub jitter: f32,
+    // Rows per second
+    pub rate: f32,
+    // Seconds after the scene starts of the first row
+    pub start: f32,
+    // Number of rows, the emitter never stops when it is None
+    pub rows: Option<usize>,
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Emitter {
+            position: Vec3::new(-SIZE_X / 2. + 40., SIZE_Y / 2., 0.),
+            offset: Vec3::new(40., 0., 0.),
+            count: 7,
+            velocity: Vec3::new(0., -400., 0.),
+            jitter: 0.2,
+            rate: 5.,
+            start: 0.,
+            rows: None,
+        }
+    }
+}
+
+impl Emitter {
+    // Number of rows added by the given time since the scene started
+    pub fn rows_due(&self, time: f32) -> usize {
+        if time < self.start {
+            return 0;
+        }
+        let rows = ((time - self.start) * self.rate).floor() as usize + 1;
+        self.rows.map_or(rows, |limit| rows.min(limit))
+    }
+
+    // Position and velocity of every particle of a row. The jitter is seeded with the row so a
+    // scene plays out the same way every time.
+    pub fn row(&self, row: usize) -> Vec<(Vec3, Vec3)> {
+        let mut rng = StdRng::seed_from_u64(row as u64);
+        (0..self.count)
+            .map(|i| {
+                let jitter = self.jitter * Vec3::new(rng.gen(), 0., rng.gen());
+                (
+                    self.position + i as f32 * self.offset,
+                    self.velocity + jitter,
+                )
+            })
+            .collect()
+    }
+}
+
+// Rows every emitter of a scene added so far, so a row is only added once
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EmitterState {
+    pub emitted_rows: Vec<usize>,
+}
+
+impl EmitterState {
+    // Nothing added yet, when the scene starts
+    pub fn new(scene: &Scene) -> Self {
+        EmitterState {
+            emitted_rows: vec![0; scene.emitters.len()],
+        }
+    }
+
+    // Carry the scene on from a checkpoint taken the given seconds after it started, the rows
+    // due by then are in the checkpoint already
+    pub fn resume_at(scene: &Scene, time: f64) -> Self {
+        EmitterState {
+            emitted_rows: (scene.emitters.iter())
+                .map(|emitter| emitter.rows_due(time as f32))
+                .collect(),
+        }
+    }
+
+    // Add the rows due by the given seconds after the scene started and return the identifiers
+    // of their particles
+    pub fn emit_due(
+        &mut self,
+        scene: &Scene,
+        time: f64,
+        particles: &mut ParticleSet,
+    ) -> Vec<usize> {
+        let mut ids = Vec::new();
+        for (emitter, emitted_rows) in scene.emitters.iter().zip(self.emitted_rows.iter_mut()) {
+            while *emitted_rows < emitter.rows_due(time as f32) {
+                for (position, velocity) in emitter.row(*emitted_rows) {
+                    ids.push(particles.push(position, velocity));
+                }
+                *emitted_rows += 1;
+            }
+        }
+        ids
+    }
+}
+
+// Particles read from a CSV or PLY file, the path is relative to the scene file. The points are
+// scaled, then moved by the offset, and the velocity is added to the one in the file.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PointCloudSource {
+    pub path: PathBuf,
+    pub offset: Vec3,
+    pub scale: f32,
+    pub velocity: Vec3,
+    // Read by Scene::load
+    #[serde(skip)]
+    pub points: PointCloud,
+}
+
+impl Default for PointCloudSource {
+    fn default() -> Self {
+        PointCloudSource {
+            path: PathBuf::new(),
+            offset: Vec3::ZERO,
+            scale: 1.,
+            velocity: Vec3::ZERO,
+            points: PointCloud::default(),
+        }
+    }
+}
+
+impl PointCloudSource {
+    // Positions and velocities of the particles, in the scene
+    pub fn particles(&self) -> impl Iterator<Item = (Vec3, Vec3)> + '_ {
+        (self.points.positions.iter())
+            .zip(self.points.velocities.iter())
+            .map(|(&position, &velocity)| {
+                (
+                    position * self.scale + self.offset,
+                    velocity + self.velocity,
+                )
+            })
+    }
+}
+
+// Body moved by the fluid, like the Orion capsule dropped with the spacebar
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RigidBody {
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+// Static box the particles bounce off like the walls of the domain
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Obstacle {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+// Point where the pressure, density and velocity are sampled every step, named after the sensor
+// it stands for
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbePoint {
+    pub name: String,
+    pub position: Vec3,
+}
+
+impl Obstacle {
+    // Move a particle inside the box out through the closest face and reflect its velocity
+    pub fn collide(&self, position: &mut Vec3, velocity: &mut Vec3, coef_rest: f32) {
+        if !(position.cmpgt(self.min).all() && position.cmplt(self.max).all()) {
+            return;
+        }
+        let to_min = *position - self.min;
+        let to_max = self.max - *position;
+        let axis = (0..3)
+            .min_by(|&a, &b| {
+                to_min[a]
+                    .min(to_max[a])
+                    .total_cmp(&to_min[b].min(to_max[b]))
+            })
+            .unwrap();
+        if to_min[axis] < to_max[axis] {
+            position[axis] = self.min[axis];
+            velocity[axis] = -velocity[axis].abs() * coef_rest;
+        } else {
+            position[axis] = self.max[axis];
+            velocity[axis] = velocity[axis].abs() * coef_rest;
+        }
+    }
+}
+
+// Why a scene file could not be loaded, displayed with the file and the offending field
+#[derive(Debug)]
+pub enum SceneError {
+    Read(PathBuf, std::io::Error),
+    UnknownFormat(PathBuf),
+    Ron(PathBuf, ron::error::SpannedError),
+    Json(PathBuf, serde_json::Error),
+    PointCloud(PointCloudError),
+    Invalid {
+        path: PathBuf,
+        field: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Read(path, error) => {
+                write!(f, "could not read scene {}: {error}", path.display())
+            }
+            SceneError::UnknownFormat(path) => write!(
+                f,
+                "{}: unknown scene format, expected a .ron or .json file",
+                path.display()
+            ),
+            SceneError::Ron(path, error) => write!(f, "{}:{error}", path.display()),
+            SceneError::Json(path, error) => write!(f, "{}: {error}", path.display()),
+            SceneError::PointCloud(error) => write!(f, "{error}"),
+            SceneError::Invalid {
+                path,
+                field,
+                message,
+            } => write!(f, "{}: {field} {message}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl Scene {
+    // Read a scene file and the point clouds it lists, the extension selects RON or JSON
+    pub fn load(path: &Path) -> Result<Scene, SceneError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| SceneError::Read(path.to_path_buf(), error))?;
+        let mut scene = Scene::parse(&text, path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for source in scene.point_clouds.iter_mut() {
+            source.points =
+                PointCloud::load(&directory.join(&source.path)).map_err(SceneError::PointCloud)?;
+        }
+        Ok(scene)
+    }
+
+    // Parse the text of a scene file, the path selects the format and names the file in errors
+    pub fn parse(text: &str, path: &Path) -> Result<Scene, SceneError> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let mut scene: Scene = match extension {
+            Some("ron") => {
+                ron::from_str(text).map_err(|error| SceneError::Ron(path.to_path_buf(), error))?
+            }
+            Some("json") => serde_json::from_str(text)
+                .map_err(|error| SceneError::Json(path.to_path_buf(), error))?,
+            _ => return Err(SceneError::UnknownFormat(path.to_path_buf())),
+        };
+
+        scene
+            .validate()
+            .map_err(|(field, message)| SceneError::Invalid {
+                path: path.to_path_buf(),
+                field,
+                message,
+            })?;
+        scene.params.box_size = scene.domain.size;
+        scene.params.update_kernels();
+        Ok(scene)
+    }
+
+    // Add the particles of the fluid blocks and point clouds and return their identifiers
+    pub fn fill(&self, particles: &mut ParticleSet) -> Vec<usize> {
+        let mut ids = Vec::new();
+        for block in self.fluid_blocks.iter() {
+            for position in block.positions() {
+                ids.push(particles.push(position, block.velocity));
+            }
+        }
+        for source in self.point_clouds.iter() {
+            for (position, velocity) in source.particles() {
+                ids.push(particles.push(position, velocity));
+            }
+        }
+        ids
+    }
+
+    // Keep every particle out of the obstacles, after every step
+    pub fn collide_obstacles(&self, particles: &mut ParticleSet, coef_rest: f32) {
+        if self.obstacles.is_empty() {
+            return;
+        }
+        for (position, velocity) in particles
+            .positions
+            .iter_mut()
+            .zip(particles.velocities.iter_mut())
+        {
+            for obstacle in self.obstacles.iter() {
+                obstacle.collide(position, velocity, coef_rest);
+            }
+        }
+    }
+
+    // Check the values serde can not, returns the path of the first invalid field and why
+    fn validate(&self) -> Result<(), (String, String)> {
+        let check = |valid: bool, field: String, message: &str| {
+            if valid {
+                Ok(())
+            } else {
+                Err((field, message.to_string()))
+            }
+        };
+        let positive = |value: f32, field: String| check(value > 0., field, "must be positive");
+        let finite = |value: Vec3, field: String| check(value.is_finite(), field, "must be finite");
+        let above = |min: Vec3, max: Vec3, field: String| {
+            check(
+                max.cmpgt(min).all(),
+                field,
+                "must be greater than min on every axis",
+            )
+        };
+
+        check(
+            self.domain.size.cmpgt(Vec3::ZERO).all() && self.domain.size.is_finite(),
+            "domain.size".to_string(),
+            "must be positive on every axis",
+        )?;
+
+        for (i, block) in self.fluid_blocks.iter().enumerate() {
+            finite(block.min, format!("fluid_blocks[{i}].min"))?;
+            above(block.min, block.max, format!("fluid_blocks[{i}].max"))?;
+            positive(block.spacing, format!("fluid_blocks[{i}].spacing"))?;
+            finite(block.velocity, format!("fluid_blocks[{i}].velocity"))?;
+        }
+
+        for (i, source) in self.point_clouds.iter().enumerate() {
+            check(
+                source.path != Path::new(""),
+                format!("point_clouds[{i}].path"),
+                "must name a file",
+            )?;
+            finite(source.offset, format!("point_clouds[{i}].offset"))?;
+            positive(source.scale, format!("point_clouds[{i}].scale"))?;
+            finite(source.velocity, format!("point_clouds[{i}].velocity"))?;
+        }
+
+        for (i, emitter) in self.emitters.iter().enumerate() {
+            finite(emitter.position, format!("emitters[{i}].position"))?;
+            check(
+                emitter.count > 0,
+                format!("emitters[{i}].count"),
+                "must be at least 1",
+            )?;
+            positive(emitter.rate, format!("emitters[{i}].rate"))?;
+            check(
+                emitter.start >= 0.,
+                format!("emitters[{i}].start"),
+                "must not be negative",
+            )?;
+        }
+
+        for (i, body) in self.rigid_bodies.iter().enumerate() {
+            finite(body.position, format!("rigid_bodies[{i}].position"))?;
+            finite(body.velocity, format!("rigid_bodies[{i}].velocity"))?;
+        }
+
+        for (i, obstacle) in self.obstacles.iter().enumerate() {
+            finite(obstacle.min, format!("obstacles[{i}].min"))?;
+            above(obstacle.min, obstacle.max, format!("obstacles[{i}].max"))?;
+        }
+
+        for (i, probe) in self.probes.iter().enumerate() {
+            check(
+                !probe.name.is_empty() && !probe.name.contains([',', '\n']),
+                format!("probes[{i}].name"),
+                "must be a name without commas",
+            )?;
+            finite(probe.position, format!("probes[{i}].position"))?;
+        }
+
+        let solver = &self.solver;
+        check(
+            solver.max_iterations >= solver.min_iterations,
+            "solver.max_iterations".to_string(),
+            "must be at least solver.min_iterations",
+        )?;
+        positive(
+            solver.max_density_error,
+            "solver.max_density_error".to_string(),
+        )?;
+        positive(
+            solver.max_divergence_error,
+            "solver.max_divergence_error".to_string(),
+        )?;
+        check(
+            solver.neighbor_skin >= 0.,
+            "solver.neighbor_skin".to_string(),
+            "must not be negative",
+        )?;
+
+        let params = &self.params;
+        positive(params.particle_mass, "params.particle_mass".to_string())?;
+        positive(params.base_density, "params.base_density".to_string())?;
+        positive(
+            params.smoothing_length,
+            "params.smoothing_length".to_string(),
+        )?;
+        positive(
+            params.isotropic_exponent,
+            "params.isotropic_exponent".to_string(),
+        )?;
+        check(
+            params.dynamic_viscosity >= 0.,
+            "params.dynamic_viscosity".to_string(),
+            "must not be negative",
+        )?;
+        check(
+            (0.0..=1.0).contains(&params.coef_rest),
+            "params.coef_rest".to_string(),
+            "must be between 0 and 1",
+        )?;
+        positive(params.body_mass, "params.body_mass".to_string())?;
+        positive(params.body_size, "params.body_size".to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::{EquationOfState, SolverMode};
+
+    #[test]
+    fn example_scenes_load() {
+        let dam_break = Scene::parse(
+            include_str!("../scenes/dam_break.ron"),
+            Path::new("dam_break.ron"),
+        )
+        .unwrap();
+        assert_eq!(dam_break.solver.mode, SolverMode::Dfsph);
+        assert_eq!(dam_break.rigid_bodies.len(), 1);
+        assert_eq!(dam_break.probes[1].name, "wall");
+        assert_eq!(dam_break.params.box_size, dam_break.domain.size);
+        let mut particles = ParticleSet::default();
+        assert_eq!(dam_break.fill(&mut particles).len(), 8 * 10 * 13);
+
+        let emitters = Scene::parse(
+            include_str!("../scenes/emitters.json"),
+            Path::new("emitters.json"),
+        )
+        .unwrap();
+        assert_eq!(emitters.emitters[1].start, 2.);
+        assert!(matches!(
+            emitters.solver.equation_of_state,
+            EquationOfState::Tait(_)
+        ));
+        assert_eq!(emitters.params.dynamic_viscosity, 4.);
+        assert_eq!(emitters.params.gravity, SimParams::default().gravity);
+    }
+
+    #[test]
+    fn point_clouds_load_next_to_the_scene() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/droplet.ron");
+        let droplet = Scene::load(&path).unwrap();
+        let source = &droplet.point_clouds[0];
+        assert_eq!(source.points.len(), 179);
+        let mut particles = ParticleSet::default();
+        let ids = droplet.fill(&mut particles);
+        let pool = droplet.fluid_blocks[0].positions().len();
+        assert_eq!(ids.len(), pool + 179);
+        assert_eq!(particles.positions[pool], Vec3::new(-174., 42., -58.));
+        assert_eq!(particles.velocities[pool], Vec3::new(0., -300., 0.));
+
+        let error = Scene::parse("(point_clouds: [(path: \"drop.csv\", scale: 0)])", &path);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            format!("{}: point_clouds[0].scale must be positive", path.display())
+        );
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let path = Path::new("scene.ron");
+        let error = Scene::parse("(fluid_blocks: [(spacng: 50)])", path).unwrap_err();
+        assert!(matches!(error, SceneError::Ron(..)));
+        let message = error.to_string();
+        assert!(message.starts_with("scene.ron:1:"), "{message}");
+        assert!(message.contains("spacng"), "{message}");
+
+        let error = Scene::parse("(fluid_blocks: [(), (spacing: -5)])", path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "scene.ron: fluid_blocks[1].spacing must be positive"
+        );
+
+        let error = Scene::parse("(probes: [(position: (0, 0, 0))])", path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "scene.ron: probes[0].name must be a name without commas"
+        );
+
+        let path = Path::new("scene.json");
+        let error = Scene::parse(r#"{"solver": {"mode": "Sph"}}"#, path).unwrap_err();
+        let message = error.to_string();
+        assert!(
+            message.contains("Sph") && message.contains("line 1"),
+            "{message}"
+        );
+
+        let error = Scene::parse(r#"{"params": {"coef_rest": 2}}"#, path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "scene.json: params.coef_rest must be between 0 and 1"
+        );
+    }
+
+    #[test]
+    fn emitter_rows_follow_the_rate() {
+        let emitter = Emitter {
+            rate: 4.,
+            start: 1.,
+            rows: Some(6),
+            ..Default::default()
+        };
+        assert_eq!(emitter.rows_due(0.5), 0);
+        assert_eq!(emitter.rows_due(1.), 1);
+        assert_eq!(emitter.rows_due(1.6), 3);
+        assert_eq!(emitter.rows_due(10.), 6);
+        assert_eq!(emitter.row(3), emitter.row(3));
+        assert_eq!(emitter.row(3).len(), emitter.count);
+    }
+
+    #[test]
+    fn emitters_add_each_row_once() {
+        let scene = Scene {
+            emitters: vec![Emitter {
+                rate: 4.,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let count = scene.emitters[0].count;
+        let mut particles = ParticleSet::default();
+        let mut emitters = EmitterState::new(&scene);
+        assert_eq!(
+            emitters.emit_due(&scene, 0., &mut particles),
+            (0..count).collect::<Vec<_>>()
+        );
+        assert!(emitters.emit_due(&scene, 0.1, &mut particles).is_empty());
+        assert_eq!(
+            emitters.emit_due(&scene, 0.6, &mut particles).len(),
+            2 * count
+        );
+        assert_eq!(emitters, EmitterState::resume_at(&scene, 0.6));
+        assert_eq!(particles.positions[count], scene.emitters[0].row(1)[0].0);
+    }
+
+    #[test]
+    fn obstacles_push_particles_out() {
+        let obstacle = Obstacle {
+            min: Vec3::splat(-100.),
+            max: Vec3::splat(100.),
+        };
+        let mut position = Vec3::new(10., 90., 0.);
+        let mut velocity = Vec3::new(0., -50., 0.);
+        obstacle.collide(&mut position, &mut velocity, 0.5);
+        assert_eq!(position, Vec3::new(10., 100., 0.));
+        assert_eq!(velocity, Vec3::new(0., 25., 0.));
+    }
+}
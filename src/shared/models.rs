@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use bevy::prelude::Vec3;
 use transvoxel::density::ScalarField;
 
+use particles::sph::SimParams;
+
 #[derive(PartialEq, Debug, Copy, Clone, Hash, Eq)]
 pub enum Model {
     NewModel, // not sure which shape yet
@@ -19,31 +21,16 @@ pub fn models_map() -> HashMap<Model, Box<dyn ScalarField<f32, f32>>> {
             r: 100f32,
         }),
     );
-    return fields;
+    fields
 }
 
 pub const THRESHOLD: f32 = 0.;
 
 pub struct ParticleModel {
     pub positions: Vec<Vec3>,
+    pub params: SimParams,
 }
 
-const PARTICLE_MASS: f32 = 50.;
-const SMOOTHING_LENGTH: f32 = 80.;
-
-const NORMALIZATION_DENSITY: f32 = (315. * PARTICLE_MASS)
-    / (64.
-        * core::f32::consts::PI
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH);
-
 impl ScalarField<f32, f32> for ParticleModel {
     fn get_density(&self, x: f32, y: f32, z: f32) -> f32 {
         let mut point_density: f32 = 0.;
@@ -51,16 +38,9 @@ impl ScalarField<f32, f32> for ParticleModel {
             let grid_position = Vec3::new(x, y, z);
 
             let distance_between = grid_position - *particle_position;
-            //this is the distance
-            let length = distance_between.length();
-
-            if length < SMOOTHING_LENGTH {
-                let density =
-                    NORMALIZATION_DENSITY * (SMOOTHING_LENGTH.powf(2.) - length.powf(2.)).powf(3.);
-                point_density += density;
-            }
+            point_density += self.params.density_kernel(distance_between);
         }
-        return point_density;
+        point_density
     }
 }
 
@@ -77,7 +57,6 @@ impl ScalarField<f32, f32> for NewModel {
             //+ (y - self.cy) * (y - self.cy)
             + (z - self.cz).powi(2))
         .sqrt();
-        let d = 1f32 - distance_from_center / self.r;
-        d
+        1f32 - distance_from_center / self.r
     }
 }
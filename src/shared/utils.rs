@@ -2,6 +2,7 @@ use crate::models;
 use crate::models::ParticleModel;
 use bevy::prelude::Vec3;
 use bevy::render::mesh::Mesh as BevyMesh;
+use particles::sph::SimParams;
 use transvoxel::shrink_if_needed;
 use transvoxel::transition_sides::*;
 use transvoxel::{
@@ -13,7 +14,7 @@ use transvoxel::{
     voxel_source::WorldMappingVoxelSource,
 };
 
-fn to_bevy(mesh: OurMesh<f32>, wireframe: bool) -> BevyMesh {
+pub fn to_bevy(mesh: OurMesh<f32>, wireframe: bool) -> BevyMesh {
     if wireframe {
         bevy_mesh::to_bevy_wireframe(mesh)
     } else {
@@ -21,15 +22,19 @@ fn to_bevy(mesh: OurMesh<f32>, wireframe: bool) -> BevyMesh {
     }
 }
 
-pub fn mesh_for_model(
+// The extracted surface, to_bevy() turns it into the mesh the app renders
+pub fn surface_for_model(
     positions: Vec<Vec3>,
-    wireframe: bool,
+    params: &SimParams,
     block: &Block<f32>,
     transition_sides: &TransitionSides,
-) -> BevyMesh {
-    let mut field = ParticleModel { positions };
+) -> OurMesh<f32> {
+    let mut field = ParticleModel {
+        positions,
+        params: *params,
+    };
 
-    field_model(&mut field, wireframe, block, transition_sides)
+    field_model(&mut field, block, transition_sides)
 }
 
 pub fn inside_grid_points(
@@ -44,16 +49,11 @@ pub fn inside_grid_points(
 
 fn field_model(
     field: &mut dyn ScalarField<f32, f32>,
-    wireframe: bool,
     block: &Block<f32>,
     transition_sides: &TransitionSides,
-) -> BevyMesh {
-    let mut source = WorldMappingVoxelSource {
-        field: field,
-        block: &block,
-    };
-    let mesh = extract(&mut source, &block, models::THRESHOLD, *transition_sides);
-    to_bevy(mesh, wireframe)
+) -> OurMesh<f32> {
+    let mut source = WorldMappingVoxelSource { field, block };
+    extract(&mut source, block, models::THRESHOLD, *transition_sides)
 }
 
 fn inside_grid_points_for_field(
@@ -92,7 +92,7 @@ fn inside_grid_points_for_field(
             }
         }
     }
-    return result;
+    result
 }
 
 pub fn grid_lines(block: &Block<f32>, transition_sides: &TransitionSides) -> BevyMesh {
@@ -207,7 +207,7 @@ pub fn grid_lines(block: &Block<f32>, transition_sides: &TransitionSides) -> Bev
     bevy_mesh.insert_attribute(BevyMesh::ATTRIBUTE_POSITION, positions);
     bevy_mesh.insert_attribute(BevyMesh::ATTRIBUTE_NORMAL, normals);
     bevy_mesh.insert_attribute(BevyMesh::ATTRIBUTE_UV_0, uvs);
-    return bevy_mesh;
+    bevy_mesh
 }
 
 fn high_res_face_grid_point_position(
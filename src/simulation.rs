@@ -0,0 +1,194 @@
+use bevy::prelude::*;
+use bevy::render::mesh::Mesh as BevyMesh;
+use bevy_mod_raycast::{ray_intersection_over_mesh, Backfaces, Ray3d};
+
+use particles::checkpoint::BodyState;
+use particles::conservation::Conservation;
+use particles::sph::{step, ParticleSet, SimParams, SolverMode, SolverSettings, SolverStats};
+
+/*
+ *
+ * Bevy Frontend of the Solver Core
+ * The particles live in the Fluid resource, the windowed app renders them with one entity per
+ * particle. The capsule bodies are not part of the fluid and are moved here. The windowed app
+ * and the headless runner both run these systems.
+ *
+ */
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SimulationSettings(pub SolverSettings);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SimulationStats(pub SolverStats);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct Fluid(pub ParticleSet);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SimulationParams(pub SimParams);
+
+// Energy, momentum, mass and density error of the fluid and bodies after the last step
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SimulationConservation(pub Conservation);
+
+// Simulated seconds and steps taken since the run started
+#[derive(Resource, Default)]
+pub struct SimulationTime {
+    pub elapsed: f64,
+    pub steps: u64,
+}
+
+#[derive(Component)]
+pub struct Body {
+    pub velocity: Vec3,
+    pub force: Vec3,
+}
+
+#[derive(Component)]
+pub struct BoxCollision;
+
+// Radius of the sphere the particles collide with around a capsule
+pub const BODY_RADIUS: f32 = 125.;
+
+pub fn body_collision_mesh() -> BevyMesh {
+    BevyMesh::from(shape::UVSphere {
+        radius: BODY_RADIUS,
+        sectors: 10,
+        stacks: 10,
+    })
+}
+
+// Step the fluid, the forces the bodies applied to the particles since the last step are
+// already in the particle set
+pub fn simulation_step_system(
+    time: Res<Time>,
+    settings: Res<SimulationSettings>,
+    params: Res<SimulationParams>,
+    mut stats: ResMut<SimulationStats>,
+    mut fluid: ResMut<Fluid>,
+    mut simulation_time: ResMut<SimulationTime>,
+) {
+    // A step of no time would still reorder the particles and count as a step, skip it so a
+    // run resumed from a checkpoint reorders on the same steps
+    if time.delta_seconds() == 0. {
+        return;
+    }
+    let new_stats = step(&mut fluid, &settings, &params, time.delta_seconds());
+    simulation_time.elapsed += time.delta_seconds_f64();
+    simulation_time.steps += 1;
+    // The weakly compressible solver has no pressure solve, keep the stats of the last one
+    if settings.mode != SolverMode::Wcsph {
+        **stats = new_stats;
+    }
+}
+
+// Measure what the run should conserve whenever the simulated time moved, after the bodies did
+pub fn conservation_system(
+    simulation_time: Res<SimulationTime>,
+    fluid: Res<Fluid>,
+    params: Res<SimulationParams>,
+    body_query: Query<(&Body, &Transform)>,
+    mut conservation: ResMut<SimulationConservation>,
+) {
+    if !simulation_time.is_changed() {
+        return;
+    }
+    let bodies: Vec<BodyState> = (body_query.iter())
+        .map(|(body, transform)| BodyState {
+            position: transform.translation,
+            velocity: body.velocity,
+            force: body.force,
+        })
+        .collect();
+    **conservation = Conservation::measure(&fluid, &bodies, &params);
+}
+
+// Push the particles out of the capsules, with an equal and opposite force on the capsule
+pub fn box_collision_system(
+    time: Res<Time>,
+    meshes: Res<Assets<BevyMesh>>,
+    mut fluid: ResMut<Fluid>,
+    params: Res<SimulationParams>,
+    mut collision_query: Query<(&Handle<BevyMesh>, &mut Body, &Transform), With<BoxCollision>>,
+) {
+    // No step follows to apply the forces on frames without time
+    if time.delta_seconds() == 0. {
+        return;
+    }
+    for (mesh_handle, mut body, box_transform) in &mut collision_query {
+        for i in 0..fluid.len() {
+            if let Some(mesh) = meshes.get(mesh_handle) {
+                let mesh_to_world = box_transform.compute_matrix();
+                let from = box_transform.translation;
+                let to = fluid.positions[i];
+                let particle_vec = to - from;
+                let particle_length = particle_vec.length();
+                let ray_direction = (to - from).normalize();
+                let ray = Ray3d::new(from, ray_direction);
+
+                if let Some(intersection) =
+                    ray_intersection_over_mesh(mesh, &mesh_to_world, &ray, Backfaces::Include)
+                {
+                    // There was an intersection, check if it is before the cursor
+                    // on the ray
+                    let hit_distance = intersection.distance() + 50.0;
+                    let deflection = hit_distance - particle_length;
+                    if deflection > 0.0 {
+                        //println!("Hit");
+                        let force = params.particle_stiffness * deflection * ray_direction;
+                        fluid.forces[i] += force;
+                        body.force -= force;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Bounce the capsules off the walls of the box
+pub fn body_wall_collision_system(
+    params: Res<SimulationParams>,
+    mut body_query: Query<(&mut Body, &Transform)>,
+) {
+    let half_width = params.box_size.x * 0.5;
+    let half_height = params.box_size.y * 0.5;
+    let half_length = params.box_size.z * 0.5;
+    let body_size = params.body_size;
+
+    for (mut body, body_transform) in &mut body_query {
+        if body_transform.translation.x < -(half_width - (body_size / 2.)) {
+            body.velocity.x = 1.;
+        }
+
+        if body_transform.translation.x > (half_width - (body_size / 2.)) {
+            body.velocity.x = -1.;
+        }
+
+        if body_transform.translation.y < -(half_height - (body_size / 2.) - 40.) {
+            body.velocity.y = 1.;
+        }
+
+        if body_transform.translation.z < -(half_length - (body_size / 2.)) {
+            body.velocity.z = 1.;
+        }
+
+        if body_transform.translation.z > (half_length - (body_size / 2.)) {
+            body.velocity.z = -1.;
+        }
+    }
+}
+
+// numerical integration of the capsule positions
+pub fn body_movement_system(
+    time: Res<Time>,
+    params: Res<SimulationParams>,
+    mut body_query: Query<(&mut Body, &mut Transform), With<BoxCollision>>,
+) {
+    let dt = time.delta_seconds();
+    for (mut body, mut body_transform) in &mut body_query {
+        let force: Vec3 = body.force;
+        body_transform.translation += dt * body.velocity;
+        body.velocity += dt * (force / params.body_mass + Vec3::new(0.0, params.gravity, 0.0));
+        body.force = Vec3::ZERO;
+    }
+}
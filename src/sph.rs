@@ -1,221 +1,849 @@
-use bevy::prelude::*;
+use glam::Vec3;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::dfsph;
+use crate::iisph;
+use crate::kernels::{Poly6, SmoothingKernel, Spiky, Viscosity};
+use crate::neighbors::{neighbors_from_pairs, NeighborBackend, VerletList};
+use crate::octree_nearest_neighbor::morton_code;
+use crate::pcisph;
+
+// Default size of the box, the one SimParams starts with
+pub const SIZE_X: f32 = 1200.;
+pub const SIZE_Y: f32 = 800.;
+pub const SIZE_Z: f32 = 800.;
 
-use crate::{Body, BoxCollision, Particle};
+// Fewest particles a thread of the parallel path takes on, below it spreading the work costs more
+// than it saves
+const PAR_MIN_CHUNK: usize = 256;
+
+// Physical parameters of the fluid, the box and the capsule body, editable while the simulation
+// runs. The kernels are derived from the smoothing length, update_kernels() recomputes them.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimParams {
+    pub gravity: f32,
+    // Fraction of its velocity a particle keeps when it bounces off a wall
+    pub coef_rest: f32,
+    // Scene files set it with the domain size
+    #[serde(skip)]
+    pub box_size: Vec3,
+    pub particle_mass: f32,
+    // Stiffness of the linear equation of state
+    pub isotropic_exponent: f32,
+    pub base_density: f32,
+    pub smoothing_length: f32,
+    pub dynamic_viscosity: f32,
+    // Stiffness of the collisions between the particles and the capsule
+    pub particle_stiffness: f32,
+    pub body_mass: f32,
+    pub body_size: f32,
+    // Kernels used for the density, the pressure force and the viscous force
+    #[serde(skip)]
+    density_kernel: Poly6,
+    #[serde(skip)]
+    pressure_kernel: Spiky,
+    #[serde(skip)]
+    viscosity_kernel: Viscosity,
+}
 
-const GRAVITY: f32 = -200.;
+impl SimParams {
+    // Recompute the kernel normalizations from the smoothing length
+    pub fn update_kernels(&mut self) {
+        self.density_kernel = Poly6::new(self.smoothing_length);
+        self.pressure_kernel = Spiky::new(self.smoothing_length);
+        self.viscosity_kernel = Viscosity::new(self.smoothing_length);
+    }
 
-const BODY_MASS: f32 = 0.005;
-const BODY_SIZE: f32 = 150.;
+    // Density kernel scaled by the particle mass
+    pub fn density_kernel(&self, r: Vec3) -> f32 {
+        self.particle_mass * self.density_kernel.value(r)
+    }
 
-const PARTICLE_DAMPING: f32 = 10.;
+    // Pressure kernel gradient scaled by the particle mass, r points from the neighbor to the
+    // particle
+    pub fn pressure_kernel_gradient(&self, r: Vec3) -> Vec3 {
+        self.particle_mass * self.pressure_kernel.gradient(r)
+    }
 
-const COEF_REST: f32 = 0.6; //was 0.828
+    // Viscosity kernel laplacian scaled by the particle mass
+    pub fn viscosity_kernel_laplacian(&self, r: Vec3) -> f32 {
+        self.particle_mass * self.viscosity_kernel.laplacian(r)
+    }
+}
 
-pub const SIZE_X: f32 = 1200.;
-pub const SIZE_Y: f32 = 800.;
-pub const SIZE_Z: f32 = 800.;
+impl Default for SimParams {
+    fn default() -> Self {
+        let smoothing_length = 80.;
+        SimParams {
+            gravity: -200.,
+            coef_rest: 0.6, //was 0.828
+            box_size: Vec3::new(SIZE_X, SIZE_Y, SIZE_Z),
+            particle_mass: 50.,
+            isotropic_exponent: 300000.,
+            base_density: 0.00025,
+            smoothing_length,
+            dynamic_viscosity: 2.0,
+            particle_stiffness: 0.04,
+            body_mass: 0.005,
+            body_size: 150.,
+            density_kernel: Poly6::new(smoothing_length),
+            pressure_kernel: Spiky::new(smoothing_length),
+            viscosity_kernel: Viscosity::new(smoothing_length),
+        }
+    }
+}
 
-const PARTICLE_MASS: f32 = 50.;
-const ISOTROPIC_EXPONENT: f32 = 300000.;
-const BASE_DENSITY: f32 = 0.00025;
-const SMOOTHING_LENGTH: f32 = 80.;
-const DYNAMIC_VISCOSITY: f32 = 2.0;
-
-//const PI: f32 = std::f32::consts::PI;
-const NORMALIZATION_DENSITY: f32 = (315. * PARTICLE_MASS)
-    / (64.
-        * core::f32::consts::PI
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH);
-
-const NORMALIZATION_PRESSURE_FORCE: f32 = (-45. * PARTICLE_MASS)
-    / (core::f32::consts::PI
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH);
-
-const NORMALIZATION_VISCOUS_FORCE: f32 = (45. * DYNAMIC_VISCOSITY * PARTICLE_MASS)
-    / (core::f32::consts::PI
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH
-        * SMOOTHING_LENGTH);
-
-pub fn pressure_and_density_system(mut particle_query: Query<(&mut Particle, &Transform)>) {
-    let mut combinations = particle_query.iter_combinations_mut();
-    while let Some([(mut particle_0, transform_0), (mut particle_1, transform_1)]) =
-        combinations.fetch_next()
-    {
-        let distance_between = transform_1.translation - transform_0.translation;
-        //this is the distance
-        let length = distance_between.length();
-        if length < SMOOTHING_LENGTH {
-            let density =
-                NORMALIZATION_DENSITY * (SMOOTHING_LENGTH.powf(2.) - length.powf(2.)).powf(3.);
-            particle_0.density += density;
-            particle_1.density += density;
+// Pressure solver used to keep the fluid from compressing
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SolverMode {
+    // Weakly compressible SPH: pressure from the linear equation of state
+    Wcsph,
+    // Divergence-free SPH: constant density and divergence-free solves
+    Dfsph,
+    // Predictive-corrective incompressible SPH: iterated pressure corrections
+    Pcisph,
+    // Implicit incompressible SPH: pressure Poisson equation with relaxed Jacobi iterations
+    Iisph,
+}
+
+impl SolverMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SolverMode::Wcsph => "WCSPH",
+            SolverMode::Dfsph => "DFSPH",
+            SolverMode::Pcisph => "PCISPH",
+            SolverMode::Iisph => "IISPH",
         }
     }
+}
 
-    for (mut particle, _transform) in &mut particle_query.iter_mut() {
-        let own_density: f32 = NORMALIZATION_DENSITY * SMOOTHING_LENGTH.powf(2.).powf(3.);
-        particle.density += own_density;
-        particle.pressure = ISOTROPIC_EXPONENT * (particle.density - BASE_DENSITY);
+// Pressure law of the weakly compressible solver
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum EquationOfState {
+    // p = isotropic_exponent * (density - base_density)
+    Linear,
+    // p = stiffness * ((density / rest_density)^7 - 1)
+    Tait(TaitParameters),
+}
+
+impl EquationOfState {
+    pub fn pressure(&self, density: f32, params: &SimParams) -> f32 {
+        match self {
+            EquationOfState::Linear => params.isotropic_exponent * (density - params.base_density),
+            EquationOfState::Tait(parameters) => parameters.pressure(density),
+        }
     }
 }
 
-pub fn particle_collision_system(mut particle_query: Query<(&mut Particle, &Transform)>) {
-    let mut combinations = particle_query.iter_combinations_mut();
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaitParameters {
+    pub rest_density: f32,
+    pub speed_of_sound: f32,
+    // Negative pressures pull particles together and make them clump at the free surface
+    pub clamp_negative_pressure: bool,
+}
 
-    while let Some([(mut particle_0, transform_0), (mut particle_1, transform_1)]) =
-        combinations.fetch_next()
-    {
-        let distance_between = transform_1.translation - transform_0.translation;
-        //this is the distance
-        let length = distance_between.length();
-        let unit_vector = distance_between / length;
+impl TaitParameters {
+    pub const EXPONENT: i32 = 7;
+
+    // The density varies with the square of the Mach number, so the speed of sound needs to be
+    // max_velocity / sqrt(max_compressibility) to keep the density variation below the target
+    pub fn from_compressibility(
+        rest_density: f32,
+        max_velocity: f32,
+        max_compressibility: f32,
+        clamp_negative_pressure: bool,
+    ) -> Self {
+        TaitParameters {
+            rest_density,
+            speed_of_sound: max_velocity / max_compressibility.sqrt(),
+            clamp_negative_pressure,
+        }
+    }
 
-        let p0_pressure: f32 = particle_0.pressure;
-        let p1_pressure: f32 = particle_1.pressure;
+    // 1% compressibility for a particle falling the full height of the box
+    pub fn for_params(params: &SimParams) -> Self {
+        TaitParameters::from_compressibility(
+            params.base_density,
+            (2. * -params.gravity * params.box_size.y).sqrt(),
+            0.01,
+            true,
+        )
+    }
 
-        if length < SMOOTHING_LENGTH {
-            let density_both = particle_0.density + particle_1.density;
+    pub fn stiffness(&self) -> f32 {
+        self.rest_density * self.speed_of_sound.powf(2.) / Self::EXPONENT as f32
+    }
 
-            //Pressure Force
-            let pressure_force = NORMALIZATION_PRESSURE_FORCE
-                * ((p1_pressure + p0_pressure) / density_both)
-                * ((SMOOTHING_LENGTH - length).powf(2.));
+    pub fn pressure(&self, density: f32) -> f32 {
+        let pressure = self.stiffness() * ((density / self.rest_density).powi(Self::EXPONENT) - 1.);
+        if self.clamp_negative_pressure {
+            pressure.max(0.)
+        } else {
+            pressure
+        }
+    }
+}
 
-            particle_0.force += pressure_force * unit_vector;
-            particle_1.force -= pressure_force * unit_vector;
+impl Default for TaitParameters {
+    fn default() -> Self {
+        TaitParameters::for_params(&SimParams::default())
+    }
+}
 
-            let p0_velocity: Vec3 = particle_0.velocity;
-            let p1_velocity: Vec3 = particle_1.velocity;
+// Order the particles are stored in, so the neighbors of a particle are close to it in memory
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ParticleOrder {
+    // The order the particles were added in
+    Insertion,
+    // Row by row through the grid cells
+    Cell,
+    // Along the Morton (Z-order) curve through the grid cells, which keeps the cells of a block
+    // close together instead of only the cells of a row
+    #[default]
+    Morton,
+}
+
+impl ParticleOrder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParticleOrder::Insertion => "Insertion",
+            ParticleOrder::Cell => "Cell",
+            ParticleOrder::Morton => "Morton",
+        }
+    }
+}
 
-            //Viscous Force
-            let viscous_force = NORMALIZATION_VISCOUS_FORCE
-                * ((p1_velocity - p0_velocity) / density_both)
-                * (SMOOTHING_LENGTH - length);
+// Parameters of a simulation step
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SolverSettings {
+    pub mode: SolverMode,
+    pub equation_of_state: EquationOfState,
+    pub neighbor_backend: NeighborBackend,
+    // Distance added to the search radius of the neighbor lists, as a fraction of the smoothing
+    // length. They are rebuilt once a particle moved more than half of it, 0 rebuilds them every
+    // step.
+    pub neighbor_skin: f32,
+    // Allowed average density error, as a fraction of the base density
+    pub max_density_error: f32,
+    // Allowed average density change over one step, as a fraction of the base density
+    pub max_divergence_error: f32,
+    pub min_iterations: usize,
+    pub max_iterations: usize,
+    // Evaluate the density, forces and integration on all CPU cores. Gives the same result as
+    // the serial path, bit for bit.
+    pub parallel: bool,
+    pub particle_order: ParticleOrder,
+    // Reorder the particles every this many steps, the particles move little in between
+    pub reorder_interval: usize,
+}
 
-            particle_0.force += viscous_force;
-            particle_1.force -= viscous_force;
+impl Default for SolverSettings {
+    fn default() -> Self {
+        Self {
+            mode: SolverMode::Dfsph,
+            equation_of_state: EquationOfState::Linear,
+            neighbor_backend: NeighborBackend::UniformGrid,
+            neighbor_skin: 0.2,
+            max_density_error: 0.01,
+            max_divergence_error: 0.1,
+            min_iterations: 2,
+            max_iterations: 100,
+            parallel: true,
+            particle_order: ParticleOrder::Morton,
+            reorder_interval: 10,
         }
     }
 }
 
-pub fn wall_collision_system(
-    mut particle_query: Query<(&mut Particle, &Transform)>,
-    mut body_query: Query<(&mut Body, &Transform)>,
-) {
-    let half_width = SIZE_X * 0.5;
-    let half_height = SIZE_Y * 0.5;
-    let half_length = SIZE_Z * 0.5;
+// Iteration counts and remaining errors of the last pressure solve
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct SolverStats {
+    pub density_iterations: usize,
+    pub density_error: f32,
+    pub divergence_iterations: usize,
+    pub divergence_error: f32,
+}
+
+// The particles of the fluid as a structure of arrays, each particle is at the same index in
+// every list. step() keeps the particles sorted in the configured ParticleOrder so neighbors are
+// close in memory.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ParticleSet {
+    // Identifier of each particle, stays the same when the particles are sorted
+    pub ids: Vec<usize>,
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+    // Density and pressure computed by the last step
+    pub densities: Vec<f32>,
+    pub pressures: Vec<f32>,
+    // External forces, like collisions with bodies, applied by the next step and then cleared
+    pub forces: Vec<Vec3>,
+    // Pressure, viscous and external force the last step applied, without gravity
+    pub last_forces: Vec<Vec3>,
+    // Current index of every particle identifier
+    pub(crate) indices: Vec<usize>,
+    // Steps taken since the particles were last reordered
+    pub(crate) steps_since_reorder: usize,
+    pub(crate) neighbor_list: VerletList,
+}
 
-    if !body_query.is_empty() {
-        let (mut body, body_transform) = body_query.get_single_mut().unwrap();
+impl ParticleSet {
+    // Add a particle and return its identifier
+    pub fn push(&mut self, position: Vec3, velocity: Vec3) -> usize {
+        let id = self.indices.len();
+        self.indices.push(self.positions.len());
+        self.ids.push(id);
+        self.positions.push(position);
+        self.velocities.push(velocity);
+        self.densities.push(0.);
+        self.pressures.push(0.);
+        self.forces.push(Vec3::ZERO);
+        self.last_forces.push(Vec3::ZERO);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    // Current index of a particle
+    pub fn index_of(&self, id: usize) -> usize {
+        self.indices[id]
+    }
 
-        if body_transform.translation.x < -(half_width - (BODY_SIZE / 2.)) {
-            body.velocity.x = 1.;
+    // Rebuild the index of every identifier after the ids were read from a file, failing with
+    // the first id that is out of range or repeated
+    pub(crate) fn index_ids(&mut self) -> Result<(), usize> {
+        self.indices = vec![usize::MAX; self.ids.len()];
+        for (index, &id) in self.ids.iter().enumerate() {
+            match self.indices.get_mut(id) {
+                Some(slot) if *slot == usize::MAX => *slot = index,
+                _ => return Err(id),
+            }
         }
+        Ok(())
+    }
+
+    // Cached neighbor pairs, with the number of times they were rebuilt
+    pub fn neighbor_list(&self) -> &VerletList {
+        &self.neighbor_list
+    }
+
+    // Reorder the particles by the grid cell they are in, so the particles of a cell and of the
+    // cells next to it along x are next to each other in memory
+    pub fn sort_by_cell(&mut self, cell_size: f32) {
+        self.sort_by_key(|position| {
+            let cell = (position / cell_size).floor().as_ivec3();
+            (cell.z, cell.y, cell.x)
+        });
+    }
+
+    // Reorder the particles along the Morton curve through the grid cells, so the particles of
+    // every block of cells are next to each other in memory
+    pub fn sort_by_morton(&mut self, cell_size: f32) {
+        let min = (self.positions.iter()).fold(Vec3::splat(f32::INFINITY), |min, p| min.min(*p));
+        self.sort_by_key(|position| {
+            // Cells are counted from the lowest particle so the coordinates are never negative
+            morton_code(((position - min) / cell_size).as_uvec3())
+        });
+    }
 
-        if body_transform.translation.x > (half_width - (BODY_SIZE / 2.)) {
-            body.velocity.x = -1.;
+    // Reorder the particles by the key of their position
+    fn sort_by_key<K: Ord>(&mut self, key: impl Fn(Vec3) -> K) {
+        let mut keys: Vec<_> = (self.positions.iter())
+            .enumerate()
+            .map(|(i, &position)| (key(position), i))
+            .collect();
+        // Most steps no particle changes cell, skip the gather then
+        if keys.windows(2).all(|pair| pair[0] <= pair[1]) {
+            return;
         }
+        // The index breaks ties so the order only changes when the keys do
+        keys.sort_unstable();
+        let order: Vec<usize> = keys.into_iter().map(|(_, i)| i).collect();
+        self.reorder(&order);
+    }
+
+    // Move the particle at order[i] to index i
+    fn reorder(&mut self, order: &[usize]) {
+        fn gather<T: Copy>(values: &[T], order: &[usize]) -> Vec<T> {
+            order.iter().map(|&i| values[i]).collect()
+        }
+        self.ids = gather(&self.ids, order);
+        self.positions = gather(&self.positions, order);
+        self.velocities = gather(&self.velocities, order);
+        self.densities = gather(&self.densities, order);
+        self.pressures = gather(&self.pressures, order);
+        self.forces = gather(&self.forces, order);
+        self.last_forces = gather(&self.last_forces, order);
+        for (index, &id) in self.ids.iter().enumerate() {
+            self.indices[id] = index;
+        }
+        self.neighbor_list.invalidate();
+    }
+}
+
+// Density above the base density. The pressure solvers only correct compression, otherwise the
+// free surface is pulled together.
+pub(crate) fn compression(density: f32, params: &SimParams) -> f32 {
+    (density - params.base_density).max(0.)
+}
+
+// Particle data gathered from the particle set so the iterative solvers can work on plain arrays
+pub struct FluidState {
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+    pub densities: Vec<f32>,
+    // Indices of the other particles within the kernel support of each particle
+    pub neighbors: Vec<Vec<usize>>,
+    pub params: SimParams,
+}
 
-        if body_transform.translation.y < -(half_height - (BODY_SIZE / 2.) - 40.) {
-            body.velocity.y = 1.;
+// Advance the particles by one time step: density, pressure and viscous forces, the pressure
+// solve, wall collisions and integration. Returns the stats of the pressure solve.
+pub fn step(
+    particles: &mut ParticleSet,
+    settings: &SolverSettings,
+    params: &SimParams,
+    dt: f32,
+) -> SolverStats {
+    if particles.steps_since_reorder % settings.reorder_interval.max(1) == 0 {
+        match settings.particle_order {
+            ParticleOrder::Insertion => {}
+            ParticleOrder::Cell => particles.sort_by_cell(params.smoothing_length),
+            ParticleOrder::Morton => particles.sort_by_morton(params.smoothing_length),
         }
+    }
+    particles.steps_since_reorder += 1;
+    let pairs = particles.neighbor_list.pairs(
+        &particles.positions,
+        params.smoothing_length,
+        settings.neighbor_skin * params.smoothing_length,
+        settings.neighbor_backend,
+    );
+    let neighbors = neighbors_from_pairs(particles.len(), &pairs);
+
+    compute_densities(particles, settings, params, &pairs, &neighbors);
+    compute_forces(particles, settings, params, &pairs, &neighbors);
+    let stats = solve_pressure(particles, settings, params, neighbors, dt);
+    wall_collisions(particles, params);
+    integrate(particles, settings, params, dt);
+    stats
+}
+
+// Evaluate a function for every particle index on the rayon thread pool, which is started once
+// and shared with every step. The results are collected in order, so they are the same as a
+// serial map. Each task gets at least PAR_MIN_CHUNK particles, so small sets stay on one thread.
+fn par_map<T: Send>(count: usize, function: impl Fn(usize) -> T + Sync + Send) -> Vec<T> {
+    if count < 2 * PAR_MIN_CHUNK {
+        return (0..count).map(function).collect();
+    }
+    (0..count)
+        .into_par_iter()
+        .with_min_len(PAR_MIN_CHUNK)
+        .map(function)
+        .collect()
+}
 
-        if body_transform.translation.z < -(half_length - (BODY_SIZE / 2.)) {
-            body.velocity.z = 1.;
+// Density of every particle from its neighbors and itself, and the pressure of the weakly
+// compressible solver
+fn compute_densities(
+    particles: &mut ParticleSet,
+    settings: &SolverSettings,
+    params: &SimParams,
+    pairs: &[(usize, usize)],
+    neighbors: &[Vec<usize>],
+) {
+    if settings.parallel {
+        // Each particle adds its neighbors in increasing index order, the same order the pair
+        // loop adds them in, so the sums match the serial path exactly
+        let positions = &particles.positions;
+        particles.densities = par_map(particles.len(), |i| {
+            let mut density = 0.;
+            for &j in neighbors[i].iter() {
+                density += params.density_kernel(positions[j] - positions[i]);
+            }
+            density
+        });
+    } else {
+        particles.densities = vec![0.; particles.len()];
+        for &(a, b) in pairs.iter() {
+            let distance_between = particles.positions[b] - particles.positions[a];
+            let density = params.density_kernel(distance_between);
+            particles.densities[a] += density;
+            particles.densities[b] += density;
         }
+    }
+
+    let own_density: f32 = params.density_kernel(Vec3::ZERO);
+    for (density, pressure) in particles
+        .densities
+        .iter_mut()
+        .zip(particles.pressures.iter_mut())
+    {
+        *density += own_density;
+        // The incompressible solvers compute the pressure after the viscous forces are known
+        *pressure = if settings.mode == SolverMode::Wcsph {
+            settings.equation_of_state.pressure(*density, params)
+        } else {
+            0.
+        };
+    }
+}
 
-        if body_transform.translation.z > (half_length - (BODY_SIZE / 2.)) {
-            body.velocity.z = -1.;
+// Pressure and viscous force that particle b applies to particle a, particle a applies the
+// opposite forces to particle b
+fn pair_forces(particles: &ParticleSet, params: &SimParams, a: usize, b: usize) -> (Vec3, Vec3) {
+    // points from particle b to particle a
+    let distance_between = particles.positions[a] - particles.positions[b];
+
+    let density_both = particles.densities[a] + particles.densities[b];
+
+    //Pressure Force
+    let pressure_force = -((particles.pressures[b] + particles.pressures[a]) / density_both)
+        * params.pressure_kernel_gradient(distance_between);
+
+    //Viscous Force
+    let viscous_force = params.dynamic_viscosity
+        * ((particles.velocities[b] - particles.velocities[a]) / density_both)
+        * params.viscosity_kernel_laplacian(distance_between);
+
+    (pressure_force, viscous_force)
+}
+
+// Add the pressure and viscous forces between every pair of neighbors to the external forces
+fn compute_forces(
+    particles: &mut ParticleSet,
+    settings: &SolverSettings,
+    params: &SimParams,
+    pairs: &[(usize, usize)],
+    neighbors: &[Vec<usize>],
+) {
+    if settings.parallel {
+        // Each pair is evaluated with the lower index as particle a, like the pair loop, so the
+        // forces match the serial path exactly
+        let set = &*particles;
+        particles.forces = par_map(set.len(), |i| {
+            let mut force = set.forces[i];
+            for &j in neighbors[i].iter() {
+                if i < j {
+                    let (pressure_force, viscous_force) = pair_forces(set, params, i, j);
+                    force += pressure_force;
+                    force += viscous_force;
+                } else {
+                    let (pressure_force, viscous_force) = pair_forces(set, params, j, i);
+                    force -= pressure_force;
+                    force -= viscous_force;
+                }
+            }
+            force
+        });
+    } else {
+        for &(a, b) in pairs.iter() {
+            let (pressure_force, viscous_force) = pair_forces(particles, params, a, b);
+
+            particles.forces[a] += pressure_force;
+            particles.forces[b] -= pressure_force;
+
+            particles.forces[a] += viscous_force;
+            particles.forces[b] -= viscous_force;
         }
     }
+}
 
-    for (mut particle, transform) in &mut particle_query {
-        let x_pos = transform.translation.x;
-        let y_pos = transform.translation.y;
-        let z_pos = transform.translation.z;
+// Iterative pressure solve of the incompressible solver modes, runs once the viscous forces are known
+fn solve_pressure(
+    particles: &mut ParticleSet,
+    settings: &SolverSettings,
+    params: &SimParams,
+    neighbors: Vec<Vec<usize>>,
+    dt: f32,
+) -> SolverStats {
+    let mut stats = SolverStats::default();
+    if settings.mode == SolverMode::Wcsph || dt <= 0. || particles.is_empty() {
+        return stats;
+    }
 
-        let mut out_of_bounds = false;
+    let mut fluid = FluidState {
+        positions: particles.positions.clone(),
+        velocities: particles.velocities.clone(),
+        densities: particles.densities.clone(),
+        neighbors,
+        params: *params,
+    };
+    let non_pressure_accelerations: Vec<Vec3> = particles
+        .forces
+        .iter()
+        .zip(particles.densities.iter())
+        .map(|(force, density)| *force / *density + Vec3::new(0.0, params.gravity, 0.0))
+        .collect();
+
+    let (pressure_accelerations, pressures) = match settings.mode {
+        SolverMode::Dfsph => dfsph::solve(
+            &mut fluid,
+            &non_pressure_accelerations,
+            dt,
+            settings,
+            &mut stats,
+        ),
+        SolverMode::Pcisph => pcisph::solve(
+            &fluid,
+            &non_pressure_accelerations,
+            dt,
+            settings,
+            &mut stats,
+        ),
+        SolverMode::Iisph => iisph::solve(
+            &fluid,
+            &non_pressure_accelerations,
+            dt,
+            settings,
+            &mut stats,
+        ),
+        SolverMode::Wcsph => return stats,
+    };
+
+    // Velocity corrections are written back directly, the pressure acceleration is applied as
+    // a force so integrate() handles it like any other force
+    particles.velocities = fluid.velocities;
+    for ((force, density), acceleration) in particles
+        .forces
+        .iter_mut()
+        .zip(particles.densities.iter())
+        .zip(pressure_accelerations.iter())
+    {
+        *force += *density * *acceleration;
+    }
+    particles.pressures = pressures;
+    stats
+}
+
+// Keep all particles inside the box by reflecting their velocity at the walls
+fn wall_collisions(particles: &mut ParticleSet, params: &SimParams) {
+    let half_size = params.box_size * 0.5;
 
-        if x_pos > half_width {
-            particle.velocity.x = -particle.velocity.x.abs() * COEF_REST;
-            out_of_bounds = true;
+    for (position, velocity) in particles
+        .positions
+        .iter()
+        .zip(particles.velocities.iter_mut())
+    {
+        for axis in 0..3 {
+            if position[axis] > half_size[axis] {
+                velocity[axis] = -velocity[axis].abs() * params.coef_rest;
+            }
+            if position[axis] < -half_size[axis] {
+                velocity[axis] = velocity[axis].abs() * params.coef_rest;
+            }
         }
+    }
+}
+
+// numerical integration of particle positions, explicit Euler for WCSPH and semi-implicit Euler
+// for the pressure solvers
+fn integrate(particles: &mut ParticleSet, settings: &SolverSettings, params: &SimParams, dt: f32) {
+    // The pressure solvers update the velocity first so the position uses the pressure
+    // corrected velocity, WCSPH keeps the explicit Euler order it always used
+    let explicit = settings.mode == SolverMode::Wcsph;
+    if explicit {
+        move_particles(particles, dt);
+    }
+
+    let velocity = |i: usize| {
+        particles.velocities[i]
+            + dt * (particles.forces[i] / particles.densities[i]
+                + Vec3::new(0.0, params.gravity, 0.0))
+    };
+    particles.velocities = if settings.parallel {
+        par_map(particles.len(), velocity)
+    } else {
+        (0..particles.len()).map(velocity).collect()
+    };
+
+    if !explicit {
+        move_particles(particles, dt);
+    }
+
+    // Keep the forces for output and start the next step without any
+    std::mem::swap(&mut particles.forces, &mut particles.last_forces);
+    particles.forces.fill(Vec3::ZERO);
+}
 
-        if x_pos < -half_width {
-            particle.velocity.x = particle.velocity.x.abs() * COEF_REST;
-            out_of_bounds = true;
+fn move_particles(particles: &mut ParticleSet, dt: f32) {
+    for (position, velocity) in particles
+        .positions
+        .iter_mut()
+        .zip(particles.velocities.iter())
+    {
+        *position += dt * *velocity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Block of fluid in the corner of the box at about the rest density
+    fn dam_break() -> ParticleSet {
+        let mut particles = ParticleSet::default();
+        let spacing = 58.;
+        let corner = Vec3::new(-SIZE_X / 2., -SIZE_Y / 2., -SIZE_Z / 2.) + spacing / 2.;
+        for x in 0..10 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let position = corner + spacing * Vec3::new(x as f32, y as f32, z as f32);
+                    particles.push(position, Vec3::ZERO);
+                }
+            }
         }
+        particles
+    }
 
-        if y_pos > half_height {
-            particle.velocity.y = -particle.velocity.y.abs() * COEF_REST;
-            out_of_bounds = true;
+    #[test]
+    fn parallel_step_matches_serial() {
+        for mode in [SolverMode::Wcsph, SolverMode::Dfsph] {
+            let mut serial = dam_break();
+            let mut parallel = dam_break();
+            for _ in 0..10 {
+                let serial_settings = SolverSettings {
+                    mode,
+                    parallel: false,
+                    ..Default::default()
+                };
+                let parallel_settings = SolverSettings {
+                    parallel: true,
+                    ..serial_settings
+                };
+                step(
+                    &mut serial,
+                    &serial_settings,
+                    &SimParams::default(),
+                    1. / 60.,
+                );
+                step(
+                    &mut parallel,
+                    &parallel_settings,
+                    &SimParams::default(),
+                    1. / 60.,
+                );
+            }
+            assert_ne!(serial.positions, dam_break().positions);
+            assert_eq!(serial, parallel);
         }
+    }
 
-        if y_pos < -half_height {
-            particle.velocity.y = particle.velocity.y.abs() * COEF_REST;
-            out_of_bounds = true;
+    #[test]
+    fn neighbor_lists_match_searching_every_step() {
+        let mut searched = dam_break();
+        let mut cached = dam_break();
+        let search_settings = SolverSettings {
+            neighbor_skin: 0.,
+            ..Default::default()
+        };
+        let cached_settings = SolverSettings::default();
+        for _ in 0..60 {
+            step(
+                &mut searched,
+                &search_settings,
+                &SimParams::default(),
+                1. / 60.,
+            );
+            step(
+                &mut cached,
+                &cached_settings,
+                &SimParams::default(),
+                1. / 60.,
+            );
         }
+        assert_eq!(searched.positions, cached.positions);
+        assert_eq!(searched.velocities, cached.velocities);
+        assert_eq!(cached.neighbor_list().uses, 60);
+        assert!(cached.neighbor_list().builds < searched.neighbor_list().builds);
+    }
 
-        if z_pos > half_length {
-            particle.velocity.z = -particle.velocity.z.abs() * COEF_REST;
-            out_of_bounds = true;
+    #[test]
+    fn sorting_keeps_particle_ids() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut particles = ParticleSet::default();
+        let mut pushed = Vec::new();
+        for _ in 0..500 {
+            let position = Vec3::new(
+                rng.gen_range(-600.0..600.0),
+                rng.gen_range(-400.0..400.0),
+                rng.gen_range(-400.0..400.0),
+            );
+            let velocity = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+            pushed.push((particles.push(position, velocity), position, velocity));
         }
 
-        if z_pos < -half_length {
-            particle.velocity.z = particle.velocity.z.abs() * COEF_REST;
-            out_of_bounds = true;
+        let smoothing_length = SimParams::default().smoothing_length;
+        particles.sort_by_cell(smoothing_length);
+
+        for &(id, position, velocity) in pushed.iter() {
+            let index = particles.index_of(id);
+            assert_eq!(particles.ids[index], id);
+            assert_eq!(particles.positions[index], position);
+            assert_eq!(particles.velocities[index], velocity);
         }
+        let cells: Vec<(i32, i32, i32)> = particles
+            .positions
+            .iter()
+            .map(|position| {
+                let cell = (*position / smoothing_length).floor().as_ivec3();
+                (cell.z, cell.y, cell.x)
+            })
+            .collect();
+        assert!(cells.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        particles.sort_by_morton(smoothing_length);
+
+        for (id, position, velocity) in pushed {
+            let index = particles.index_of(id);
+            assert_eq!(particles.ids[index], id);
+            assert_eq!(particles.positions[index], position);
+            assert_eq!(particles.velocities[index], velocity);
+        }
+    }
 
-        if out_of_bounds {
-            let dv = -particle.velocity * PARTICLE_DAMPING / PARTICLE_MASS;
-            particle.acceleration += dv;
+    #[test]
+    fn dam_break_stays_in_the_box() {
+        let mut particles = dam_break();
+        let settings = SolverSettings::default();
+        let params = SimParams::default();
+        for _ in 0..120 {
+            step(&mut particles, &settings, &params, 1. / 60.);
+        }
+        // Particles may overshoot a wall by a step before bouncing back
+        let half_size = params.box_size * 0.5 + params.smoothing_length;
+        for position in particles.positions.iter() {
+            assert!(position.is_finite());
+            assert!(position.abs().cmple(half_size).all(), "{position}");
         }
     }
-}
 
-// numerical integration of particle positions
-pub fn movement_system(
-    time: Res<Time>,
-    mut particle_query: Query<(&mut Particle, &mut Transform), Without<BoxCollision>>,
-    mut body_query: Query<(&mut Body, &mut Transform), With<BoxCollision>>,
-) {
-    //println!("start of movement system");
-
-    let dt = time.delta_seconds();
-    for (mut particle, mut transform) in &mut particle_query {
-        let force: Vec3 = particle.force;
-        let density: f32 = particle.density;
-        transform.translation += dt * particle.velocity;
-        particle.velocity += dt * (force / density + Vec3::new(0.0, GRAVITY, 0.0));
-
-        particle.density = 0.;
-        particle.pressure = 0.;
-        particle.force = Vec3::ZERO;
-    }
-
-    if !body_query.is_empty() {
-        let (mut body, mut body_transform) = body_query.get_single_mut().unwrap();
-        let force: Vec3 = body.force;
-        body_transform.translation += dt * body.velocity;
-        body.velocity += dt * (force / BODY_MASS + Vec3::new(0.0, GRAVITY, 0.0));
-        body.force = Vec3::ZERO;
+    #[test]
+    fn changing_the_smoothing_length_updates_the_kernels() {
+        let mut params = SimParams::default();
+        let r = Vec3::new(50., 0., 0.);
+        assert!(params.density_kernel(r) > 0.);
+
+        params.smoothing_length = 40.;
+        params.update_kernels();
+        assert_eq!(params.density_kernel(r), 0.);
+        assert_eq!(params.pressure_kernel_gradient(r), Vec3::ZERO);
+        // The poly6 kernel is 315 / (64 pi h^3) at its center
+        let own_density = 315. / (64. * std::f32::consts::PI * 40_f32.powi(3));
+        let relative_error =
+            (params.density_kernel(Vec3::ZERO) / params.particle_mass - own_density) / own_density;
+        assert!(relative_error.abs() < 1e-5, "{relative_error}");
     }
 }
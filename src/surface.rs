@@ -0,0 +1,500 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+use serde_json::{json, Value};
+
+use crate::kernels::{Poly6, SmoothingKernel};
+use crate::neighbors::SpatialHash;
+use crate::sph::ParticleSet;
+
+/*
+ *
+ * Surface Mesh Export
+ * Writes the reconstructed fluid surface as OBJ, PLY or binary glTF (.glb) files that Blender
+ * imports directly. The normals are optional, and the velocity, speed, density and pressure of
+ * the particles under the surface can be sampled onto the vertices for shading: PLY stores them
+ * as extra vertex properties and glTF as custom _VELOCITY, _SPEED, ... attributes. OBJ has no
+ * place for them, so an OBJ file only holds the positions and normals.
+ *
+ */
+
+// Triangle mesh of the fluid surface with values carried by every vertex
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SurfaceMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    // Three vertex indices per triangle
+    pub indices: Vec<u32>,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+// Named value with one or three components per vertex
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexAttribute {
+    pub name: String,
+    pub components: usize,
+    pub values: Vec<f32>,
+}
+
+impl SurfaceMesh {
+    // Mesh from flat x, y, z lists and a flat triangle list, the layout the surface extraction
+    // produces
+    pub fn from_flat(positions: &[f32], normals: &[f32], indices: &[usize]) -> SurfaceMesh {
+        let vectors = |flat: &[f32]| flat.chunks_exact(3).map(Vec3::from_slice).collect();
+        SurfaceMesh {
+            positions: vectors(positions),
+            normals: vectors(normals),
+            indices: indices.iter().map(|&index| index as u32).collect(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // Add the velocity, speed, density and pressure of the particles as vertex attributes,
+    // averaged over the particles within radius of each vertex with kernel weights. The surface
+    // lies where the particle kernels end, so the radius should reach further than the smoothing
+    // length. Vertices with no particle within the radius get zeros.
+    pub fn sample_particles(&mut self, particles: &ParticleSet, radius: f32) {
+        let kernel = Poly6::new(radius);
+        let hash = SpatialHash::new(&particles.positions, radius);
+        let count = self.positions.len();
+        let mut velocities = Vec::with_capacity(count * 3);
+        let mut speeds = Vec::with_capacity(count);
+        let mut densities = Vec::with_capacity(count);
+        let mut pressures = Vec::with_capacity(count);
+
+        for &vertex in self.positions.iter() {
+            let mut weights = 0.;
+            let mut velocity = Vec3::ZERO;
+            let mut density = 0.;
+            let mut pressure = 0.;
+            for index in hash.near(vertex) {
+                let weight = kernel.value(vertex - particles.positions[index]);
+                weights += weight;
+                velocity += weight * particles.velocities[index];
+                density += weight * particles.densities[index];
+                pressure += weight * particles.pressures[index];
+            }
+            if weights > 0. {
+                velocity /= weights;
+                density /= weights;
+                pressure /= weights;
+            }
+            velocities.extend(velocity.to_array());
+            speeds.push(velocity.length());
+            densities.push(density);
+            pressures.push(pressure);
+        }
+
+        self.attributes = vec![
+            VertexAttribute::new("velocity", 3, velocities),
+            VertexAttribute::new("speed", 1, speeds),
+            VertexAttribute::new("density", 1, densities),
+            VertexAttribute::new("pressure", 1, pressures),
+        ];
+    }
+}
+
+impl VertexAttribute {
+    pub fn new(name: &str, components: usize, values: Vec<f32>) -> VertexAttribute {
+        VertexAttribute {
+            name: name.to_string(),
+            components,
+            values,
+        }
+    }
+
+    // Names of the components, a single value keeps the name of the attribute
+    fn component_names(&self) -> Vec<String> {
+        match self.components {
+            1 => vec![self.name.clone()],
+            _ => (["x", "y", "z", "w"].iter().take(self.components))
+                .map(|axis| format!("{}_{axis}", self.name))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MeshFormat {
+    Obj,
+    Ply,
+    Glb,
+}
+
+impl MeshFormat {
+    pub fn from_name(name: &str) -> Option<MeshFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "obj" => Some(MeshFormat::Obj),
+            "ply" => Some(MeshFormat::Ply),
+            "glb" | "gltf" => Some(MeshFormat::Glb),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MeshFormat::Obj => "obj",
+            MeshFormat::Ply => "ply",
+            MeshFormat::Glb => "glb",
+        }
+    }
+
+    // Write the mesh in this format, without the normals unless asked for
+    pub fn write(
+        &self,
+        writer: &mut impl Write,
+        mesh: &SurfaceMesh,
+        normals: bool,
+    ) -> io::Result<()> {
+        match self {
+            MeshFormat::Obj => write_obj(writer, mesh, normals),
+            MeshFormat::Ply => write_ply(writer, mesh, normals),
+            MeshFormat::Glb => write_glb(writer, mesh, normals),
+        }
+    }
+}
+
+// Wavefront OBJ, the attributes are left out since the format has no place for them
+pub fn write_obj(writer: &mut impl Write, mesh: &SurfaceMesh, normals: bool) -> io::Result<()> {
+    writeln!(writer, "# Fluid surface, {} vertices", mesh.positions.len())?;
+    for position in mesh.positions.iter() {
+        writeln!(writer, "v {} {} {}", position.x, position.y, position.z)?;
+    }
+    if normals {
+        for normal in mesh.normals.iter() {
+            writeln!(writer, "vn {} {} {}", normal.x, normal.y, normal.z)?;
+        }
+    }
+    // OBJ counts the vertices from 1
+    for triangle in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+        if normals {
+            writeln!(writer, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        } else {
+            writeln!(writer, "f {a} {b} {c}")?;
+        }
+    }
+    Ok(())
+}
+
+// ASCII PLY with the normals and attributes as extra vertex properties
+pub fn write_ply(writer: &mut impl Write, mesh: &SurfaceMesh, normals: bool) -> io::Result<()> {
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format ascii 1.0")?;
+    writeln!(writer, "element vertex {}", mesh.positions.len())?;
+    let mut properties = ["x", "y", "z"].map(String::from).to_vec();
+    if normals {
+        properties.extend(["nx", "ny", "nz"].map(String::from));
+    }
+    for attribute in mesh.attributes.iter() {
+        properties.extend(attribute.component_names());
+    }
+    for property in properties.iter() {
+        writeln!(writer, "property float {property}")?;
+    }
+    writeln!(writer, "element face {}", mesh.triangle_count())?;
+    writeln!(writer, "property list uchar uint vertex_indices")?;
+    writeln!(writer, "end_header")?;
+
+    for (vertex, position) in mesh.positions.iter().enumerate() {
+        write!(writer, "{} {} {}", position.x, position.y, position.z)?;
+        if normals {
+            let normal = mesh.normals[vertex];
+            write!(writer, " {} {} {}", normal.x, normal.y, normal.z)?;
+        }
+        for attribute in mesh.attributes.iter() {
+            let start = vertex * attribute.components;
+            for value in attribute.values[start..start + attribute.components].iter() {
+                write!(writer, " {value}")?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    for triangle in mesh.indices.chunks_exact(3) {
+        writeln!(writer, "3 {} {} {}", triangle[0], triangle[1], triangle[2])?;
+    }
+    Ok(())
+}
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_VERSION: u32 = 2;
+const GLB_JSON_CHUNK: &[u8; 4] = b"JSON";
+const GLB_BIN_CHUNK: &[u8; 4] = b"BIN\0";
+const GLTF_FLOAT: u32 = 5126;
+const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_ARRAY_BUFFER: u32 = 34962;
+const GLTF_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const GLTF_TRIANGLES: u32 = 4;
+
+// Binary glTF 2.0 holding one mesh, the attributes become custom attributes named after them
+// in upper case with a leading underscore, as the format asks of application specific data
+pub fn write_glb(writer: &mut impl Write, mesh: &SurfaceMesh, normals: bool) -> io::Result<()> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut buffer_views = Vec::new();
+    let mut accessors = Vec::new();
+    // Append the values to the buffer, with a buffer view and an accessor describing them
+    let mut push_accessor = |values: &mut dyn Iterator<Item = [u8; 4]>,
+                             count: usize,
+                             accessor_type: &str,
+                             component_type: u32,
+                             target: u32,
+                             extra: Value| {
+        let offset = buffer.len();
+        values.for_each(|bytes| buffer.extend(bytes));
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": target,
+        }));
+        let mut accessor = json!({
+            "bufferView": buffer_views.len() - 1,
+            "componentType": component_type,
+            "count": count,
+            "type": accessor_type,
+        });
+        if let (Value::Object(accessor), Value::Object(extra)) = (&mut accessor, extra) {
+            accessor.extend(extra);
+        }
+        accessors.push(accessor);
+        accessors.len() - 1
+    };
+    let floats = |vectors: &[Vec3]| {
+        let values: Vec<[u8; 4]> = (vectors.iter())
+            .flat_map(|vector| vector.to_array())
+            .map(f32::to_le_bytes)
+            .collect();
+        values.into_iter()
+    };
+
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "particles" },
+        "scene": 0,
+        "scenes": [{}],
+    });
+    // glTF does not allow empty accessors, a frame without a surface is a scene without a mesh
+    if !mesh.indices.is_empty() {
+        let mut attributes = serde_json::Map::new();
+        // The position accessor must give the bounds of the mesh
+        let min = (mesh.positions.iter()).fold(Vec3::splat(f32::INFINITY), |min, p| min.min(*p));
+        let max =
+            (mesh.positions.iter()).fold(Vec3::splat(f32::NEG_INFINITY), |max, p| max.max(*p));
+        let position = push_accessor(
+            &mut floats(&mesh.positions),
+            mesh.positions.len(),
+            "VEC3",
+            GLTF_FLOAT,
+            GLTF_ARRAY_BUFFER,
+            json!({ "min": min.to_array(), "max": max.to_array() }),
+        );
+        attributes.insert("POSITION".to_string(), json!(position));
+        if normals {
+            let normal = push_accessor(
+                &mut floats(&mesh.normals),
+                mesh.normals.len(),
+                "VEC3",
+                GLTF_FLOAT,
+                GLTF_ARRAY_BUFFER,
+                json!({}),
+            );
+            attributes.insert("NORMAL".to_string(), json!(normal));
+        }
+        for attribute in mesh.attributes.iter() {
+            let accessor_type = match attribute.components {
+                1 => "SCALAR",
+                2 => "VEC2",
+                3 => "VEC3",
+                _ => "VEC4",
+            };
+            let accessor = push_accessor(
+                &mut attribute.values.iter().map(|value| value.to_le_bytes()),
+                attribute.values.len() / attribute.components,
+                accessor_type,
+                GLTF_FLOAT,
+                GLTF_ARRAY_BUFFER,
+                json!({}),
+            );
+            let name = format!("_{}", attribute.name.to_ascii_uppercase());
+            attributes.insert(name, json!(accessor));
+        }
+        let indices = push_accessor(
+            &mut mesh.indices.iter().map(|index| index.to_le_bytes()),
+            mesh.indices.len(),
+            "SCALAR",
+            GLTF_UNSIGNED_INT,
+            GLTF_ELEMENT_ARRAY_BUFFER,
+            json!({}),
+        );
+
+        document["scenes"] = json!([{ "nodes": [0] }]);
+        document["nodes"] = json!([{ "mesh": 0, "name": "fluid" }]);
+        document["meshes"] = json!([{
+            "name": "fluid",
+            "primitives": [{ "attributes": attributes, "indices": indices, "mode": GLTF_TRIANGLES }],
+        }]);
+        document["buffers"] = json!([{ "byteLength": buffer.len() }]);
+        document["bufferViews"] = json!(buffer_views);
+        document["accessors"] = json!(accessors);
+    }
+
+    // Both chunks are padded to four bytes, the JSON with spaces and the buffer with zeros
+    let mut json = serde_json::to_vec(&document)?;
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+    while buffer.len() % 4 != 0 {
+        buffer.push(0);
+    }
+    let mut length = 12 + 8 + json.len();
+    if !buffer.is_empty() {
+        length += 8 + buffer.len();
+    }
+
+    writer.write_all(GLB_MAGIC)?;
+    writer.write_all(&GLB_VERSION.to_le_bytes())?;
+    writer.write_all(&(length as u32).to_le_bytes())?;
+    writer.write_all(&(json.len() as u32).to_le_bytes())?;
+    writer.write_all(GLB_JSON_CHUNK)?;
+    writer.write_all(&json)?;
+    if !buffer.is_empty() {
+        writer.write_all(&(buffer.len() as u32).to_le_bytes())?;
+        writer.write_all(GLB_BIN_CHUNK)?;
+        writer.write_all(&buffer)?;
+    }
+    Ok(())
+}
+
+// Numbered surface files in a directory, one per frame
+pub struct SurfaceSeries {
+    directory: PathBuf,
+    name: String,
+    pub format: MeshFormat,
+    pub normals: bool,
+}
+
+impl SurfaceSeries {
+    // Series of <name>_<frame> meshes in one format, the directory is made if it is missing
+    pub fn new(
+        directory: &Path,
+        name: &str,
+        format: MeshFormat,
+        normals: bool,
+    ) -> io::Result<SurfaceSeries> {
+        fs::create_dir_all(directory)?;
+        Ok(SurfaceSeries {
+            directory: directory.to_path_buf(),
+            name: name.to_string(),
+            format,
+            normals,
+        })
+    }
+
+    // Write the surface of a frame to <name>_<frame>.<extension>
+    pub fn write_frame(&self, frame: u64, mesh: &SurfaceMesh) -> io::Result<PathBuf> {
+        let path = (self.directory).join(format!(
+            "{}_{frame:06}.{}",
+            self.name,
+            self.format.extension()
+        ));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        self.format.write(&mut writer, mesh, self.normals)?;
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One triangle with a particle sitting on its first vertex
+    fn triangle() -> (SurfaceMesh, ParticleSet) {
+        let mesh = SurfaceMesh::from_flat(
+            &[0., 0., 0., 1., 0., 0., 0., 1., 0.],
+            &[0., 0., 1., 0., 0., 1., 0., 0., 1.],
+            &[0, 1, 2],
+        );
+        let mut particles = ParticleSet::default();
+        particles.push(Vec3::ZERO, Vec3::new(3., 4., 0.));
+        particles.densities = vec![1000.];
+        particles.pressures = vec![5.];
+        (mesh, particles)
+    }
+
+    #[test]
+    fn sampled_attributes_follow_the_particles() {
+        let (mut mesh, particles) = triangle();
+        mesh.sample_particles(&particles, 1.5);
+        let speed = &mesh.attributes[1];
+        assert_eq!(speed.name, "speed");
+        assert_eq!(speed.values[0], 5.);
+        // A lone particle gives its own values to every vertex it reaches
+        assert_eq!(mesh.attributes[2].values, [1000., 1000., 1000.]);
+
+        mesh.sample_particles(&particles, 0.5);
+        assert_eq!(mesh.attributes[3].values, [5., 0., 0.]);
+    }
+
+    #[test]
+    fn obj_and_ply_hold_the_triangle() {
+        let (mut mesh, particles) = triangle();
+        mesh.sample_particles(&particles, 1.5);
+
+        let mut obj = Vec::new();
+        write_obj(&mut obj, &mesh, true).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert_eq!(obj.lines().filter(|line| line.starts_with("v ")).count(), 3);
+        assert!(obj.contains("vn 0 0 1"));
+        assert!(obj.contains("f 1//1 2//2 3//3"));
+
+        let mut ply = Vec::new();
+        write_ply(&mut ply, &mesh, false).unwrap();
+        let ply = String::from_utf8(ply).unwrap();
+        assert!(ply.contains("property float velocity_y"));
+        assert!(!ply.contains("property float nx"));
+        assert!(ply.contains("0 0 0 3 4 0 5 1000 5\n"));
+        assert!(ply.ends_with("3 0 1 2\n"));
+    }
+
+    #[test]
+    fn glb_chunks_describe_the_mesh() {
+        let (mut mesh, particles) = triangle();
+        mesh.sample_particles(&particles, 1.5);
+        let mut glb = Vec::new();
+        write_glb(&mut glb, &mesh, true).unwrap();
+
+        let word = |at: usize| u32::from_le_bytes(glb[at..at + 4].try_into().unwrap()) as usize;
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(word(8), glb.len());
+        let json_length = word(12);
+        let document: Value = serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+        let attributes = &document["meshes"][0]["primitives"][0]["attributes"];
+        for name in [
+            "POSITION",
+            "NORMAL",
+            "_VELOCITY",
+            "_SPEED",
+            "_DENSITY",
+            "_PRESSURE",
+        ] {
+            assert!(attributes.get(name).is_some(), "{name} is missing");
+        }
+        assert_eq!(document["accessors"][0]["max"], json!([1., 1., 0.]));
+
+        // Positions, normals, 6 attribute floats per vertex and the indices
+        let bin_length = word(20 + json_length);
+        assert_eq!(bin_length, 3 * 4 * (3 + 3 + 6 + 1));
+        assert_eq!(document["buffers"][0]["byteLength"], bin_length);
+
+        // An empty surface is still a valid file
+        let mut empty = Vec::new();
+        write_glb(&mut empty, &SurfaceMesh::default(), true).unwrap();
+        assert!(empty.len() % 4 == 0);
+    }
+}
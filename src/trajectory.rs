@@ -0,0 +1,853 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+
+use crate::checkpoint::{read_bytes, read_len, read_vec3, write_len, write_vec3, BodyState};
+use crate::sph::ParticleSet;
+
+/*
+ *
+ * Trajectories
+ * A recorded run as a sequence of frames, each holding the time, the step, the position of every
+ * particle, the optional velocity, density and pressure channels, and the state of every body.
+ * Enough to show the run again without stepping the physics, not to carry it on, which is what
+ * checkpoints are for. The particles are stored in identifier order, so frames line up no
+ * matter how the solver sorted them.
+ * To keep long recordings small the values are quantized to 16 bits:
+ *  - Positions relative to the bounds in the header, so the error is at most half of
+ *    (max - min) / 65535 on each axis. Positions outside the bounds are clamped to them.
+ *  - Velocities relative to the largest component of the frame, densities and pressures
+ *    relative to their range in the frame, again to half a step.
+ * Every few frames a keyframe stores the quantized positions as they are, the frames between
+ * store how far each particle moved since the last keyframe as variable length integers, a byte
+ * or two for most particles. A frame is decoded from itself and its keyframe alone, so the
+ * reader can jump to any frame.
+ * The file starts with a magic number, TRAJECTORY_VERSION and the header, then every frame
+ * follows with its length in bytes first, so a reader finds the frames without decoding them.
+ * A frame cut short by a crash while recording is left out.
+ *
+ */
+
+pub const TRAJECTORY_VERSION: u32 = 2;
+const MAGIC: &[u8; 8] = b"SPHTRAJ\0";
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 30;
+
+const KEYFRAME: u8 = 0;
+const DELTA_FRAME: u8 = 1;
+const LEVELS: f32 = u16::MAX as f32;
+const SIGNED_LEVELS: f32 = i16::MAX as f32;
+
+// Values recorded besides the positions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Channels {
+    pub velocity: bool,
+    pub density: bool,
+    pub pressure: bool,
+}
+
+impl Channels {
+    pub const ALL: Channels = Channels {
+        velocity: true,
+        density: true,
+        pressure: true,
+    };
+    pub const NONE: Channels = Channels {
+        velocity: false,
+        density: false,
+        pressure: false,
+    };
+
+    // Channels from a comma separated list like "velocity,density", or "none"
+    pub fn from_names(names: &str) -> Option<Channels> {
+        let mut channels = Channels::NONE;
+        for name in names.split(',').map(str::trim) {
+            match name {
+                "velocity" => channels.velocity = true,
+                "density" => channels.density = true,
+                "pressure" => channels.pressure = true,
+                "all" => channels = Channels::ALL,
+                "none" => {}
+                _ => return None,
+            }
+        }
+        Some(channels)
+    }
+
+    fn to_bits(self) -> u8 {
+        self.velocity as u8 | (self.density as u8) << 1 | (self.pressure as u8) << 2
+    }
+
+    fn from_bits(bits: u8) -> Channels {
+        Channels {
+            velocity: bits & 1 != 0,
+            density: bits & 2 != 0,
+            pressure: bits & 4 != 0,
+        }
+    }
+}
+
+// Header of a trajectory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrajectoryOptions {
+    // Box the positions are quantized in
+    pub min: Vec3,
+    pub max: Vec3,
+    pub channels: Channels,
+    // Frames from one keyframe to the next
+    pub keyframe_interval: u32,
+}
+
+impl TrajectoryOptions {
+    // Every channel and the default keyframe interval
+    pub fn new(min: Vec3, max: Vec3) -> TrajectoryOptions {
+        TrajectoryOptions {
+            min,
+            max,
+            channels: Channels::ALL,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+        }
+    }
+
+    // Largest difference between a position and the one read back, on each axis, for positions
+    // within the bounds
+    pub fn position_tolerance(&self) -> Vec3 {
+        (self.max - self.min) / LEVELS * 0.5
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_vec3(writer, self.min)?;
+        write_vec3(writer, self.max)?;
+        writer.write_all(&[self.channels.to_bits()])?;
+        writer.write_all(&self.keyframe_interval.to_le_bytes())
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<TrajectoryOptions> {
+        Ok(TrajectoryOptions {
+            min: read_vec3(reader)?,
+            max: read_vec3(reader)?,
+            channels: Channels::from_bits(read_bytes::<1>(reader)?[0]),
+            keyframe_interval: u32::from_le_bytes(read_bytes(reader)?),
+        })
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct TrajectoryFrame {
+    // Simulated seconds and steps when the frame was recorded
+    pub time: f64,
+    pub step: u64,
+    // Values of every particle in identifier order, a channel that was not recorded is empty
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+    pub densities: Vec<f32>,
+    pub pressures: Vec<f32>,
+    pub bodies: Vec<BodyState>,
+}
+
+impl TrajectoryFrame {
+    pub fn new(time: f64, step: u64, particles: &ParticleSet, bodies: Vec<BodyState>) -> Self {
+        let indices: Vec<usize> = (0..particles.len())
+            .map(|id| particles.index_of(id))
+            .collect();
+        TrajectoryFrame {
+            time,
+            step,
+            positions: indices.iter().map(|&i| particles.positions[i]).collect(),
+            velocities: indices.iter().map(|&i| particles.velocities[i]).collect(),
+            densities: indices.iter().map(|&i| particles.densities[i]).collect(),
+            pressures: indices.iter().map(|&i| particles.pressures[i]).collect(),
+            bodies,
+        }
+    }
+
+    // The particles of the frame, with zeros for the channels that were not recorded
+    pub fn particles(&self) -> ParticleSet {
+        let mut particles = ParticleSet::default();
+        for (id, &position) in self.positions.iter().enumerate() {
+            let velocity = self.velocities.get(id).copied().unwrap_or_default();
+            particles.push(position, velocity);
+        }
+        if !self.densities.is_empty() {
+            particles.densities = self.densities.clone();
+        }
+        if !self.pressures.is_empty() {
+            particles.pressures = self.pressures.clone();
+        }
+        particles
+    }
+}
+
+// Why a trajectory could not be read, displayed with the file
+#[derive(Debug)]
+pub enum TrajectoryError {
+    Io(PathBuf, io::Error),
+    NotATrajectory(PathBuf),
+    Version {
+        path: PathBuf,
+        found: u32,
+    },
+    Corrupt(PathBuf, String),
+    MissingFrame {
+        path: PathBuf,
+        index: usize,
+        frames: usize,
+    },
+}
+
+impl fmt::Display for TrajectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrajectoryError::Io(path, error) => write!(f, "{}: {error}", path.display()),
+            TrajectoryError::NotATrajectory(path) => {
+                write!(f, "{}: not a trajectory file", path.display())
+            }
+            TrajectoryError::Version { path, found } => write!(
+                f,
+                "{}: trajectory version {found} cannot be loaded, this build reads version {TRAJECTORY_VERSION}",
+                path.display()
+            ),
+            TrajectoryError::Corrupt(path, message) => {
+                write!(f, "{}: corrupt trajectory, {message}", path.display())
+            }
+            TrajectoryError::MissingFrame {
+                path,
+                index,
+                frames,
+            } => write!(
+                f,
+                "{}: no frame {index}, the trajectory has {frames} frames",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrajectoryError {}
+
+// Quantized positions of a keyframe, the frames after it are stored relative to them
+type QuantizedPositions = Vec<[u16; 3]>;
+
+// Appends frames to a trajectory
+pub struct TrajectoryWriter<W: Write> {
+    writer: W,
+    options: TrajectoryOptions,
+    frames: usize,
+    keyframe: Option<QuantizedPositions>,
+    since_keyframe: u32,
+    // Encoded frame, reused so its length can be written first
+    buffer: Vec<u8>,
+}
+
+impl TrajectoryWriter<BufWriter<File>> {
+    pub fn create(path: &Path, options: TrajectoryOptions) -> io::Result<Self> {
+        TrajectoryWriter::new(BufWriter::new(File::create(path)?), options)
+    }
+}
+
+impl<W: Write> TrajectoryWriter<W> {
+    // Start a trajectory by writing its header
+    pub fn new(mut writer: W, options: TrajectoryOptions) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&TRAJECTORY_VERSION.to_le_bytes())?;
+        options.write(&mut writer)?;
+        Ok(TrajectoryWriter {
+            writer,
+            options,
+            frames: 0,
+            keyframe: None,
+            since_keyframe: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Write a frame, the channels the options leave out are dropped
+    pub fn write_frame(&mut self, frame: &TrajectoryFrame) -> io::Result<()> {
+        let options = &self.options;
+        let quantized: QuantizedPositions = (frame.positions.iter())
+            .map(|position| {
+                let t = (*position - options.min) / (options.max - options.min);
+                t.to_array().map(quantize)
+            })
+            .collect();
+
+        // A keyframe starts over when it is due, or when particles were removed
+        let keyframe = match &self.keyframe {
+            Some(keyframe)
+                if self.since_keyframe < options.keyframe_interval
+                    && keyframe.len() <= quantized.len() =>
+            {
+                Some(keyframe)
+            }
+            _ => None,
+        };
+
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        buffer.push(if keyframe.is_some() {
+            DELTA_FRAME
+        } else {
+            KEYFRAME
+        });
+        buffer.extend(frame.time.to_le_bytes());
+        buffer.extend(frame.step.to_le_bytes());
+        buffer.extend((quantized.len() as u32).to_le_bytes());
+        let known = keyframe.map_or(0, |keyframe| keyframe.len());
+        for (id, position) in quantized.iter().enumerate() {
+            for axis in 0..3 {
+                match keyframe {
+                    Some(keyframe) if id < known => write_varint(
+                        buffer,
+                        zigzag(position[axis] as i32 - keyframe[id][axis] as i32),
+                    ),
+                    _ => buffer.extend(position[axis].to_le_bytes()),
+                }
+            }
+        }
+
+        if options.channels.velocity {
+            let scale = (frame.velocities.iter())
+                .map(|velocity| velocity.abs().max_element())
+                .fold(0., f32::max);
+            buffer.extend(scale.to_le_bytes());
+            for velocity in frame.velocities.iter() {
+                for component in velocity.to_array() {
+                    buffer.extend(quantize_signed(component, scale).to_le_bytes());
+                }
+            }
+        }
+        if options.channels.density {
+            write_range(buffer, &frame.densities);
+        }
+        if options.channels.pressure {
+            write_range(buffer, &frame.pressures);
+        }
+
+        write_len(buffer, frame.bodies.len())?;
+        for body in frame.bodies.iter() {
+            write_vec3(buffer, body.position)?;
+            write_vec3(buffer, body.velocity)?;
+            write_vec3(buffer, body.force)?;
+        }
+
+        if keyframe.is_some() {
+            self.since_keyframe += 1;
+        } else {
+            self.keyframe = Some(quantized);
+            self.since_keyframe = 1;
+        }
+        write_len(&mut self.writer, self.buffer.len())?;
+        self.writer.write_all(&self.buffer)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Frames written so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Where a frame is stored and what it needs to be decoded
+struct FrameEntry {
+    // After the length
+    start: u64,
+    length: usize,
+    time: f64,
+    // Index of the keyframe of a delta frame
+    keyframe: Option<usize>,
+}
+
+// Reads the frames of a trajectory in any order
+pub struct TrajectoryReader<R: Read + Seek> {
+    reader: R,
+    path: PathBuf,
+    options: TrajectoryOptions,
+    frames: Vec<FrameEntry>,
+    // Last keyframe decoded, usually the one of the next frame read too
+    keyframe: Option<(usize, QuantizedPositions)>,
+}
+
+impl TrajectoryReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, TrajectoryError> {
+        let file =
+            File::open(path).map_err(|error| TrajectoryError::Io(path.to_path_buf(), error))?;
+        TrajectoryReader::new(BufReader::new(file), path)
+    }
+}
+
+impl<R: Read + Seek> TrajectoryReader<R> {
+    // Check the header and find every complete frame, the path names the file in errors
+    pub fn new(mut reader: R, path: &Path) -> Result<Self, TrajectoryError> {
+        let io_error = |error| TrajectoryError::Io(path.to_path_buf(), error);
+        let not_a_trajectory = |_| TrajectoryError::NotATrajectory(path.to_path_buf());
+        let magic: [u8; 8] = read_bytes(&mut reader).map_err(not_a_trajectory)?;
+        if &magic != MAGIC {
+            return Err(TrajectoryError::NotATrajectory(path.to_path_buf()));
+        }
+        let version = read_bytes(&mut reader)
+            .map(u32::from_le_bytes)
+            .map_err(not_a_trajectory)?;
+        if version != TRAJECTORY_VERSION {
+            return Err(TrajectoryError::Version {
+                path: path.to_path_buf(),
+                found: version,
+            });
+        }
+        let options = TrajectoryOptions::read(&mut reader).map_err(|_| {
+            TrajectoryError::Corrupt(path.to_path_buf(), "the header ends early".to_string())
+        })?;
+
+        let mut start = reader.stream_position().map_err(io_error)?;
+        let end = reader.seek(SeekFrom::End(0)).map_err(io_error)?;
+        reader.seek(SeekFrom::Start(start)).map_err(io_error)?;
+        let mut frames = Vec::new();
+        let mut last_keyframe = None;
+        // Stop at the first frame that does not fit in the file, a corrupt length may be anything
+        while let Ok(length) = read_len(&mut reader) {
+            start += 8;
+            if (start.checked_add(length as u64)).map_or(true, |frame_end| frame_end > end) {
+                break;
+            }
+            let Ok([kind, time @ ..]) = read_bytes::<9>(&mut reader) else {
+                break;
+            };
+            let keyframe = match kind {
+                KEYFRAME => {
+                    last_keyframe = Some(frames.len());
+                    None
+                }
+                DELTA_FRAME if last_keyframe.is_some() => last_keyframe,
+                _ => {
+                    return Err(TrajectoryError::Corrupt(
+                        path.to_path_buf(),
+                        format!("frame {} has no keyframe", frames.len()),
+                    ))
+                }
+            };
+            frames.push(FrameEntry {
+                start,
+                length,
+                time: f64::from_le_bytes(time),
+                keyframe,
+            });
+            start += length as u64;
+            reader.seek(SeekFrom::Start(start)).map_err(io_error)?;
+        }
+
+        Ok(TrajectoryReader {
+            reader,
+            path: path.to_path_buf(),
+            options,
+            frames,
+            keyframe: None,
+        })
+    }
+
+    pub fn options(&self) -> &TrajectoryOptions {
+        &self.options
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Simulated time of a frame, without reading it
+    pub fn time(&self, index: usize) -> Option<f64> {
+        self.frames.get(index).map(|entry| entry.time)
+    }
+
+    pub fn read_frame(&mut self, index: usize) -> Result<TrajectoryFrame, TrajectoryError> {
+        let Some(entry) = self.frames.get(index) else {
+            return Err(TrajectoryError::MissingFrame {
+                path: self.path.clone(),
+                index,
+                frames: self.frames.len(),
+            });
+        };
+        let cached = self.keyframe.take();
+        let keyframe = match (entry.keyframe, cached) {
+            (Some(keyframe), Some((cached, positions))) if cached == keyframe => {
+                Some((keyframe, positions))
+            }
+            (Some(keyframe), _) => Some((keyframe, self.decode(keyframe, None)?.1)),
+            (None, _) => None,
+        };
+        let (frame, positions) =
+            self.decode(index, keyframe.as_ref().map(|(_, positions)| positions))?;
+        // Keep the keyframe for the frames after it
+        self.keyframe = keyframe.or(Some((index, positions)));
+        Ok(frame)
+    }
+
+    // Decode a frame, and the quantized positions it holds
+    fn decode(
+        &mut self,
+        index: usize,
+        keyframe: Option<&QuantizedPositions>,
+    ) -> Result<(TrajectoryFrame, QuantizedPositions), TrajectoryError> {
+        let path = &self.path;
+        let entry = &self.frames[index];
+        let mut bytes = vec![0; entry.length];
+        (self.reader.seek(SeekFrom::Start(entry.start)))
+            .and_then(|_| self.reader.read_exact(&mut bytes))
+            .map_err(|error| TrajectoryError::Io(path.clone(), error))?;
+
+        let corrupt = |message: &str| {
+            TrajectoryError::Corrupt(path.clone(), format!("frame {index} {message}"))
+        };
+        let mut payload = bytes.as_slice();
+        let (frame, positions) = decode_frame(&mut payload, &self.options, keyframe)
+            .map_err(|_| corrupt("ends early"))?;
+        if !payload.is_empty() {
+            return Err(corrupt("is longer than its contents"));
+        }
+        Ok((frame, positions))
+    }
+}
+
+fn decode_frame(
+    reader: &mut &[u8],
+    options: &TrajectoryOptions,
+    keyframe: Option<&QuantizedPositions>,
+) -> io::Result<(TrajectoryFrame, QuantizedPositions)> {
+    let [kind] = read_bytes(reader)?;
+    let mut frame = TrajectoryFrame {
+        time: f64::from_le_bytes(read_bytes(reader)?),
+        step: u64::from_le_bytes(read_bytes(reader)?),
+        ..Default::default()
+    };
+    let count = u32::from_le_bytes(read_bytes(reader)?) as usize;
+    let keyframe = keyframe.filter(|_| kind == DELTA_FRAME);
+    let known = keyframe.map_or(0, |keyframe| keyframe.len());
+    let mut positions = QuantizedPositions::new();
+    for id in 0..count {
+        let mut position = [0; 3];
+        for (axis, value) in position.iter_mut().enumerate() {
+            *value = match keyframe {
+                Some(keyframe) if id < known => {
+                    let delta = unzigzag(read_varint(reader)?);
+                    u16::try_from(keyframe[id][axis] as i32 + delta)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+                }
+                _ => u16::from_le_bytes(read_bytes(reader)?),
+            };
+        }
+        positions.push(position);
+    }
+    let size = options.max - options.min;
+    frame.positions = (positions.iter())
+        .map(|position| options.min + Vec3::from_array(position.map(dequantize)) * size)
+        .collect();
+
+    if options.channels.velocity {
+        let scale = f32::from_le_bytes(read_bytes(reader)?);
+        for _ in 0..count {
+            let mut velocity = [0.; 3];
+            for component in velocity.iter_mut() {
+                *component = dequantize_signed(i16::from_le_bytes(read_bytes(reader)?), scale);
+            }
+            frame.velocities.push(Vec3::from_array(velocity));
+        }
+    }
+    if options.channels.density {
+        frame.densities = read_range(reader, count)?;
+    }
+    if options.channels.pressure {
+        frame.pressures = read_range(reader, count)?;
+    }
+
+    let body_count = read_len(reader)?;
+    for _ in 0..body_count {
+        frame.bodies.push(BodyState {
+            position: read_vec3(reader)?,
+            velocity: read_vec3(reader)?,
+            force: read_vec3(reader)?,
+        });
+    }
+    Ok((frame, positions))
+}
+
+// A value between 0 and 1 to the nearest of 65536 levels
+fn quantize(t: f32) -> u16 {
+    (t.clamp(0., 1.) * LEVELS).round() as u16
+}
+
+fn dequantize(level: u16) -> f32 {
+    level as f32 / LEVELS
+}
+
+// A value between -scale and scale to the nearest of 65535 levels
+fn quantize_signed(value: f32, scale: f32) -> i16 {
+    if scale > 0. {
+        (value / scale * SIGNED_LEVELS).round() as i16
+    } else {
+        0
+    }
+}
+
+fn dequantize_signed(level: i16, scale: f32) -> f32 {
+    level as f32 / SIGNED_LEVELS * scale
+}
+
+// Values quantized between their minimum and maximum, which are stored first
+fn write_range(buffer: &mut Vec<u8>, values: &[f32]) {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let (min, max) = if min <= max { (min, max) } else { (0., 0.) };
+    buffer.extend(min.to_le_bytes());
+    buffer.extend(max.to_le_bytes());
+    for &value in values {
+        let t = if max > min {
+            (value - min) / (max - min)
+        } else {
+            0.
+        };
+        buffer.extend(quantize(t).to_le_bytes());
+    }
+}
+
+fn read_range(reader: &mut &[u8], count: usize) -> io::Result<Vec<f32>> {
+    let min = f32::from_le_bytes(read_bytes(reader)?);
+    let max = f32::from_le_bytes(read_bytes(reader)?);
+    let mut values = Vec::with_capacity(count.min(reader.len() / 2));
+    for _ in 0..count {
+        let level = u16::from_le_bytes(read_bytes(reader)?);
+        values.push(min + dequantize(level) * (max - min));
+    }
+    Ok(values)
+}
+
+// Small signed numbers to small unsigned ones: 0, -1, 1, -2, ... to 0, 1, 2, 3, ...
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+// Seven bits per byte, the high bit set on every byte but the last
+fn write_varint(buffer: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(reader: &mut &[u8]) -> io::Result<u32> {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let [byte] = read_bytes(reader)?;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "variable length integer too long",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MIN: Vec3 = Vec3::new(-500., -300., -200.);
+    const MAX: Vec3 = Vec3::new(500., 300., 200.);
+
+    // Particles swirling through the box at up to about 50 units per second, more of them every
+    // 20 frames
+    fn frame(index: usize) -> TrajectoryFrame {
+        let mut particles = ParticleSet::default();
+        let time = index as f32 / 600.;
+        for i in 0..(200 + index / 20 * 10) {
+            let phase = i as f32 * 0.37 + time;
+            let position = Vec3::new(phase.sin(), (phase * 1.3).cos(), (phase * 0.7).sin());
+            particles.push(
+                position * MAX * 0.9,
+                Vec3::new(phase.cos(), -phase, 3.) * 40.,
+            );
+        }
+        particles.densities = (0..particles.len())
+            .map(|i| 0.0003 + (i as f32 * 0.1 + time).sin() * 0.0001)
+            .collect();
+        particles.pressures = (0..particles.len()).map(|i| i as f32 * -25.).collect();
+        // Stored out of identifier order, as after a reorder
+        particles.sort_by_morton(100.);
+        let bodies = vec![BodyState {
+            position: Vec3::Y * time,
+            ..Default::default()
+        }];
+        TrajectoryFrame::new(index as f64 / 60., index as u64, &particles, bodies)
+    }
+
+    fn recording(options: TrajectoryOptions, frames: usize) -> Vec<u8> {
+        let mut writer = TrajectoryWriter::new(Vec::new(), options).unwrap();
+        for index in 0..frames {
+            writer.write_frame(&frame(index)).unwrap();
+        }
+        assert_eq!(writer.frames(), frames);
+        writer.into_inner()
+    }
+
+    fn reader(bytes: Vec<u8>) -> Result<TrajectoryReader<Cursor<Vec<u8>>>, TrajectoryError> {
+        TrajectoryReader::new(Cursor::new(bytes), Path::new("test.traj"))
+    }
+
+    fn assert_within(read: &[f32], written: &[f32], tolerance: f32) {
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written.iter()) {
+            // A little slack for the rounding of the floats themselves
+            assert!(
+                (read - written).abs() <= tolerance * 1.01 + written.abs() * 1e-6,
+                "{read} is not within {tolerance} of {written}"
+            );
+        }
+    }
+
+    #[test]
+    fn frames_come_back_within_the_error_bounds() {
+        let mut options = TrajectoryOptions::new(MIN, MAX);
+        options.keyframe_interval = 8;
+        let mut reader = reader(recording(options, 50)).unwrap();
+        assert_eq!(reader.len(), 50);
+        assert_eq!(reader.options(), &options);
+        assert_eq!(reader.time(30), Some(0.5));
+
+        let tolerance = options.position_tolerance();
+        // Out of order, so frames are decoded from keyframes that are not cached
+        for index in [49, 3, 17, 0, 16, 41, 42] {
+            let read = reader.read_frame(index).unwrap();
+            let written = frame(index);
+            assert_eq!((read.time, read.step), (written.time, written.step));
+            assert_eq!(read.bodies, written.bodies);
+            for axis in 0..3 {
+                let component = |positions: &[Vec3]| -> Vec<f32> {
+                    positions.iter().map(|position| position[axis]).collect()
+                };
+                assert_within(
+                    &component(&read.positions),
+                    &component(&written.positions),
+                    tolerance[axis],
+                );
+            }
+
+            let flatten = |vectors: &[Vec3]| -> Vec<f32> {
+                vectors
+                    .iter()
+                    .flat_map(|vector| vector.to_array())
+                    .collect()
+            };
+            let scale = flatten(&written.velocities)
+                .iter()
+                .fold(0., |scale: f32, value| scale.max(value.abs()));
+            assert_within(
+                &flatten(&read.velocities),
+                &flatten(&written.velocities),
+                scale / SIGNED_LEVELS * 0.5,
+            );
+            for (read, written) in [
+                (&read.densities, &written.densities),
+                (&read.pressures, &written.pressures),
+            ] {
+                let min = written.iter().copied().fold(f32::INFINITY, f32::min);
+                let max = written.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                assert_within(read, written, (max - min) / LEVELS * 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_between_keyframes_are_small() {
+        let mut options = TrajectoryOptions::new(MIN, MAX);
+        options.channels = Channels::NONE;
+        let reader = reader(recording(options, 10)).unwrap();
+        // Against 12 bytes for the position of each of the 200 particles as plain floats, and a
+        // little for the time, the step and the body
+        assert!(reader.frames[0].length < 200 * 12 / 2 + 100);
+        assert!(reader.frames[1].length < 200 * 12 / 4 + 100);
+    }
+
+    #[test]
+    fn channels_left_out_are_empty() {
+        let mut options = TrajectoryOptions::new(MIN, MAX);
+        options.channels = Channels::from_names("pressure").unwrap();
+        let mut reader = reader(recording(options, 3)).unwrap();
+        let read = reader.read_frame(2).unwrap();
+        assert!(read.velocities.is_empty() && read.densities.is_empty());
+        assert_eq!(read.pressures.len(), read.positions.len());
+
+        let particles = read.particles();
+        assert_eq!(particles.velocities[0], Vec3::ZERO);
+        assert_eq!(Channels::from_names("all, none"), Some(Channels::ALL));
+        assert_eq!(Channels::from_names("speed"), None);
+    }
+
+    #[test]
+    fn positions_outside_the_bounds_are_clamped() {
+        let mut options = TrajectoryOptions::new(MIN, MAX);
+        options.channels = Channels::NONE;
+        let mut writer = TrajectoryWriter::new(Vec::new(), options).unwrap();
+        let mut outside = TrajectoryFrame {
+            positions: vec![MAX * 2., MIN - 1., Vec3::ZERO],
+            ..Default::default()
+        };
+        writer.write_frame(&outside).unwrap();
+        // Fewer particles than the keyframe starts a new keyframe
+        outside.positions.pop();
+        writer.write_frame(&outside).unwrap();
+
+        let mut reader = reader(writer.into_inner()).unwrap();
+        assert!(reader.frames.iter().all(|frame| frame.keyframe.is_none()));
+        assert_eq!(reader.read_frame(1).unwrap().positions, [MAX, MIN]);
+    }
+
+    #[test]
+    fn a_cut_off_frame_is_left_out() {
+        let options = TrajectoryOptions::new(MIN, MAX);
+        let mut bytes = recording(options, 4);
+        bytes.truncate(bytes.len() - 5);
+        let mut cut = reader(bytes).unwrap();
+        assert_eq!(cut.len(), 3);
+        assert_eq!(cut.time(3), None);
+        assert!(matches!(
+            cut.read_frame(3),
+            Err(TrajectoryError::MissingFrame {
+                index: 3,
+                frames: 3,
+                ..
+            })
+        ));
+
+        // A corrupt length of the third frame leaves it and the ones after it out
+        let mut bytes = recording(options, 4);
+        let length_at = reader(bytes.clone()).unwrap().frames[2].start as usize - 8;
+        bytes[length_at..length_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let corrupt = reader(bytes).unwrap();
+        assert_eq!(corrupt.len(), 2);
+
+        // Version 1 stored plain floats and is refused
+        let mut bytes = recording(options, 4);
+        bytes[8] = 1;
+        let error = reader(bytes).err().unwrap();
+        assert!(matches!(error, TrajectoryError::Version { found: 1, .. }));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+
+use crate::sph::ParticleSet;
+
+/*
+ *
+ * VTK Export
+ * Writes the particles as VTK XML unstructured grids (.vtu) that ParaView opens directly: one
+ * vertex cell per particle, with the id, velocity, density, pressure and force of every
+ * particle as point data. A VtkSeries writes one file per frame and keeps a .pvd collection
+ * listing them by simulated time, so ParaView loads the whole run as one animated dataset.
+ * The data is written as ASCII so the files can be checked by eye.
+ *
+ */
+
+// Write the particles as one VTK XML unstructured grid
+pub fn write_vtu(writer: &mut impl Write, particles: &ParticleSet) -> io::Result<()> {
+    let count = particles.len();
+    writeln!(writer, r#"<?xml version="1.0"?>"#)?;
+    writeln!(
+        writer,
+        r#"<VTKFile type="UnstructuredGrid" version="1.0" byte_order="LittleEndian" header_type="UInt64">"#
+    )?;
+    writeln!(writer, "  <UnstructuredGrid>")?;
+    writeln!(
+        writer,
+        r#"    <Piece NumberOfPoints="{count}" NumberOfCells="{count}">"#
+    )?;
+
+    writeln!(
+        writer,
+        r#"      <PointData Scalars="density" Vectors="velocity">"#
+    )?;
+    write_array(writer, "Int64", "id", 1, particles.ids.iter())?;
+    write_vectors(writer, "velocity", &particles.velocities)?;
+    write_array(writer, "Float32", "density", 1, particles.densities.iter())?;
+    write_array(writer, "Float32", "pressure", 1, particles.pressures.iter())?;
+    write_vectors(writer, "force", &particles.last_forces)?;
+    writeln!(writer, "      </PointData>")?;
+
+    writeln!(writer, "      <Points>")?;
+    write_vectors(writer, "position", &particles.positions)?;
+    writeln!(writer, "      </Points>")?;
+
+    // Every particle is a cell of one vertex
+    writeln!(writer, "      <Cells>")?;
+    write_array(writer, "Int64", "connectivity", 1, 0..count)?;
+    write_array(writer, "Int64", "offsets", 1, 1..=count)?;
+    write_array(writer, "UInt8", "types", 1, (0..count).map(|_| VTK_VERTEX))?;
+    writeln!(writer, "      </Cells>")?;
+
+    writeln!(writer, "    </Piece>")?;
+    writeln!(writer, "  </UnstructuredGrid>")?;
+    writeln!(writer, "</VTKFile>")
+}
+
+const VTK_VERTEX: u8 = 1;
+
+fn write_array<T: std::fmt::Display>(
+    writer: &mut impl Write,
+    data_type: &str,
+    name: &str,
+    components: usize,
+    values: impl Iterator<Item = T>,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        r#"        <DataArray type="{data_type}" Name="{name}" NumberOfComponents="{components}" format="ascii">"#
+    )?;
+    write!(writer, "         ")?;
+    for value in values {
+        write!(writer, " {value}")?;
+    }
+    writeln!(writer)?;
+    writeln!(writer, "        </DataArray>")
+}
+
+fn write_vectors(writer: &mut impl Write, name: &str, vectors: &[Vec3]) -> io::Result<()> {
+    let components = vectors.iter().flat_map(|vector| vector.to_array());
+    write_array(writer, "Float32", name, 3, components)
+}
+
+// Numbered .vtu files in a directory and the .pvd collection listing them
+pub struct VtkSeries {
+    directory: PathBuf,
+    name: String,
+    // Simulated time and file name of every frame written so far
+    frames: Vec<(f64, String)>,
+}
+
+impl VtkSeries {
+    // Empty <name>.pvd collection, made in the directory along with any missing parents
+    pub fn new(directory: &Path, name: &str) -> io::Result<VtkSeries> {
+        fs::create_dir_all(directory)?;
+        Ok(VtkSeries {
+            directory: directory.to_path_buf(),
+            name: name.to_string(),
+            frames: Vec::new(),
+        })
+    }
+
+    // Carry on with a series an earlier run wrote up to the given time, keeping the frames of
+    // its .pvd collection up to then
+    pub fn resume(directory: &Path, name: &str, time: f64) -> io::Result<VtkSeries> {
+        let mut series = VtkSeries::new(directory, name)?;
+        let collection = match fs::read_to_string(series.collection_path()) {
+            Ok(collection) => collection,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(series),
+            Err(error) => return Err(error),
+        };
+        for line in collection.lines() {
+            let attribute = |name: &str| {
+                let start = line.find(&format!(r#"{name}=""#))? + name.len() + 2;
+                let end = start + line[start..].find('"')?;
+                Some(&line[start..end])
+            };
+            if let (Some(timestep), Some(file)) = (attribute("timestep"), attribute("file")) {
+                match timestep.parse::<f64>() {
+                    Ok(timestep) if timestep <= time => {
+                        series.frames.push((timestep, file.to_string()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(series)
+    }
+
+    // Write the particles of a frame to <name>_<frame>.vtu and add it to the collection
+    pub fn write_frame(
+        &mut self,
+        frame: u64,
+        time: f64,
+        particles: &ParticleSet,
+    ) -> io::Result<PathBuf> {
+        let file_name = format!("{}_{frame:06}.vtu", self.name);
+        let path = self.directory.join(&file_name);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        write_vtu(&mut writer, particles)?;
+        writer.flush()?;
+
+        // Writing a frame again replaces it
+        self.frames.retain(|(_, file)| *file != file_name);
+        self.frames.push((time, file_name));
+        self.write_collection()?;
+        Ok(path)
+    }
+
+    pub fn collection_path(&self) -> PathBuf {
+        self.directory.join(format!("{}.pvd", self.name))
+    }
+
+    // Rewrite the .pvd file after every frame, so it lists every frame even if the run stops
+    fn write_collection(&self) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(self.collection_path())?);
+        writeln!(writer, r#"<?xml version="1.0"?>"#)?;
+        writeln!(
+            writer,
+            r#"<VTKFile type="Collection" version="1.0" byte_order="LittleEndian">"#
+        )?;
+        writeln!(writer, "  <Collection>")?;
+        for (time, file) in self.frames.iter() {
+            writeln!(
+                writer,
+                r#"    <DataSet timestep="{time}" group="" part="0" file="{file}"/>"#
+            )?;
+        }
+        writeln!(writer, "  </Collection>")?;
+        writeln!(writer, "</VTKFile>")?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles() -> ParticleSet {
+        let mut particles = ParticleSet::default();
+        particles.push(Vec3::new(1., 2., 3.), Vec3::new(-1., 0., 0.5));
+        particles.push(Vec3::new(4., 5., 6.), Vec3::ZERO);
+        particles.densities = vec![0.25, 0.5];
+        particles.last_forces[1] = Vec3::new(0., 10., 0.);
+        particles
+    }
+
+    // Values of the DataArray with the given name
+    fn data_array(vtu: &str, name: &str) -> Vec<f32> {
+        let start = vtu.find(&format!(r#"Name="{name}""#)).unwrap();
+        let line = vtu[start..].lines().nth(1).unwrap();
+        line.split_whitespace()
+            .map(|value| value.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn vtu_holds_every_particle() {
+        let mut bytes = Vec::new();
+        write_vtu(&mut bytes, &particles()).unwrap();
+        let vtu = String::from_utf8(bytes).unwrap();
+
+        assert!(vtu.contains(r#"<Piece NumberOfPoints="2" NumberOfCells="2">"#));
+        assert_eq!(data_array(&vtu, "position"), [1., 2., 3., 4., 5., 6.]);
+        assert_eq!(data_array(&vtu, "velocity"), [-1., 0., 0.5, 0., 0., 0.]);
+        assert_eq!(data_array(&vtu, "density"), [0.25, 0.5]);
+        assert_eq!(data_array(&vtu, "force"), [0., 0., 0., 0., 10., 0.]);
+        assert_eq!(data_array(&vtu, "offsets"), [1., 2.]);
+        assert_eq!(data_array(&vtu, "types"), [1., 1.]);
+    }
+
+    #[test]
+    fn collection_lists_the_frames_by_time() {
+        let directory = std::env::temp_dir().join(format!("vtk_series_{}", std::process::id()));
+        let mut series = VtkSeries::new(&directory, "particles").unwrap();
+        for frame in 0..3 {
+            series
+                .write_frame(frame * 10, frame as f64 * 0.5, &particles())
+                .unwrap();
+        }
+        assert!(directory.join("particles_000020.vtu").exists());
+        let collection = fs::read_to_string(series.collection_path()).unwrap();
+        assert!(collection.contains(
+            r#"<DataSet timestep="0.5" group="" part="0" file="particles_000010.vtu"/>"#
+        ));
+
+        // A resumed run drops the frames after its checkpoint and writes them again
+        let mut resumed = VtkSeries::resume(&directory, "particles", 0.5).unwrap();
+        assert_eq!(resumed.frames.len(), 2);
+        resumed.write_frame(15, 0.75, &particles()).unwrap();
+        let collection = fs::read_to_string(resumed.collection_path()).unwrap();
+        assert_eq!(collection.matches("<DataSet").count(), 3);
+        assert!(!collection.contains("particles_000020.vtu"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}